@@ -1,8 +1,8 @@
-use mini_redis::{client, Result};
+use learn_rust::my_redis::client::{self, Result};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Open a connection to the mini-redis address.
+    // Open a connection to the server address.
     let mut client = client::connect("127.0.0.1:6379").await?;
 
     // Set the key "hello" with value "world"
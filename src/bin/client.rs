@@ -1,71 +1,47 @@
-use mini_redis::client;
-use tokio::sync:: {
-    mpsc,
-    oneshot,
-};
 use learn_rust::my_redis::{
-    Command::{self, *},
+    config::{ClientConfig, Configure},
+    manager::ClientHandle,
     client_dbg_print,
+    set_log_level,
 };
 
 #[tokio::main]
 async fn main() {
-
-    let (tx, mut rx) = mpsc::channel(32);
-    let tx2 = tx.clone();
-
-    let manager = tokio::spawn(async move {
-        let mut client = client::connect("127.0.0.1:6379").await.unwrap();
-
-        while let Some(cmd) = rx.recv().await {
-
-            match cmd {
-                Get { key, resp } => {
-                    let res = client.get(&key).await;
-                    let _ = resp.send(res);
-                }
-                Set { key, val, resp } => {
-                    let res = client.set(&key, val).await;
-
-                    let _ = resp.send(res);
-                }
-            }
+    let config = match ClientConfig::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", ClientConfig::USAGE);
+            return;
         }
-    });
+        Err(err) => {
+            eprintln!("[!] {}", err);
+            eprintln!("{}", ClientConfig::USAGE);
+            std::process::exit(1);
+        }
+    };
+    set_log_level(config.loglevel);
+
+    let handle = match ClientHandle::connect(config.addr(), 1).await {
+        Ok(handle) => handle,
+        Err(err) => {
+            eprintln!("[!] Can't connect to {}: {}", config.addr(), err);
+            std::process::exit(1);
+        }
+    };
+    let handle2 = handle.clone();
 
-    
     let t1 = tokio::spawn(async move {
-        let (resp_tx, resp_rx) = oneshot::channel();
-
-        let cmd = Command::Get {
-            key: "hello".to_string(),
-            resp: resp_tx,
-        };
-
-        tx.send(cmd).await.unwrap();
-
-        let res = resp_rx.await;
+        let res = handle.get("hello").await;
 
         client_dbg_print(&format!("GOT = {:?}", res));
-
     });
 
     let t2 = tokio::spawn(async move {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let cmd = Command::Set {
-            key: "foo".to_string(),
-            val: "bar".into(),
-            resp: resp_tx,
-        };
-
-        tx2.send(cmd).await.unwrap();
-        
-        let res = resp_rx.await;
+        let res = handle2.set("foo", "bar".into()).await;
 
         client_dbg_print(&format!("GOT = {:?}", res));
     });
 
     t1.await.unwrap();
     t2.await.unwrap();
-    manager.await.unwrap();
-}
\ No newline at end of file
+}
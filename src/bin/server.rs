@@ -1,61 +1,106 @@
-use tokio::net::{ TcpListener, TcpStream };
+use std::time::Duration;
+use tokio::net::TcpListener;
 use learn_rust::my_redis::{
-    ShardedDb,
-    get_db,
-    server_dbg_print,
+    aof::AppendOnlyLog,
+    config::{Configure, ServerConfig},
+    eviction::set_max_memory,
+    server::Shared,
+    snapshot::Snapshotter,
 };
-use mini_redis::{Connection, Frame};
 
 
 #[tokio::main]
 async fn main() {
     use learn_rust::my_redis::*;
 
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", ServerConfig::USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("[!] {}", err);
+            eprintln!("{}", ServerConfig::USAGE);
+            std::process::exit(1);
+        }
+    };
+    set_log_level(config.loglevel);
 
-    server_dbg_print("Listenning");
+    let listener = TcpListener::bind(config.addr()).await.unwrap_or_else(|err| {
+        eprintln!("[!] Can't listen on {}: {}", config.addr(), err);
+        std::process::exit(1);
+    });
 
-    let shared_db = new_shared_db(8);
+    server_dbg_print(&format!("Listenning on {}", config.addr()));
 
+    let shared_db = new_shared_db(config.shards);
 
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        
-        let shared_db = shared_db.clone();
-        
-        tokio::spawn(async move {
-            process(socket, shared_db).await;
-        });
-    }
+    let snapshotter = config.snapshot_path().map(Snapshotter::new);
+
+    // With the append-only log enabled, it is the most complete copy of the dataset
+    // and is loaded instead of the snapshot.
+    let aof = match config.aof_path() {
+        Some(path) => {
+            match aof::replay(&shared_db, path) {
+                Ok(count) => server_dbg_print(&format!("Replayed {} commands from {:?}", count, path)),
+                Err(err) => {
+                    eprintln!("[!] Can't replay {:?}: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+            Some(AppendOnlyLog::open(path, config.appendfsync).unwrap_or_else(|err| {
+                eprintln!("[!] Can't open the append-only log: {}", err);
+                std::process::exit(1);
+            }))
+        }
+        None => {
+            if let Some(snapshotter) = &snapshotter {
+                match snapshotter.load(&shared_db) {
+                    Ok(count) => {
+                        server_dbg_print(&format!("Loaded {} keys from {:?}", count, snapshotter.path()))
+                    }
+                    Err(err) => {
+                        eprintln!("[!] Can't load {:?}: {}", snapshotter.path(), err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None
+        }
+    };
+
+    // The limit is only set once loaded, so that loading never evicts keys.
+    set_max_memory(&shared_db, config.maxmemory, config.maxmemory_policy);
+
+    spawn_purge_task(&shared_db, Duration::from_millis(100));
+
+    let shared = Shared {
+        shared_db,
+        pub_sub: new_shared_pub_sub(),
+        snapshotter,
+        aof,
+    };
+
+    server::run(listener, shared, config.options, shutdown_signal()).await;
+    server_dbg_print("Bye");
 }
 
+/// Complete on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-async fn process(socket: TcpStream, shared_db: ShardedDb) {
-    use mini_redis::{Command::{self, Get, Set}};
-
-    let mut connection = Connection::new(socket);
-    
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut db = get_db(&shared_db, cmd.key())
-                    .lock()
-                    .unwrap();
-                server_dbg_print(&format!("Set key:[{}]", cmd.key()));
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
-            },
-            Get(cmd) => {
-                let db = get_db(&shared_db, cmd.key()).lock().unwrap();
-                server_dbg_print(&format!("Get key:[{}]", cmd.key()));
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
-                }
-            },
-            cmd => panic!("Unimplemented {:?}", cmd),
-        };
-        connection.write_frame(&response).await.unwrap();
+        let mut terminate = signal(SignalKind::terminate()).expect("can't install the SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
     }
-}
\ No newline at end of file
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1 +1,2 @@
+pub mod skip_list;
 pub mod union_find;
\ No newline at end of file
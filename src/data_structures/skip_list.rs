@@ -0,0 +1,386 @@
+//! An indexable skip list [Skip Lists: A Probabilistic Alternative to Balanced Trees - William Pugh](https://15721.courses.cs.cmu.edu/spring2018/papers/08-oltpindexes1/pugh-skiplists-cacm1990.pdf).
+//!
+//! Every link also records its span, the number of level 0 nodes it skips, as
+//! in the sorted sets of Redis. Summing the spans along a search path gives the
+//! rank of a value, so both finding a value by rank and the rank of a value take
+//! O(log n) on average.
+//!
+//! Nodes live in a `Vec` and link to each other by index, freed slots being
+//! reused by later insertions.
+
+/// The maximum number of levels of a node.
+const MAX_LEVEL: usize = 32;
+
+/// The probability for a node to reach the next level is `1 / BRANCHING`.
+const BRANCHING: u64 = 4;
+
+/// The index of the head node, which holds no value.
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    /// The index of the next node of this level, `None` at the end of the list.
+    next: Option<usize>,
+
+    /// How many level 0 nodes the link skips over, counting the next node.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    /// `None` for the head node and for freed slots.
+    value: Option<T>,
+
+    /// One link per level of the node.
+    links: Vec<Link>,
+}
+
+/// A sorted list of distinct values with O(log n) search, insertion, removal and rank queries.
+#[derive(Debug, Clone)]
+pub struct SkipList<T> {
+    nodes: Vec<Node<T>>,
+
+    /// Slots of `nodes` freed by removals.
+    free: Vec<usize>,
+
+    /// The number of levels in use.
+    level: usize,
+
+    len: usize,
+
+    /// The state of the xorshift generator drawing node levels.
+    rng: u64,
+}
+
+impl<T: Ord> Default for SkipList<T> {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+impl<T: Ord> SkipList<T> {
+    /// Create an empty skip list.
+    pub fn new() -> SkipList<T> {
+        let head = Node {
+            value: None,
+            links: vec![Link { next: None, span: 0 }; MAX_LEVEL],
+        };
+
+        SkipList {
+            nodes: vec![head],
+            free: vec![],
+            level: 1,
+            len: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Return the number of values in the list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn value(&self, node: usize) -> &T {
+        self.nodes[node].value.as_ref().expect("linked to a node without value")
+    }
+
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            if !self.rng.is_multiple_of(BRANCHING) {
+                break;
+            }
+            level += 1;
+        }
+        level
+    }
+
+    /// Walk down from the head, on each level moving forward while `before` holds
+    /// for the next value.
+    ///
+    /// Return, for every level, the last node visited and its rank, the head
+    /// being at rank 0 and the first value at rank 1.
+    fn search(&self, mut before: impl FnMut(&T) -> bool) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+
+        let mut node = HEAD;
+        let mut traversed = 0;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].links[level].next {
+                if !before(self.value(next)) {
+                    break;
+                }
+                traversed += self.nodes[node].links[level].span;
+                node = next;
+            }
+            update[level] = node;
+            rank[level] = traversed;
+        }
+
+        (update, rank)
+    }
+
+    /// Insert `value`, returning its rank, or `None` if an equal value is already there.
+    pub fn insert(&mut self, value: T) -> Option<usize> {
+        let (mut update, mut rank) = self.search(|other| *other < value);
+        if let Some(next) = self.nodes[update[0]].links[0].next {
+            if *self.value(next) == value {
+                return None;
+            }
+        }
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                update[i] = HEAD;
+                rank[i] = 0;
+                self.nodes[HEAD].links[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            value: Some(value),
+            links: vec![Link { next: None, span: 0 }; level],
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let prev = self.nodes[update[i]].links[i];
+            // The new node sits `rank[0] - rank[i]` nodes after `update[i]`.
+            self.nodes[index].links[i] = Link {
+                next: prev.next,
+                span: prev.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].links[i] = Link {
+                next: Some(index),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        // Links above the new node now skip over it too.
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].links[i].span += 1;
+        }
+
+        self.len += 1;
+        Some(rank[0])
+    }
+
+    /// Remove the value equal to `value`, returning it.
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let (update, _) = self.search(|other| other < value);
+        let index = self.nodes[update[0]].links[0].next?;
+        if self.value(index) != value {
+            return None;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let link = self.nodes[prev].links[i];
+            if link.next == Some(index) {
+                let removed = self.nodes[index].links[i];
+                self.nodes[prev].links[i] = Link {
+                    next: removed.next,
+                    span: link.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[prev].links[i].span -= 1;
+            }
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].next.is_none() {
+            self.nodes[HEAD].links[self.level - 1].span = 0;
+            self.level -= 1;
+        }
+
+        self.len -= 1;
+        self.free.push(index);
+        self.nodes[index].links = vec![];
+        self.nodes[index].value.take()
+    }
+
+    /// Return the 0-based rank of `value`, `None` if it is not in the list.
+    pub fn rank(&self, value: &T) -> Option<usize> {
+        let (update, rank) = self.search(|other| other <= value);
+        if update[0] != HEAD && self.value(update[0]) == value {
+            Some(rank[0] - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Return the index of the node at the 0-based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+
+        let target = rank + 1;
+        let mut node = HEAD;
+        let mut traversed = 0;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].links[level].next {
+                let span = self.nodes[node].links[level].span;
+                if traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                node = next;
+            }
+            if traversed == target {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Return the value at the 0-based `rank`.
+    pub fn get_by_rank(&self, rank: usize) -> Option<&T> {
+        self.node_at(rank).map(|node| self.value(node))
+    }
+
+    /// Return the number of values for which `before` holds, assuming it holds
+    /// for a prefix of the list, as [`slice::partition_point`] does.
+    pub fn partition_point(&self, before: impl FnMut(&T) -> bool) -> usize {
+        let (_, rank) = self.search(before);
+        rank[0]
+    }
+
+    /// Iterate over the values in order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.iter_from(0)
+    }
+
+    /// Iterate over the values in order, starting at the 0-based `rank`.
+    pub fn iter_from(&self, rank: usize) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            node: self.node_at(rank),
+        }
+    }
+}
+
+/// An iterator over the values of a [`SkipList`].
+pub struct Iter<'a, T> {
+    list: &'a SkipList<T>,
+    node: Option<usize>,
+}
+
+impl<'a, T: Ord> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.node?;
+        self.node = self.list.nodes[node].links[0].next;
+        Some(self.list.value(node))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A permutation of `0..n`.
+    fn shuffled(n: u64) -> Vec<u64> {
+        // 7919 is prime, so multiplying by it permutes the residues modulo a prime `n`.
+        (0..n).map(|i| (i * 7919) % n).collect()
+    }
+
+    #[test]
+    fn skiplist_insert_and_rank() {
+        let mut list = SkipList::new();
+        assert!(list.is_empty());
+        assert_eq!(None, list.get_by_rank(0));
+
+        let values = shuffled(1009);
+        for value in &values {
+            assert!(list.insert(*value).is_some());
+        }
+        assert_eq!(None, list.insert(500));
+        assert_eq!(1009, list.len());
+
+        for value in 0..1009 {
+            assert_eq!(Some(value as usize), list.rank(&value));
+            assert_eq!(Some(&value), list.get_by_rank(value as usize));
+        }
+        assert_eq!(None, list.rank(&5000));
+        assert_eq!(None, list.get_by_rank(1009));
+        assert!(list.iter().copied().eq(0..1009));
+        assert!(list.iter_from(1000).copied().eq(1000..1009));
+    }
+
+    #[test]
+    fn skiplist_insert_returns_rank() {
+        let mut list = SkipList::new();
+        assert_eq!(Some(0), list.insert(10));
+        assert_eq!(Some(1), list.insert(30));
+        assert_eq!(Some(1), list.insert(20));
+        assert_eq!(Some(0), list.insert(5));
+        assert_eq!(Some(4), list.insert(40));
+    }
+
+    #[test]
+    fn skiplist_remove() {
+        let mut list = SkipList::new();
+        for value in shuffled(503) {
+            list.insert(value);
+        }
+
+        for value in (0..503).filter(|value| value % 3 == 0) {
+            assert_eq!(Some(value), list.remove(&value));
+        }
+        assert_eq!(None, list.remove(&0));
+        assert_eq!(335, list.len());
+
+        let expected: Vec<u64> = (0..503).filter(|value| value % 3 != 0).collect();
+        assert!(list.iter().eq(expected.iter()));
+        for (rank, value) in expected.iter().enumerate() {
+            assert_eq!(Some(rank), list.rank(value));
+            assert_eq!(Some(value), list.get_by_rank(rank));
+        }
+
+        // Freed slots are reused.
+        let slots = list.nodes.len();
+        for value in (0..503).filter(|value| value % 3 == 0) {
+            list.insert(value);
+        }
+        assert_eq!(slots, list.nodes.len());
+        assert!(list.iter().copied().eq(0..503));
+
+        for value in 0..503 {
+            list.remove(&value);
+        }
+        assert!(list.is_empty());
+        assert_eq!(1, list.level);
+        assert_eq!(None, list.iter().next());
+    }
+
+    #[test]
+    fn skiplist_partition_point() {
+        let mut list = SkipList::new();
+        for value in (0..100).map(|value| value * 2) {
+            list.insert(value);
+        }
+
+        assert_eq!(0, list.partition_point(|value| *value < 0));
+        assert_eq!(5, list.partition_point(|value| *value < 10));
+        assert_eq!(6, list.partition_point(|value| *value <= 10));
+        assert_eq!(6, list.partition_point(|value| *value < 11));
+        assert_eq!(100, list.partition_point(|value| *value < 1000));
+    }
+}
@@ -0,0 +1,745 @@
+//! An append-only log of the commands modifying a [`ShardedDb`], as an alternative to snapshots.
+//!
+//! Every write command is appended to the log as a RESP array once it has been
+//! applied, and the log is replayed into the database on startup. How often the
+//! log is flushed to disk is set by a [`FsyncPolicy`].
+//!
+//! The log file is locked before a command is applied, so that writes are logged
+//! in the order they were applied. While the log is rewritten, the commands
+//! appended are also buffered, and added to the rewritten log before it replaces
+//! the current one, as Redis does.
+
+use super::cmd::Command;
+use super::db::{to_unix_millis, Entry, ShardedDb, Value};
+use super::frame::{self, Frame};
+use super::pubsub::PubSub;
+use bytes::Bytes;
+use std::{
+    fmt, fs,
+    io::{self, BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// When the log is flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every command, the safest and slowest.
+    Always,
+    /// Once per second, losing at most a second of commands on a crash.
+    EverySec,
+    /// Whenever the operating system decides to.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("invalid fsync policy '{}', expected always, everysec or no", s)),
+        }
+    }
+}
+
+/// Error raised when the log can't be replayed.
+#[derive(Debug)]
+pub enum AofError {
+    Io(io::Error),
+    /// The entry starting at `offset` is not a valid command.
+    Corrupt { offset: usize, reason: String },
+}
+
+impl fmt::Display for AofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AofError::Io(err) => write!(f, "append-only log I/O error: {}", err),
+            AofError::Corrupt { offset, reason } => {
+                write!(f, "corrupt append-only log at byte {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AofError {}
+
+impl From<io::Error> for AofError {
+    fn from(err: io::Error) -> Self {
+        AofError::Io(err)
+    }
+}
+
+pub type SharedAof = Arc<AppendOnlyLog>;
+
+#[derive(Debug)]
+pub struct AppendOnlyLog {
+    path: PathBuf,
+    policy: FsyncPolicy,
+    /// Held while a command is applied and appended.
+    log: Mutex<LogFile>,
+    /// How many bytes were appended since the log was opened.
+    appended: AtomicU64,
+    /// How many of the bytes appended are known to be on disk.
+    synced: AtomicU64,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: fs::File,
+    /// `Some` while the log is rewritten, with the entries appended since the
+    /// rewrite took its copy of the dataset.
+    rewrite: Option<Vec<u8>>,
+}
+
+impl AppendOnlyLog {
+    /// Open the log at `path` for appending, creating it if needed.
+    ///
+    /// With [`FsyncPolicy::EverySec`] a task flushing the log every second is
+    /// spawned, so this must be called from within a tokio runtime.
+    pub fn open(path: impl Into<PathBuf>, policy: FsyncPolicy) -> io::Result<SharedAof> {
+        let path = path.into();
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let aof = Arc::new(AppendOnlyLog {
+            path,
+            policy,
+            log: Mutex::new(LogFile { file, rewrite: None }),
+            appended: AtomicU64::new(0),
+            synced: AtomicU64::new(0),
+        });
+
+        if policy == FsyncPolicy::EverySec {
+            let weak_aof = Arc::downgrade(&aof);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    let aof = match weak_aof.upgrade() {
+                        Some(aof) => aof,
+                        None => break,
+                    };
+                    let _ = tokio::task::spawn_blocking(move || aof.sync()).await;
+                }
+            });
+        }
+
+        Ok(aof)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush the log to disk.
+    ///
+    /// Commands keep being appended while the file is flushed.
+    pub fn sync(&self) -> io::Result<()> {
+        let (file, appended) = {
+            let log = self.log.lock().unwrap();
+            (log.file.try_clone()?, self.appended.load(Ordering::Acquire))
+        };
+        file.sync_data()?;
+        self.synced.fetch_max(appended, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// With [`FsyncPolicy::Always`], flush the log to disk unless every command
+    /// appended already is, from a blocking thread.
+    ///
+    /// Called before replies are sent, so that a client only learns of a write
+    /// once it is durable, while the writes of every client since the last flush
+    /// are flushed together.
+    pub async fn sync_appended(self: &Arc<Self>) -> io::Result<()> {
+        if self.policy != FsyncPolicy::Always
+            || self.synced.load(Ordering::Acquire) >= self.appended.load(Ordering::Acquire)
+        {
+            return Ok(());
+        }
+        let aof = self.clone();
+        tokio::task::spawn_blocking(move || aof.sync()).await?
+    }
+
+    /// Apply `command` with `apply` and append it to the log unless it failed or is read-only.
+    pub fn apply_logged<F>(&self, command: Command, apply: F) -> Frame
+    where
+        F: FnOnce(Command) -> Frame,
+    {
+        let mut log = self.log.lock().unwrap();
+
+        let entry = command.to_log_entry();
+        let reply = apply(command);
+
+        if let (Some(entry), false) = (entry, matches!(reply, Frame::Error(_))) {
+            if let Err(err) = self.append(&mut log, &[entry]) {
+                eprintln!("[!] Can't append to {:?}: {}", self.path, err);
+            }
+        }
+
+        reply
+    }
+
+    /// Apply the `commands` of a transaction with `apply`, which returns one reply
+    /// per command, and append those which succeeded to the log in a single write.
+    pub fn apply_logged_all<F>(&self, commands: Vec<Command>, apply: F) -> Vec<Frame>
+    where
+        F: FnOnce(Vec<Command>) -> Vec<Frame>,
+    {
+        let mut log = self.log.lock().unwrap();
+
+        let entries: Vec<Option<Vec<Bytes>>> = commands.iter().map(Command::to_log_entry).collect();
+        let replies = apply(commands);
+
+        let entries: Vec<Vec<Bytes>> = entries
+            .into_iter()
+            .zip(&replies)
+            .filter(|(_, reply)| !matches!(reply, Frame::Error(_)))
+            .filter_map(|(entry, _)| entry)
+            .collect();
+        if !entries.is_empty() {
+            if let Err(err) = self.append(&mut log, &entries) {
+                eprintln!("[!] Can't append to {:?}: {}", self.path, err);
+            }
+        }
+
+        replies
+    }
+
+    /// Run `apply`, which returns its result along with the entries to append, for
+    /// writes whose entries depend on the dataset, such as the pop of a blocked
+    /// `BLPOP` from the first of its keys holding an element.
+    pub fn apply_logged_with<T, F>(&self, apply: F) -> T
+    where
+        F: FnOnce() -> (T, Vec<Vec<Bytes>>),
+    {
+        let mut log = self.log.lock().unwrap();
+
+        let (result, entries) = apply();
+        if !entries.is_empty() {
+            if let Err(err) = self.append(&mut log, &entries) {
+                eprintln!("[!] Can't append to {:?}: {}", self.path, err);
+            }
+        }
+
+        result
+    }
+
+    /// Append `entries` to the `log`, locked by the caller since before the
+    /// commands they come from were applied.
+    ///
+    /// The log isn't flushed to disk here, see [`AppendOnlyLog::sync_appended`].
+    fn append(&self, log: &mut LogFile, entries: &[Vec<Bytes>]) -> io::Result<()> {
+        let mut buf = vec![];
+        for entry in entries {
+            encode(entry, &mut buf);
+        }
+
+        if let Some(rewrite) = &mut log.rewrite {
+            rewrite.extend_from_slice(&buf);
+        }
+        log.file.write_all(&buf)?;
+        self.appended.fetch_add(buf.len() as u64, Ordering::AcqRel);
+
+        Ok(())
+    }
+
+    /// Rewrite the log as the shortest sequence of commands recreating the current
+    /// content of `shared_db`, returning the number of keys written.
+    ///
+    /// Write commands are only held back while the commands recreating the
+    /// dataset are collected, which shares the values with it, not while they are
+    /// written to disk.
+    pub fn rewrite(&self, shared_db: &ShardedDb) -> io::Result<usize> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".rewrite");
+        let tmp = PathBuf::from(tmp);
+
+        let (commands, count) = {
+            let mut log = self.log.lock().unwrap();
+            if log.rewrite.is_some() {
+                return Err(io::Error::other("a rewrite is already in progress"));
+            }
+            let mut commands = vec![];
+            let mut count = 0;
+            for db in shared_db.iter() {
+                let db = db.lock().unwrap();
+                for (key, entry) in db.iter() {
+                    commands.extend(rewrite_commands(key, entry));
+                    count += 1;
+                }
+            }
+            log.rewrite = Some(vec![]);
+            (commands, count)
+        };
+
+        let written = write_commands(&tmp, &commands);
+        drop(commands);
+
+        let mut log = self.log.lock().unwrap();
+        let buffered = log.rewrite.take().expect("the rewrite buffer is only taken here");
+        let mut file = written?;
+        // Only the commands appended during the rewrite are flushed with the log locked.
+        file.write_all(&buffered)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+        log.file = file;
+
+        Ok(count)
+    }
+}
+
+/// Write `commands` to a new file at `path` and flush it to disk, returning the
+/// file for more to be appended.
+fn write_commands(path: &Path, commands: &[Vec<Bytes>]) -> io::Result<fs::File> {
+    let mut file = BufWriter::new(fs::File::create(path)?);
+    let mut buf = vec![];
+    for command in commands {
+        buf.clear();
+        encode(command, &mut buf);
+        file.write_all(&buf)?;
+    }
+    let file = file.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// How many elements of an aggregate a single rewritten command adds at most.
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+/// Return the commands recreating `entry` under `key`.
+fn rewrite_commands(key: &str, entry: &Entry) -> Vec<Vec<Bytes>> {
+    let key = Bytes::copy_from_slice(key.as_bytes());
+    let deadline = entry
+        .expires_at
+        .map(|when| Bytes::from(to_unix_millis(when).to_string()));
+
+    let mut commands = match &entry.value {
+        Value::String(value) => {
+            let mut set = vec![Bytes::from_static(b"SET"), key.clone(), value.clone()];
+            if let Some(deadline) = deadline.clone() {
+                set.push(Bytes::from_static(b"PXAT"));
+                set.push(deadline);
+            }
+            return vec![set];
+        }
+        Value::List(list) => {
+            let elements: Vec<Bytes> = list.iter().cloned().collect();
+            elements
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut push = vec![Bytes::from_static(b"RPUSH"), key.clone()];
+                    push.extend_from_slice(chunk);
+                    push
+                })
+                .collect::<Vec<_>>()
+        }
+        Value::Set(set) => {
+            let members: Vec<Bytes> = set.iter().cloned().collect();
+            members
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut sadd = vec![Bytes::from_static(b"SADD"), key.clone()];
+                    sadd.extend_from_slice(chunk);
+                    sadd
+                })
+                .collect()
+        }
+        Value::Hash(hash) => {
+            let pairs: Vec<(&Bytes, &Bytes)> = hash.iter().collect();
+            pairs
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut hset = vec![Bytes::from_static(b"HSET"), key.clone()];
+                    for (field, value) in chunk {
+                        hset.push((*field).clone());
+                        hset.push((*value).clone());
+                    }
+                    hset
+                })
+                .collect()
+        }
+        Value::SortedSet(zset) => {
+            let pairs: Vec<(&Bytes, f64)> = zset.iter().collect();
+            pairs
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut zadd = vec![Bytes::from_static(b"ZADD"), key.clone()];
+                    for (member, score) in chunk {
+                        // The shortest representation which parses back to the same score.
+                        zadd.push(Bytes::from(score.to_string()));
+                        zadd.push((*member).clone());
+                    }
+                    zadd
+                })
+                .collect()
+        }
+    };
+
+    if let Some(deadline) = deadline {
+        commands.push(vec![Bytes::from_static(b"PEXPIREAT"), key, deadline]);
+    }
+    commands
+}
+
+/// Encode a command as a RESP array of bulk strings.
+fn encode(entry: &[Bytes], dst: &mut Vec<u8>) {
+    dst.extend_from_slice(format!("*{}\r\n", entry.len()).as_bytes());
+    for arg in entry {
+        dst.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        dst.extend_from_slice(arg);
+        dst.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Replay the log at `path` into `shared_db`, returning the number of commands replayed.
+///
+/// A missing log is an empty one. A truncated last entry, as left by a crash in
+/// the middle of a write, is dropped from the file with a warning; anything else
+/// which can't be parsed is an error.
+pub fn replay(shared_db: &ShardedDb, path: &Path) -> Result<usize, AofError> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    // Replayed commands are not published anywhere.
+    let pub_sub = PubSub::default();
+    let mut count = 0;
+    let mut offset = 0;
+
+    while offset < content.len() {
+        let mut buf = Cursor::new(&content[offset..]);
+        let frame = match Frame::check(&mut buf) {
+            Ok(()) => {
+                buf.set_position(0);
+                Frame::parse(&mut buf).map_err(|err| corrupt(offset, err))?
+            }
+            Err(frame::Error::Incomplete) => {
+                eprintln!(
+                    "[!] Dropping the truncated last {} bytes of {:?}",
+                    content.len() - offset,
+                    path
+                );
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(offset as u64)?;
+                break;
+            }
+            Err(err) => return Err(corrupt(offset, err)),
+        };
+
+        let command = Command::from_frame(frame).map_err(|err| corrupt(offset, err))?;
+        if let Frame::Error(err) = command.apply(shared_db, &pub_sub) {
+            return Err(corrupt(offset, err));
+        }
+
+        offset += buf.position() as usize;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn corrupt(offset: usize, reason: impl ToString) -> AofError {
+    AofError::Corrupt {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::db::{get_db, lock_shards, new_shared_db};
+    use crate::my_redis::test_util::command;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("learn_rust_{}_{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run(aof: &AppendOnlyLog, shared_db: &ShardedDb, parts: &[&str]) -> Frame {
+        let pub_sub = PubSub::default();
+        aof.apply_logged(command(parts), |command| command.apply(shared_db, &pub_sub))
+    }
+
+    fn get(shared_db: &ShardedDb, key: &str) -> Option<Bytes> {
+        get_db(shared_db, key).lock().unwrap().get(key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn append_and_replay() {
+        let dir = TempDir::new("aof_replay");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Always).unwrap();
+        run(&aof, &shared_db, &["SET", "a", "1"]);
+        run(&aof, &shared_db, &["SET", "b", "2", "EX", "100"]);
+        run(&aof, &shared_db, &["GET", "a"]);
+        run(&aof, &shared_db, &["SET", "a", "3"]);
+        run(&aof, &shared_db, &["EXPIRE", "a", "-1"]);
+        drop(aof);
+
+        let replayed = new_shared_db(2);
+        assert_eq!(4, replay(&replayed, &path).unwrap());
+        assert_eq!(None, get(&replayed, "a"));
+        assert_eq!(Some(Bytes::from("2")), get(&replayed, "b"));
+        let ttl = get_db(&replayed, "b").lock().unwrap().ttl("b").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(98));
+    }
+
+    #[tokio::test]
+    async fn drop_truncated_tail() {
+        let dir = TempDir::new("aof_truncated");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        run(&aof, &shared_db, &["SET", "a", "1"]);
+        run(&aof, &shared_db, &["SET", "b", "2"]);
+        drop(aof);
+
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+        let replayed = new_shared_db(4);
+        assert_eq!(1, replay(&replayed, &path).unwrap());
+        assert_eq!(Some(Bytes::from("1")), get(&replayed, "a"));
+        assert_eq!(None, get(&replayed, "b"));
+        assert!(fs::metadata(&path).unwrap().len() < len - 5);
+
+        fs::write(&path, b"+not a command\r\n").unwrap();
+        assert!(matches!(
+            replay(&new_shared_db(4), &path),
+            Err(AofError::Corrupt { offset: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn rewrite_compacts_the_log() {
+        let dir = TempDir::new("aof_rewrite");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..100 {
+            run(&aof, &shared_db, &["SET", "counter", &i.to_string()]);
+        }
+        run(&aof, &shared_db, &["SET", "session", "s", "PX", "60000"]);
+        let before = fs::metadata(&path).unwrap().len();
+
+        assert_eq!(2, aof.rewrite(&shared_db).unwrap());
+        run(&aof, &shared_db, &["SET", "after", "rewrite"]);
+        assert!(fs::metadata(&path).unwrap().len() < before);
+
+        let replayed = new_shared_db(4);
+        assert_eq!(3, replay(&replayed, &path).unwrap());
+        assert_eq!(Some(Bytes::from("99")), get(&replayed, "counter"));
+        assert_eq!(Some(Bytes::from("rewrite")), get(&replayed, "after"));
+        assert!(get_db(&replayed, "session").lock().unwrap().ttl("session").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rewrite_lists() {
+        let dir = TempDir::new("aof_rewrite_lists");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..150 {
+            run(&aof, &shared_db, &["RPUSH", "queue", &i.to_string()]);
+        }
+        run(&aof, &shared_db, &["LPOP", "queue", "10"]);
+        run(&aof, &shared_db, &["EXPIRE", "queue", "60"]);
+
+        let queue = |shared_db: &ShardedDb| {
+            get_db(shared_db, "queue").lock().unwrap().value("queue").cloned()
+        };
+        let replayed = new_shared_db(4);
+        replay(&replayed, &path).unwrap();
+        assert_eq!(queue(&shared_db), queue(&replayed));
+
+        aof.rewrite(&shared_db).unwrap();
+        let rewritten = new_shared_db(4);
+        // 140 elements take 3 pushes, then the expiration.
+        assert_eq!(4, replay(&rewritten, &path).unwrap());
+        assert_eq!(queue(&shared_db), queue(&rewritten));
+        assert!(get_db(&rewritten, "queue").lock().unwrap().ttl("queue").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rewrite_hashes() {
+        let dir = TempDir::new("aof_rewrite_hashes");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..100 {
+            run(&aof, &shared_db, &["HSET", "profile", &format!("field:{}", i), "v"]);
+        }
+        run(&aof, &shared_db, &["HINCRBY", "profile", "visits", "3"]);
+        run(&aof, &shared_db, &["HDEL", "profile", "field:0"]);
+
+        let profile = |shared_db: &ShardedDb| {
+            get_db(shared_db, "profile").lock().unwrap().value("profile").cloned()
+        };
+        aof.rewrite(&shared_db).unwrap();
+        let rewritten = new_shared_db(4);
+        // 100 fields take 2 commands.
+        assert_eq!(2, replay(&rewritten, &path).unwrap());
+        assert_eq!(profile(&shared_db), profile(&rewritten));
+    }
+
+    #[tokio::test]
+    async fn rewrite_sorted_sets() {
+        let dir = TempDir::new("aof_rewrite_sorted_sets");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..100 {
+            run(&aof, &shared_db, &["ZADD", "board", &(i as f64 / 3.0).to_string(), &format!("player:{}", i)]);
+        }
+        run(&aof, &shared_db, &["ZADD", "board", "XX", "-inf", "player:0", "1e300", "player:1"]);
+        run(&aof, &shared_db, &["ZREM", "board", "player:2"]);
+
+        let board = |shared_db: &ShardedDb| get_db(shared_db, "board").lock().unwrap().value("board").cloned();
+        let replayed = new_shared_db(4);
+        replay(&replayed, &path).unwrap();
+        assert_eq!(board(&shared_db), board(&replayed));
+
+        aof.rewrite(&shared_db).unwrap();
+        let rewritten = new_shared_db(4);
+        // 99 members take 2 commands.
+        assert_eq!(2, replay(&rewritten, &path).unwrap());
+        assert_eq!(board(&shared_db), board(&rewritten));
+    }
+
+    #[tokio::test]
+    async fn log_transactions() {
+        let dir = TempDir::new("aof_transactions");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        run(&aof, &shared_db, &["SET", "name", "ada"]);
+
+        let commands = [&["INCR", "n"][..], &["INCR", "name"], &["GET", "n"], &["RPUSH", "l", "a"]]
+            .into_iter()
+            .map(command)
+            .collect();
+        let pub_sub = PubSub::default();
+        let replies = aof.apply_logged_all(commands, |commands: Vec<Command>| {
+            let keys: Vec<&str> = commands.iter().flat_map(Command::keys).collect();
+            let mut shards = lock_shards(&shared_db, &keys);
+            Command::apply_all(commands, &mut shards, &pub_sub)
+        });
+        assert!(matches!(replies[1], Frame::Error(_)));
+
+        // The failed INCR and the read-only GET are left out.
+        let replayed = new_shared_db(4);
+        assert_eq!(3, replay(&replayed, &path).unwrap());
+        assert_eq!(Some(Bytes::from("1")), get(&replayed, "n"));
+        assert_eq!(Some(Bytes::from("ada")), get(&replayed, "name"));
+    }
+
+    #[tokio::test]
+    async fn log_concurrent_writes_in_order() {
+        let dir = TempDir::new("aof_concurrent");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (aof, shared_db) = (&aof, &shared_db);
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        let value = format!("{}:{}", thread, i);
+                        run(aof, shared_db, &["RPUSH", "list", &value]);
+                        run(aof, shared_db, &["SET", "last", &value]);
+                    }
+                });
+            }
+        });
+
+        let value = |shared_db: &ShardedDb, key: &str| {
+            get_db(shared_db, key).lock().unwrap().value(key).cloned()
+        };
+        let replayed = new_shared_db(4);
+        assert_eq!(1600, replay(&replayed, &path).unwrap());
+        assert_eq!(value(&shared_db, "list"), value(&replayed, "list"));
+        assert_eq!(value(&shared_db, "last"), value(&replayed, "last"));
+    }
+
+    #[tokio::test]
+    async fn rewrite_keeps_concurrent_writes() {
+        let dir = TempDir::new("aof_rewrite_concurrent");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..1000 {
+            run(&aof, &shared_db, &["SET", &format!("key:{}", i), "v"]);
+        }
+        std::thread::scope(|scope| {
+            let (aof, shared_db) = (&aof, &shared_db);
+            let writer = scope.spawn(move || {
+                for i in 0..500 {
+                    run(aof, shared_db, &["RPUSH", "list", &i.to_string()]);
+                }
+            });
+            for _ in 0..5 {
+                aof.rewrite(shared_db).unwrap();
+            }
+            writer.join().unwrap();
+        });
+
+        let list = |shared_db: &ShardedDb| get_db(shared_db, "list").lock().unwrap().value("list").cloned();
+        let replayed = new_shared_db(4);
+        replay(&replayed, &path).unwrap();
+        assert_eq!(list(&shared_db), list(&replayed));
+        assert_eq!(Some(Bytes::from("v")), get(&replayed, "key:999"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_appended_commands() {
+        let dir = TempDir::new("aof_sync_appended");
+        let path = dir.0.join("appendonly.aof");
+
+        let shared_db = new_shared_db(4);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Always).unwrap();
+        run(&aof, &shared_db, &["SET", "a", "1"]);
+        assert!(aof.synced.load(Ordering::Acquire) < aof.appended.load(Ordering::Acquire));
+        aof.sync_appended().await.unwrap();
+        assert_eq!(aof.synced.load(Ordering::Acquire), aof.appended.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn parse_fsync_policy() {
+        assert_eq!(Ok(FsyncPolicy::EverySec), "everysec".parse());
+        assert_eq!(Ok(FsyncPolicy::Never), "no".parse());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! `BLPOP` and `BRPOP`, which wait for an element on empty lists.
+//!
+//! A blocked client queues up on each of its keys, in their shard, see
+//! [`Shard::block`](super::db::Shard::block). A push wakes as many of the
+//! clients queued on the list as it holds elements, longest waiting first. Each
+//! of them then pops as a plain `LPOP` or `RPOP` would, through the append-only
+//! log, but only while the list holds an element for every client queued before
+//! it, so that a client woken later can't take the element of one woken earlier.
+//! A plain `LPOP` may still take the element first, in which case the woken
+//! clients keep their place and wait for the next push.
+//!
+//! Dropping a [`BlockedPop`] takes the client out of every queue, whether it
+//! popped, timed out or disconnected.
+
+use super::aof::SharedAof;
+use super::db::{lock_shards, ShardedDb};
+use super::error::Result;
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+static NEXT_WAITER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A client blocked on the lists of some keys.
+pub struct BlockedPop {
+    shared_db: ShardedDb,
+    keys: Vec<String>,
+    /// Pop from the head of the lists, for `BLPOP`.
+    left: bool,
+    id: u64,
+    notify: Arc<Notify>,
+}
+
+impl BlockedPop {
+    /// Queue a client up on every key in `keys`.
+    pub fn new(shared_db: ShardedDb, mut keys: Vec<String>, left: bool) -> BlockedPop {
+        let mut seen = HashSet::new();
+        keys.retain(|key| seen.insert(key.clone()));
+
+        let id = NEXT_WAITER_ID.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+        let mut shards = lock_shards(&shared_db, &keys);
+        for key in &keys {
+            shards.shard_mut(key).block(key, id, notify.clone());
+        }
+        drop(shards);
+
+        BlockedPop {
+            shared_db,
+            keys,
+            left,
+            id,
+            notify,
+        }
+    }
+
+    /// Pop an element from the first key whose list holds one for the client,
+    /// returning the key and the element, or `None` if no list does.
+    pub fn try_pop(&self, aof: Option<&SharedAof>) -> Result<Option<(String, Bytes)>> {
+        let pop = || {
+            let popped = self.pop();
+            let entries = match &popped {
+                Ok(Some((key, _))) => {
+                    let name = if self.left { "LPOP" } else { "RPOP" };
+                    vec![vec![Bytes::from_static(name.as_bytes()), Bytes::copy_from_slice(key.as_bytes())]]
+                }
+                _ => vec![],
+            };
+            (popped, entries)
+        };
+        match aof {
+            Some(aof) => aof.apply_logged_with(pop),
+            None => pop().0,
+        }
+    }
+
+    fn pop(&self) -> Result<Option<(String, Bytes)>> {
+        let mut shards = lock_shards(&self.shared_db, &self.keys);
+        for key in &self.keys {
+            let db = shards.shard_mut(key);
+            if !db.first_in_line(key, self.id)? {
+                continue;
+            }
+            let list = db
+                .value_mut(key)
+                .expect("first in line for a missing key")
+                .as_list_mut()?;
+            let element = if self.left { list.pop_front() } else { list.pop_back() };
+            let element = element.expect("first in line for an empty list");
+            db.remove_if_empty(key);
+            // Leave the queue right away, for the next client to be served.
+            db.unblock(key, self.id);
+            return Ok(Some((key.clone(), element)));
+        }
+        Ok(None)
+    }
+
+    /// Wait until a list the client is queued on may hold an element for it.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for BlockedPop {
+    fn drop(&mut self) {
+        let mut shards = lock_shards(&self.shared_db, &self.keys);
+        for key in &self.keys {
+            shards.shard_mut(key).unblock(key, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::aof::{replay, AppendOnlyLog, FsyncPolicy};
+    use crate::my_redis::db::new_shared_db;
+    use crate::my_redis::frame::Frame;
+    use crate::my_redis::pubsub::PubSub;
+    use crate::my_redis::test_util::command;
+
+    fn popped(key: &str, element: &'static str) -> Option<(String, Bytes)> {
+        Some((key.to_string(), Bytes::from(element)))
+    }
+
+    #[test]
+    fn blocked_pops_are_served_in_order() {
+        let db = new_shared_db(4);
+        let pub_sub = PubSub::default();
+
+        let first = BlockedPop::new(db.clone(), vec!["a".to_string(), "b".to_string()], true);
+        let second = BlockedPop::new(db.clone(), vec!["b".to_string()], false);
+        assert_eq!(None, first.try_pop(None).unwrap());
+
+        // The element is for the first client, even if the second one tries first.
+        command(&["RPUSH", "b", "x"]).apply(&db, &pub_sub);
+        assert_eq!(None, second.try_pop(None).unwrap());
+        assert_eq!(popped("b", "x"), first.try_pop(None).unwrap());
+        drop(first);
+
+        command(&["RPUSH", "b", "y", "z"]).apply(&db, &pub_sub);
+        assert_eq!(popped("b", "z"), second.try_pop(None).unwrap());
+        assert_eq!(Frame::Integer(1), command(&["LLEN", "b"]).apply(&db, &pub_sub));
+
+        // Gone clients leave nothing behind.
+        drop(second);
+        let shard = lock_shards(&db, &["a", "b"]);
+        assert_eq!(0, shard.shard("a").blocked_clients("a") + shard.shard("b").blocked_clients("b"));
+    }
+
+    #[test]
+    fn blocked_pops_are_logged() {
+        let path = std::env::temp_dir().join(format!("learn_rust_blocked_{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Never).unwrap();
+        let db = new_shared_db(4);
+        let pub_sub = PubSub::default();
+        let run = |parts: &[&str]| aof.apply_logged(command(parts), |command| command.apply(&db, &pub_sub));
+
+        let blocked = BlockedPop::new(db.clone(), vec!["jobs".to_string()], true);
+        assert_eq!(None, blocked.try_pop(Some(&aof)).unwrap());
+        run(&["RPUSH", "jobs", "1", "2"]);
+        assert_eq!(popped("jobs", "1"), blocked.try_pop(Some(&aof)).unwrap());
+
+        let replayed = new_shared_db(4);
+        assert_eq!(2, replay(&replayed, &path).unwrap());
+        assert_eq!(
+            Frame::Array(vec![Frame::Bulk(Bytes::from("2"))]),
+            command(&["LRANGE", "jobs", "0", "-1"]).apply(&replayed, &pub_sub)
+        );
+        let _ = std::fs::remove_file(&path);
+
+        // Keys of another type are an error.
+        run(&["SET", "name", "ada"]);
+        let blocked = BlockedPop::new(db.clone(), vec!["name".to_string(), "jobs".to_string()], true);
+        assert!(blocked.try_pop(None).is_err());
+    }
+}
@@ -1,36 +1,76 @@
 //use std::simd::intrinsics;
 
+use super::client::{self, Client, Result, Subscriber};
+use super::pool::{Pool, PoolConfig, PooledClient};
+use super::Frame;
 use tokio::net::ToSocketAddrs;
 use tokio::runtime::Runtime;
 use bytes::Bytes;
+use std::borrow::BorrowMut;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub use mini_redis::client::Message;
+pub use super::client::{Message, Pipeline, ReconnectPolicy};
 
 
-pub struct BlockingClient {
-    inner: mini_redis::client::Client,
-    rt: Runtime,
+/// A [`Client`] driven by its own single threaded runtime, for synchronous code,
+/// or a [`PooledClient`] driven by the runtime of its [`BlockingPool`].
+pub struct BlockingClient<C = Client> {
+    inner: C,
+    rt: Arc<Runtime>,
 }
 
-pub fn connect<T: ToSocketAddrs>(addr: T) -> mini_redis::Result<BlockingClient> {
+pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<BlockingClient> {
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
-    Ok(BlockingClient {
-        inner: rt.block_on(mini_redis::client::connect(addr))?,
-        rt,
-    })
+    let inner = rt.block_on(client::connect(addr))?;
+
+    Ok(BlockingClient { inner, rt: Arc::new(rt) })
 }
 
-impl BlockingClient {
-    pub fn get(&mut self, key: &str) -> mini_redis::Result<Option<Bytes>> {
-        self.rt.block_on(self.inner.get(key))
+/// A [`Pool`] driven by a runtime of its own, whose connections are checked
+/// out as [`BlockingClient`]s.
+#[derive(Clone)]
+pub struct BlockingPool {
+    inner: Pool,
+    rt: Arc<Runtime>,
+}
+
+/// Open a pool of connections to the server at `addr`, see [`Pool::connect`].
+pub fn connect_pool(addr: &str, config: PoolConfig) -> Result<BlockingPool> {
+    // Runs the health check of the pool in the background, and the I/O of the
+    // connections while several threads use them.
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()?;
+
+    let inner = rt.block_on(Pool::connect(addr, config))?;
+
+    Ok(BlockingPool { inner, rt: Arc::new(rt) })
+}
+
+impl BlockingPool {
+    /// Check a connection out, returned to the pool when dropped, see [`Pool::get`].
+    pub fn get(&self) -> Result<BlockingClient<PooledClient>> {
+        let inner = self.rt.block_on(self.inner.get())?;
+        Ok(BlockingClient { inner, rt: self.rt.clone() })
     }
 
-    pub fn set(&mut self, key: &str, value: Bytes) -> mini_redis::Result<()> {
-        self.rt.block_on(self.inner.set(key, value))
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle_connections()
+    }
+}
+
+impl<C: BorrowMut<Client>> BlockingClient<C> {
+    pub fn get(&mut self, key: &str) -> Result<Option<Bytes>> {
+        self.rt.block_on(self.inner.borrow_mut().get(key))
+    }
+
+    pub fn set(&mut self, key: &str, value: Bytes) -> Result<()> {
+        self.rt.block_on(self.inner.borrow_mut().set(key, value))
     }
 
     pub fn set_expires(
@@ -38,24 +78,63 @@ impl BlockingClient {
         key: &str,
         value: Bytes,
         expiration: Duration,
-    ) -> mini_redis::Result<()> {
-        self.rt.block_on(self.inner.set_expires(key, value, expiration))
+    ) -> Result<()> {
+        self.rt.block_on(self.inner.borrow_mut().set_expires(key, value, expiration))
+    }
+
+    pub fn publish(&mut self, channel: &str, message:Bytes) -> Result<u64> {
+        self.rt.block_on(self.inner.borrow_mut().publish(channel, message))
+    }
+
+    /// Increment the integer stored at `key` by one, returning the new value.
+    pub fn incr(&mut self, key: &str) -> Result<i64> {
+        self.rt.block_on(self.inner.borrow_mut().incr(key))
+    }
+
+    /// Decrement the integer stored at `key` by one, returning the new value.
+    pub fn decr(&mut self, key: &str) -> Result<i64> {
+        self.rt.block_on(self.inner.borrow_mut().decr(key))
+    }
+
+    pub fn incr_by(&mut self, key: &str, increment: i64) -> Result<i64> {
+        self.rt.block_on(self.inner.borrow_mut().incr_by(key, increment))
+    }
+
+    pub fn decr_by(&mut self, key: &str, decrement: i64) -> Result<i64> {
+        self.rt.block_on(self.inner.borrow_mut().decr_by(key, decrement))
+    }
+
+    /// Increment the number stored at `key` by `increment`, returning the new value.
+    pub fn incr_by_float(&mut self, key: &str, increment: f64) -> Result<f64> {
+        self.rt.block_on(self.inner.borrow_mut().incr_by_float(key, increment))
     }
 
-    pub fn publish(&mut self, channel: &str, message:Bytes) -> mini_redis::Result<u64> {
-        self.rt.block_on(self.inner.publish(channel, message))
+    /// Set how the client reconnects after losing its connection.
+    pub fn set_reconnect_policy(&mut self, reconnect: ReconnectPolicy) {
+        self.inner.borrow_mut().set_reconnect_policy(reconnect);
+    }
+
+    /// Check that the server answers.
+    pub fn ping(&mut self) -> Result<()> {
+        self.rt.block_on(self.inner.borrow_mut().ping())
+    }
+
+    /// Send the commands of `pipeline` and return their replies in order, see
+    /// [`Client::pipeline`].
+    pub fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Frame>> {
+        self.rt.block_on(self.inner.borrow_mut().pipeline(pipeline))
     }
 }
 
 pub struct BlockingSubscriber {
-    inner: mini_redis::client::Subscriber,
-    rt: Runtime,
+    inner: Subscriber,
+    rt: Arc<Runtime>,
 }
 
 impl BlockingClient {
-    pub fn subcribe(self, channels: Vec<String>) -> mini_redis::Result<BlockingSubscriber> {
-        let subscriber = self.rt.block_on(self.inner.subscribe(channels))?;
-        Ok(BlockingSubscriber { inner: subscriber, rt: self.rt })
+    pub fn subcribe(self, channels: Vec<String>) -> Result<BlockingSubscriber> {
+        let inner = self.rt.block_on(self.inner.subscribe(channels))?;
+        Ok(BlockingSubscriber { inner, rt: self.rt })
     }
 }
 
@@ -64,15 +143,131 @@ impl BlockingSubscriber {
         self.inner.get_subscribed()
     }
 
-    pub fn next_message(&mut self) -> mini_redis::Result<Option<Message>> {
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
         self.rt.block_on(self.inner.next_message())
     }
 
-    pub fn subscribe(&mut self, channels: &[String]) -> mini_redis::Result<()> {
+    pub fn subscribe(&mut self, channels: &[String]) -> Result<()> {
         self.rt.block_on(self.inner.subscribe(channels))
     }
 
-    pub fn unsubscribe(&mut self, channels: &[String]) -> mini_redis::Result<()> {
+    pub fn unsubscribe(&mut self, channels: &[String]) -> Result<()> {
         self.rt.block_on(self.inner.unsubscribe(channels))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::test_util;
+    use std::net::SocketAddr;
+
+    /// Start a server on its own runtime thread, returning its address.
+    fn start_server() -> SocketAddr {
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async {
+                addr_tx.send(test_util::start_server().await).unwrap();
+                std::future::pending::<()>().await
+            });
+        });
+        addr_rx.recv().unwrap()
+    }
+
+    #[test]
+    fn concurrent_counters() {
+        let addr = start_server();
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let mut client = connect(addr).unwrap();
+                    for _ in 0..100 {
+                        client.incr("hits").unwrap();
+                    }
+                    client.decr_by("hits", 10).unwrap();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let mut client = connect(addr).unwrap();
+        assert_eq!(721, client.incr_by("hits", 1).unwrap());
+        assert_eq!(720, client.decr("hits").unwrap());
+        assert_eq!(Some(Bytes::from("720")), client.get("hits").unwrap());
+        assert_eq!(720.5, client.incr_by_float("hits", 0.5).unwrap());
+
+        client.set("name", Bytes::from("ada")).unwrap();
+        let err = client.incr("name").unwrap_err();
+        assert_eq!("ERR value is not an integer or out of range", err.to_string());
+        // The connection is still usable after an error reply.
+        assert_eq!(Some(Bytes::from("ada")), client.get("name").unwrap());
+    }
+
+    #[test]
+    fn pipelined_bulk_load() {
+        let mut client = connect(start_server()).unwrap();
+
+        let mut pipeline = Pipeline::new();
+        for i in 0..500 {
+            pipeline.cmd(["RPUSH", "queue", &i.to_string()]);
+        }
+        let replies = client.pipeline(&pipeline).unwrap();
+        assert_eq!(Frame::Integer(500), replies[499]);
+        let replies = client.pipeline(Pipeline::new().cmd(["RPUSH", "queue", "last"])).unwrap();
+        assert_eq!(vec![Frame::Integer(501)], replies);
+    }
+
+    #[test]
+    fn pooled_clients() {
+        let addr = start_server();
+        let config = PoolConfig {
+            max_size: 2,
+            ..PoolConfig::default()
+        };
+        let pool = connect_pool(&addr.to_string(), config).unwrap();
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        pool.get().unwrap().incr("hits").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let mut client = pool.get().unwrap();
+        client.ping().unwrap();
+        assert_eq!(Some(Bytes::from("400")), client.get("hits").unwrap());
+        drop(client);
+        assert_eq!(2, pool.idle_connections());
+    }
+
+    #[test]
+    fn subscribe_and_publish() {
+        let addr = start_server();
+
+        let mut subscriber = connect(addr)
+            .unwrap()
+            .subcribe(vec!["news".to_string(), "sport".to_string()])
+            .unwrap();
+        let mut publisher = connect(addr).unwrap();
+        assert_eq!(1, publisher.publish("sport", Bytes::from("goal")).unwrap());
+
+        let message = subscriber.next_message().unwrap().unwrap();
+        assert_eq!("sport", message.channel);
+        assert_eq!(Bytes::from("goal"), message.content);
+
+        subscriber.unsubscribe(&["sport".to_string()]).unwrap();
+        assert_eq!(&["news".to_string()], subscriber.get_subscribed());
+        assert_eq!(0, publisher.publish("sport", Bytes::from("goal")).unwrap());
+    }
+}
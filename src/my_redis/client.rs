@@ -0,0 +1,1352 @@
+//! An async client for the server, on top of [`Connection`].
+//!
+//! [`Client`] has one method per command the server supports, which sends the
+//! command and converts its reply to a Rust type, error replies being returned
+//! as [`ClientError::Server`]. Replies are accepted in their RESP2 and RESP3
+//! forms alike, such as a map or a flat array for `HGETALL`. Anything else can
+//! be sent as a frame with [`Client::call`].
+//!
+//! A [`Pipeline`] sends many commands in a single write and reads their replies
+//! back in order, saving a round trip per command.
+//!
+//! A client whose connection is lost opens a new one before its next request,
+//! following its [`ReconnectPolicy`]. Requests which can safely run twice, see
+//! [`is_idempotent`], are sent again on the new connection, while the others
+//! fail with the connection error. A [`Subscriber`] subscribes to its channels
+//! again once reconnected.
+
+use super::connection::Connection;
+use super::error::Error;
+use super::eviction::Rng;
+use super::frame::Frame;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Error raised by a [`Client`].
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// The server sent something which is not a valid frame.
+    Protocol(String),
+    /// The server answered with an error reply, whose message this is.
+    Server(String),
+    /// The reply doesn't have the type the command replies with.
+    UnexpectedReply(Frame),
+    /// The server closed the connection.
+    ConnectionClosed,
+    /// No reply came within the timeout of the request.
+    Timeout,
+    /// The [`ClientHandle`](super::manager::ClientHandle) sending the request was shut down.
+    Shutdown,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "I/O error: {}", err),
+            ClientError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            ClientError::Server(message) => f.write_str(message),
+            ClientError::UnexpectedReply(frame) => write!(f, "unexpected reply {:?}", frame),
+            ClientError::ConnectionClosed => write!(f, "connection closed by the server"),
+            ClientError::Timeout => write!(f, "timed out waiting for the reply"),
+            ClientError::Shutdown => write!(f, "the connection manager has shut down"),
+        }
+    }
+}
+
+impl ClientError {
+    /// Whether the connection the error came from can't be used any more.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Io(_) | ClientError::Protocol(_) | ClientError::ConnectionClosed
+        )
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl From<Error> for ClientError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => ClientError::Io(err),
+            Error::Protocol(reason) => ClientError::Protocol(reason),
+            Error::RequestTooLarge => ClientError::Protocol("reply too large".to_string()),
+            err => ClientError::Protocol(err.to_string()),
+        }
+    }
+}
+
+/// A message received on a subscribed channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    pub content: Bytes,
+}
+
+/// How a [`Client`] reconnects after losing its connection.
+///
+/// The delay before each attempt but the first doubles from `initial_backoff`
+/// up to `max_backoff`, and is shortened by up to `jitter` of itself at random,
+/// so that clients which lost their connections together don't all come back
+/// at once.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// How many connections are attempted in a row before giving up, 0
+    /// disabling reconnection.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Return the delay before the retry number `retry`, from 0.
+    fn backoff(&self, retry: u32, rng: &mut Rng) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rng.next_f64())
+    }
+}
+
+/// A connection to the server.
+pub struct Client {
+    connection: Connection,
+    /// Set while a request waits for its reply, and for good after a
+    /// connection error, see [`Client::is_broken`].
+    broken: bool,
+    /// Where to reconnect to.
+    addr: SocketAddr,
+    /// The protocol switched to with [`Client::hello`], restored on reconnection.
+    protocol: Option<i64>,
+    /// Whether a transaction was started with `MULTI`, or keys watched with
+    /// `WATCH`, on the server. Both are lost with the connection, so requests
+    /// are not sent again on a new one meanwhile.
+    multi: bool,
+    watching: bool,
+    reconnect: ReconnectPolicy,
+    /// Draws the jitter of the backoff.
+    rng: Rng,
+}
+
+/// Connect to the server at `addr`.
+pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<Client> {
+    let socket = TcpStream::connect(addr).await?;
+    let addr = socket.peer_addr()?;
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    Ok(Client {
+        connection: Connection::new(socket),
+        broken: false,
+        addr,
+        protocol: None,
+        multi: false,
+        watching: false,
+        reconnect: ReconnectPolicy::default(),
+        rng: Rng::new(seed ^ u64::from(addr.port())),
+    })
+}
+
+/// Commands which leave the dataset the same whether they run once or twice,
+/// and are therefore sent again after a reconnection.
+///
+/// Those which only read are included, along with `SET` and `PEXPIREAT`,
+/// which overwrite. Counters, list pushes and pops, and `PUBLISH` are not.
+const IDEMPOTENT: &[&str] = &[
+    "GET", "SET", "EXPIREAT", "PEXPIREAT", "TTL", "PTTL", "LRANGE", "LLEN", "HGET", "HGETALL", "HLEN",
+    "SMEMBERS", "SISMEMBER", "SINTER", "SUNION", "SDIFF", "ZRANGE", "ZREVRANGE", "ZRANGEBYSCORE",
+    "ZREVRANGEBYSCORE", "ZRANK", "ZREVRANK", "ZSCORE", "ZCARD", "KEYS", "SCAN", "PING", "HELLO",
+];
+
+/// Whether `request` is sent again, rather than failing, when the connection is
+/// lost before its reply came.
+pub fn is_idempotent(request: &Frame) -> bool {
+    match request {
+        Frame::Array(parts) => match parts.first() {
+            Some(Frame::Bulk(name)) => IDEMPOTENT
+                .iter()
+                .any(|idempotent| name.eq_ignore_ascii_case(idempotent.as_bytes())),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// How many commands of a pipeline are written before reading their replies, so
+/// that the replies never pile up past what the socket buffers hold while the
+/// client is still writing.
+const PIPELINE_BATCH: usize = 1024;
+
+/// Commands queued to be sent together by [`Client::pipeline`].
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    requests: Vec<Frame>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Queue the command made of `parts`, such as `["SET", "key", "value"]`.
+    pub fn cmd<A: AsRef<[u8]>>(&mut self, parts: impl IntoIterator<Item = A>) -> &mut Pipeline {
+        let parts = parts
+            .into_iter()
+            .map(|part| Frame::Bulk(Bytes::copy_from_slice(part.as_ref())))
+            .collect();
+        self.requests.push(Frame::Array(parts));
+        self
+    }
+
+    /// Queue a command frame as it is.
+    pub fn frame(&mut self, request: Frame) -> &mut Pipeline {
+        self.requests.push(request);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/// A command frame under construction, an array of bulk strings.
+struct Request(Vec<Frame>);
+
+impl Request {
+    fn new(name: &'static str) -> Request {
+        Request(vec![Frame::Bulk(Bytes::from_static(name.as_bytes()))])
+    }
+
+    /// Append an argument, copying it.
+    fn arg(mut self, arg: impl AsRef<[u8]>) -> Request {
+        self.0.push(Frame::Bulk(Bytes::copy_from_slice(arg.as_ref())));
+        self
+    }
+
+    /// Append an argument without copying it, for values.
+    fn bulk(mut self, bulk: Bytes) -> Request {
+        self.0.push(Frame::Bulk(bulk));
+        self
+    }
+
+    fn args<A: AsRef<[u8]>>(self, args: impl IntoIterator<Item = A>) -> Request {
+        args.into_iter().fold(self, Request::arg)
+    }
+
+    fn into_frame(self) -> Frame {
+        Frame::Array(self.0)
+    }
+}
+
+impl Client {
+    /// Send `request` and wait for its reply, error replies being returned as
+    /// [`ClientError::Server`].
+    ///
+    /// This is the way to send commands this client has no method for, or with
+    /// options its methods don't take.
+    ///
+    /// Within a transaction or while keys are watched, requests are never sent
+    /// again after a reconnection: the connection error is returned instead,
+    /// the transaction and the watches being lost with the connection.
+    pub async fn call(&mut self, request: Frame) -> Result<Frame> {
+        let retry = is_idempotent(&request) && !self.in_transaction();
+        let mut attempts = 0;
+        loop {
+            if self.broken && self.can_reconnect() {
+                self.check_session()?;
+                self.reconnect(&mut attempts).await?;
+            }
+            match self.exchange(&request).await {
+                Err(err) if retry && err.is_connection_error() && attempts < self.reconnect.max_attempts => {}
+                reply => return reply,
+            }
+        }
+    }
+
+    async fn exchange(&mut self, request: &Frame) -> Result<Frame> {
+        let broken = std::mem::replace(&mut self.broken, true);
+        let reply = match self.connection.write_frame(request).await {
+            Ok(()) => self.read_reply().await,
+            Err(err) => Err(err.into()),
+        };
+        self.track(request, reply.as_ref());
+        if !matches!(&reply, Err(err) if err.is_connection_error()) {
+            self.broken = broken;
+        }
+        reply
+    }
+
+    /// Whether a transaction is started or keys are watched on the server.
+    fn in_transaction(&self) -> bool {
+        self.multi || self.watching
+    }
+
+    /// Fail with [`ClientError::ConnectionClosed`] if the broken connection
+    /// held a transaction or watches, forgetting them, rather than letting the
+    /// request go to a new connection without them.
+    fn check_session(&mut self) -> Result<()> {
+        if self.in_transaction() {
+            self.multi = false;
+            self.watching = false;
+            return Err(ClientError::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    /// Follow the transaction and watches on the server through the `reply` to `request`.
+    fn track(&mut self, request: &Frame, reply: std::result::Result<&Frame, &ClientError>) {
+        let name = match request {
+            Frame::Array(parts) => match parts.first() {
+                Some(Frame::Bulk(name)) => name.to_ascii_uppercase(),
+                _ => return,
+            },
+            _ => return,
+        };
+        let succeeded = matches!(reply, Ok(reply) if !matches!(reply, Frame::Error(_)));
+        match reply {
+            Err(err) if err.is_connection_error() => {
+                self.multi = false;
+                self.watching = false;
+            }
+            // `EXEC` and `DISCARD` end the transaction even when they fail.
+            _ if name == b"EXEC" || name == b"DISCARD" => {
+                self.multi = false;
+                self.watching = false;
+            }
+            _ if !succeeded => {}
+            _ if name == b"MULTI" => self.multi = true,
+            // Within a transaction, these are queued rather than applied.
+            _ if name == b"WATCH" && !self.multi => self.watching = true,
+            _ if name == b"UNWATCH" && !self.multi => self.watching = false,
+            _ => {}
+        }
+    }
+
+    /// Whether the connection can't be used any more, because of a connection
+    /// error or because a request was dropped before its reply was read, which
+    /// would be taken for the reply to the next one.
+    ///
+    /// A broken client reconnects before its next request, unless reconnection
+    /// is disabled.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    pub fn set_reconnect_policy(&mut self, reconnect: ReconnectPolicy) {
+        self.reconnect = reconnect;
+    }
+
+    /// Whether the client opens a new connection once its connection is lost.
+    pub fn can_reconnect(&self) -> bool {
+        self.reconnect.max_attempts > 0
+    }
+
+    /// Open a new connection in place of the broken one, counting the
+    /// `attempts` made along with the previous ones for the same request.
+    async fn reconnect(&mut self, attempts: &mut u32) -> Result<()> {
+        loop {
+            if *attempts > 0 {
+                tokio::time::sleep(self.reconnect.backoff(*attempts - 1, &mut self.rng)).await;
+            }
+            *attempts += 1;
+            match self.open().await {
+                Ok(()) => return Ok(()),
+                Err(err) if *attempts >= self.reconnect.max_attempts => return Err(err),
+                Err(_) => {}
+            }
+        }
+    }
+
+    async fn open(&mut self) -> Result<()> {
+        let socket = TcpStream::connect(self.addr).await?;
+        self.connection = Connection::new(socket);
+        self.broken = false;
+        if let Some(protocol) = self.protocol {
+            self.exchange(&Request::new("HELLO").arg(protocol.to_string()).into_frame())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn read_reply(&mut self) -> Result<Frame> {
+        match self.connection.read_frame().await? {
+            Some(Frame::Error(message)) => Err(ClientError::Server(message)),
+            Some(frame) => Ok(frame),
+            None => Err(ClientError::ConnectionClosed),
+        }
+    }
+
+    /// Send the commands of `pipeline` and return their replies in order.
+    ///
+    /// Error replies are returned as [`Frame::Error`] among the others, so that
+    /// a failed command doesn't hide the replies to the following ones.
+    ///
+    /// The whole pipeline is sent again after a reconnection if all its commands
+    /// are [idempotent](is_idempotent), outside of transactions as for [`call`](Client::call).
+    pub async fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Frame>> {
+        let retry = pipeline.requests.iter().all(is_idempotent) && !self.in_transaction();
+        let mut attempts = 0;
+        loop {
+            if self.broken && self.can_reconnect() {
+                self.check_session()?;
+                self.reconnect(&mut attempts).await?;
+            }
+            match self.exchange_all(pipeline).await {
+                Err(err) if retry && err.is_connection_error() && attempts < self.reconnect.max_attempts => {}
+                replies => return replies,
+            }
+        }
+    }
+
+    async fn exchange_all(&mut self, pipeline: &Pipeline) -> Result<Vec<Frame>> {
+        let broken = std::mem::replace(&mut self.broken, true);
+        let replies = self.write_and_read_all(pipeline).await;
+        match &replies {
+            Ok(replies) => {
+                for (request, reply) in pipeline.requests.iter().zip(replies) {
+                    self.track(request, Ok(reply));
+                }
+                self.broken = broken;
+            }
+            Err(_) => {
+                self.multi = false;
+                self.watching = false;
+            }
+        }
+        replies
+    }
+
+    async fn write_and_read_all(&mut self, pipeline: &Pipeline) -> Result<Vec<Frame>> {
+        let mut replies = Vec::with_capacity(pipeline.len());
+        for batch in pipeline.requests.chunks(PIPELINE_BATCH) {
+            self.connection.write_frames(batch).await?;
+            for _ in batch {
+                match self.connection.read_frame().await? {
+                    Some(reply) => replies.push(reply),
+                    None => return Err(ClientError::ConnectionClosed),
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    async fn send(&mut self, request: Request) -> Result<Frame> {
+        self.call(request.into_frame()).await
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<Bytes>> {
+        optional_bulk(self.send(Request::new("GET").arg(key)).await?)
+    }
+
+    pub async fn set(&mut self, key: &str, value: Bytes) -> Result<()> {
+        ok(self.send(Request::new("SET").arg(key).bulk(value)).await?)
+    }
+
+    /// Set `key` to `value`, expiring after `expiration`.
+    pub async fn set_expires(&mut self, key: &str, value: Bytes, expiration: Duration) -> Result<()> {
+        let millis = expiration.as_millis().to_string();
+        ok(self.send(Request::new("SET").arg(key).bulk(value).arg("PX").arg(millis)).await?)
+    }
+
+    /// Increment the integer stored at `key` by one, returning the new value.
+    pub async fn incr(&mut self, key: &str) -> Result<i64> {
+        integer(self.send(Request::new("INCR").arg(key)).await?)
+    }
+
+    /// Decrement the integer stored at `key` by one, returning the new value.
+    pub async fn decr(&mut self, key: &str) -> Result<i64> {
+        integer(self.send(Request::new("DECR").arg(key)).await?)
+    }
+
+    pub async fn incr_by(&mut self, key: &str, increment: i64) -> Result<i64> {
+        integer(self.send(Request::new("INCRBY").arg(key).arg(increment.to_string())).await?)
+    }
+
+    pub async fn decr_by(&mut self, key: &str, decrement: i64) -> Result<i64> {
+        integer(self.send(Request::new("DECRBY").arg(key).arg(decrement.to_string())).await?)
+    }
+
+    /// Increment the number stored at `key` by `increment`, returning the new value.
+    pub async fn incr_by_float(&mut self, key: &str, increment: f64) -> Result<f64> {
+        float(self.send(Request::new("INCRBYFLOAT").arg(key).arg(increment.to_string())).await?)
+    }
+
+    /// Make `key` expire after `timeout`, returning whether it exists.
+    pub async fn expire(&mut self, key: &str, timeout: Duration) -> Result<bool> {
+        let millis = timeout.as_millis().to_string();
+        boolean(self.send(Request::new("PEXPIRE").arg(key).arg(millis)).await?)
+    }
+
+    /// Make `key` expire at `when`, returning whether it exists.
+    pub async fn expire_at(&mut self, key: &str, when: SystemTime) -> Result<bool> {
+        let millis = when.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis().to_string();
+        boolean(self.send(Request::new("PEXPIREAT").arg(key).arg(millis)).await?)
+    }
+
+    /// Return the remaining time to live of `key`.
+    ///
+    /// `None` if the key does not exist, `Some(None)` if it exists without an expiration.
+    pub async fn ttl(&mut self, key: &str) -> Result<Option<Option<Duration>>> {
+        match integer(self.send(Request::new("PTTL").arg(key)).await?)? {
+            -2 => Ok(None),
+            -1 => Ok(Some(None)),
+            millis => Ok(Some(Some(Duration::from_millis(millis.max(0) as u64)))),
+        }
+    }
+
+    /// Remove the expiration of `key`, returning whether it had one.
+    pub async fn persist(&mut self, key: &str) -> Result<bool> {
+        boolean(self.send(Request::new("PERSIST").arg(key)).await?)
+    }
+
+    /// Push `elements` at the head of the list of `key`, returning its new length.
+    pub async fn lpush(&mut self, key: &str, elements: &[Bytes]) -> Result<u64> {
+        count(self.send(push(Request::new("LPUSH").arg(key), elements)).await?)
+    }
+
+    /// Push `elements` at the tail of the list of `key`, returning its new length.
+    pub async fn rpush(&mut self, key: &str, elements: &[Bytes]) -> Result<u64> {
+        count(self.send(push(Request::new("RPUSH").arg(key), elements)).await?)
+    }
+
+    pub async fn lpop(&mut self, key: &str) -> Result<Option<Bytes>> {
+        optional_bulk(self.send(Request::new("LPOP").arg(key)).await?)
+    }
+
+    pub async fn rpop(&mut self, key: &str) -> Result<Option<Bytes>> {
+        optional_bulk(self.send(Request::new("RPOP").arg(key)).await?)
+    }
+
+    /// Pop up to `count` elements from the head of the list of `key`.
+    pub async fn lpop_count(&mut self, key: &str, count: usize) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("LPOP").arg(key).arg(count.to_string())).await?)
+    }
+
+    /// Pop up to `count` elements from the tail of the list of `key`.
+    pub async fn rpop_count(&mut self, key: &str, count: usize) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("RPOP").arg(key).arg(count.to_string())).await?)
+    }
+
+    /// Pop the head of the first list of `keys` holding an element, waiting up to
+    /// `timeout` for one, forever if it is zero.
+    ///
+    /// Return the key popped from along with the element, `None` on timeout.
+    pub async fn blpop(&mut self, keys: &[&str], timeout: Duration) -> Result<Option<(String, Bytes)>> {
+        let request = Request::new("BLPOP").args(keys).arg(timeout.as_secs_f64().to_string());
+        popped(self.send(request).await?)
+    }
+
+    /// Pop the tail of the first list of `keys` holding an element, see [`blpop`](Client::blpop).
+    pub async fn brpop(&mut self, keys: &[&str], timeout: Duration) -> Result<Option<(String, Bytes)>> {
+        let request = Request::new("BRPOP").args(keys).arg(timeout.as_secs_f64().to_string());
+        popped(self.send(request).await?)
+    }
+
+    /// Return the elements of the list of `key` between the ranks `start` and
+    /// `stop` included, negative ranks counting from the end.
+    pub async fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<Bytes>> {
+        let request = Request::new("LRANGE").arg(key).arg(start.to_string()).arg(stop.to_string());
+        bulks(self.send(request).await?)
+    }
+
+    pub async fn llen(&mut self, key: &str) -> Result<u64> {
+        count(self.send(Request::new("LLEN").arg(key)).await?)
+    }
+
+    /// Set the fields of the hash of `key`, returning how many were added.
+    pub async fn hset(&mut self, key: &str, pairs: &[(Bytes, Bytes)]) -> Result<u64> {
+        let request = pairs
+            .iter()
+            .fold(Request::new("HSET").arg(key), |request, (field, value)| {
+                request.bulk(field.clone()).bulk(value.clone())
+            });
+        count(self.send(request).await?)
+    }
+
+    pub async fn hget(&mut self, key: &str, field: &[u8]) -> Result<Option<Bytes>> {
+        optional_bulk(self.send(Request::new("HGET").arg(key).arg(field)).await?)
+    }
+
+    /// Remove `fields` from the hash of `key`, returning how many existed.
+    pub async fn hdel(&mut self, key: &str, fields: &[&[u8]]) -> Result<u64> {
+        count(self.send(Request::new("HDEL").arg(key).args(fields)).await?)
+    }
+
+    /// Return the fields of the hash of `key` along with their values.
+    pub async fn hgetall(&mut self, key: &str) -> Result<Vec<(Bytes, Bytes)>> {
+        bulk_pairs(self.send(Request::new("HGETALL").arg(key)).await?)
+    }
+
+    pub async fn hincrby(&mut self, key: &str, field: &[u8], increment: i64) -> Result<i64> {
+        let request = Request::new("HINCRBY").arg(key).arg(field).arg(increment.to_string());
+        integer(self.send(request).await?)
+    }
+
+    pub async fn hlen(&mut self, key: &str) -> Result<u64> {
+        count(self.send(Request::new("HLEN").arg(key)).await?)
+    }
+
+    /// Add `members` to the set of `key`, returning how many were not members yet.
+    pub async fn sadd(&mut self, key: &str, members: &[Bytes]) -> Result<u64> {
+        count(self.send(push(Request::new("SADD").arg(key), members)).await?)
+    }
+
+    /// Remove `members` from the set of `key`, returning how many were members.
+    pub async fn srem(&mut self, key: &str, members: &[Bytes]) -> Result<u64> {
+        count(self.send(push(Request::new("SREM").arg(key), members)).await?)
+    }
+
+    pub async fn smembers(&mut self, key: &str) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("SMEMBERS").arg(key)).await?)
+    }
+
+    pub async fn sismember(&mut self, key: &str, member: &[u8]) -> Result<bool> {
+        boolean(self.send(Request::new("SISMEMBER").arg(key).arg(member)).await?)
+    }
+
+    pub async fn sinter(&mut self, keys: &[&str]) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("SINTER").args(keys)).await?)
+    }
+
+    pub async fn sunion(&mut self, keys: &[&str]) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("SUNION").args(keys)).await?)
+    }
+
+    /// Return the members of the first set of `keys` which are in none of the others.
+    pub async fn sdiff(&mut self, keys: &[&str]) -> Result<Vec<Bytes>> {
+        bulks(self.send(Request::new("SDIFF").args(keys)).await?)
+    }
+
+    /// Add `pairs` of scores and members to the sorted set of `key`, updating
+    /// the scores of existing members, and return how many were added.
+    pub async fn zadd(&mut self, key: &str, pairs: &[(f64, Bytes)]) -> Result<u64> {
+        let request = pairs
+            .iter()
+            .fold(Request::new("ZADD").arg(key), |request, (score, member)| {
+                request.arg(score.to_string()).bulk(member.clone())
+            });
+        count(self.send(request).await?)
+    }
+
+    /// Return the members of the sorted set of `key` between the ranks `start`
+    /// and `stop` included, by ascending score.
+    pub async fn zrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<Bytes>> {
+        let request = Request::new("ZRANGE").arg(key).arg(start.to_string()).arg(stop.to_string());
+        bulks(self.send(request).await?)
+    }
+
+    /// Return the members between the ranks `start` and `stop` along with their
+    /// scores, see [`zrange`](Client::zrange).
+    pub async fn zrange_with_scores(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<(Bytes, f64)>> {
+        let request = Request::new("ZRANGE")
+            .arg(key)
+            .arg(start.to_string())
+            .arg(stop.to_string())
+            .arg("WITHSCORES");
+        scored(self.send(request).await?)
+    }
+
+    /// Return the members of the sorted set of `key` whose score lies between
+    /// `min` and `max` included, by ascending score.
+    pub async fn zrange_by_score(&mut self, key: &str, min: f64, max: f64) -> Result<Vec<Bytes>> {
+        let request = Request::new("ZRANGEBYSCORE")
+            .arg(key)
+            .arg(min.to_string())
+            .arg(max.to_string());
+        bulks(self.send(request).await?)
+    }
+
+    /// Return the rank of `member` by ascending score, `None` if it is not a member.
+    pub async fn zrank(&mut self, key: &str, member: &[u8]) -> Result<Option<u64>> {
+        optional_count(self.send(Request::new("ZRANK").arg(key).arg(member)).await?)
+    }
+
+    /// Return the rank of `member` by descending score, `None` if it is not a member.
+    pub async fn zrevrank(&mut self, key: &str, member: &[u8]) -> Result<Option<u64>> {
+        optional_count(self.send(Request::new("ZREVRANK").arg(key).arg(member)).await?)
+    }
+
+    /// Remove `members` from the sorted set of `key`, returning how many were members.
+    pub async fn zrem(&mut self, key: &str, members: &[Bytes]) -> Result<u64> {
+        count(self.send(push(Request::new("ZREM").arg(key), members)).await?)
+    }
+
+    pub async fn zscore(&mut self, key: &str, member: &[u8]) -> Result<Option<f64>> {
+        match self.send(Request::new("ZSCORE").arg(key).arg(member)).await? {
+            Frame::Null => Ok(None),
+            frame => float(frame).map(Some),
+        }
+    }
+
+    pub async fn zcard(&mut self, key: &str) -> Result<u64> {
+        count(self.send(Request::new("ZCARD").arg(key)).await?)
+    }
+
+    /// Delete `keys`, returning how many existed.
+    pub async fn del(&mut self, keys: &[&str]) -> Result<u64> {
+        count(self.send(Request::new("DEL").args(keys)).await?)
+    }
+
+    /// Return every key matching the glob `pattern`.
+    pub async fn keys(&mut self, pattern: &str) -> Result<Vec<String>> {
+        strings(self.send(Request::new("KEYS").arg(pattern)).await?)
+    }
+
+    /// Visit about `count` keys from `cursor`, 0 starting a new scan, and return
+    /// the cursor to resume from, 0 once done, along with the keys visited which
+    /// match the glob `pattern`.
+    pub async fn scan(
+        &mut self,
+        cursor: u64,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(u64, Vec<String>)> {
+        let mut request = Request::new("SCAN").arg(cursor.to_string());
+        if let Some(pattern) = pattern {
+            request = request.arg("MATCH").arg(pattern);
+        }
+        let frame = self.send(request.arg("COUNT").arg(count.to_string())).await?;
+        match frame {
+            Frame::Array(mut parts) if parts.len() == 2 => {
+                let keys = strings(parts.pop().unwrap())?;
+                let cursor = parts.pop().unwrap();
+                let parsed = match &cursor {
+                    Frame::Bulk(value) => std::str::from_utf8(value).ok().and_then(|value| value.parse().ok()),
+                    _ => None,
+                };
+                parsed
+                    .map(|cursor| (cursor, keys))
+                    .ok_or(ClientError::UnexpectedReply(cursor))
+            }
+            frame => Err(ClientError::UnexpectedReply(frame)),
+        }
+    }
+
+    /// Check that the server answers.
+    pub async fn ping(&mut self) -> Result<()> {
+        status(self.send(Request::new("PING")).await?)
+    }
+
+    /// Publish `message` on `channel`, returning how many subscribers received it.
+    pub async fn publish(&mut self, channel: &str, message: Bytes) -> Result<u64> {
+        count(self.send(Request::new("PUBLISH").arg(channel).bulk(message)).await?)
+    }
+
+    /// Subscribe to `channels`, turning the client into a [`Subscriber`].
+    pub async fn subscribe(self, channels: Vec<String>) -> Result<Subscriber> {
+        let mut subscriber = Subscriber {
+            client: self,
+            subscribed: vec![],
+            pending: VecDeque::new(),
+        };
+        subscriber.subscribe(&channels).await?;
+        Ok(subscriber)
+    }
+
+    /// Save a snapshot of the dataset, returning once it is written.
+    pub async fn save(&mut self) -> Result<()> {
+        ok(self.send(Request::new("SAVE")).await?)
+    }
+
+    /// Start saving a snapshot of the dataset in the background.
+    pub async fn bgsave(&mut self) -> Result<()> {
+        status(self.send(Request::new("BGSAVE")).await?)
+    }
+
+    /// Start rewriting the append-only log in the background.
+    pub async fn bgrewriteaof(&mut self) -> Result<()> {
+        status(self.send(Request::new("BGREWRITEAOF")).await?)
+    }
+
+    /// Start a transaction: the following commands are queued with
+    /// [`queue`](Client::queue) until [`exec`](Client::exec) or [`discard`](Client::discard).
+    pub async fn multi(&mut self) -> Result<()> {
+        ok(self.send(Request::new("MULTI")).await?)
+    }
+
+    /// Queue `request` in the transaction started by [`multi`](Client::multi).
+    pub async fn queue(&mut self, request: Frame) -> Result<()> {
+        match self.call(request).await? {
+            Frame::Simple(status) if status == "QUEUED" => Ok(()),
+            frame => Err(ClientError::UnexpectedReply(frame)),
+        }
+    }
+
+    /// Apply the queued commands, returning their replies, or `None` if a
+    /// watched key was modified and nothing was applied.
+    pub async fn exec(&mut self) -> Result<Option<Vec<Frame>>> {
+        match self.send(Request::new("EXEC")).await? {
+            Frame::Array(replies) => Ok(Some(replies)),
+            Frame::Null | Frame::NullArray => Ok(None),
+            frame => Err(ClientError::UnexpectedReply(frame)),
+        }
+    }
+
+    pub async fn discard(&mut self) -> Result<()> {
+        ok(self.send(Request::new("DISCARD")).await?)
+    }
+
+    /// Watch `keys`, so that the next [`exec`](Client::exec) fails if any is modified meanwhile.
+    pub async fn watch(&mut self, keys: &[&str]) -> Result<()> {
+        ok(self.send(Request::new("WATCH").args(keys)).await?)
+    }
+
+    pub async fn unwatch(&mut self) -> Result<()> {
+        ok(self.send(Request::new("UNWATCH")).await?)
+    }
+
+    /// Switch the connection to the protocol version `protocol`, 2 or 3,
+    /// returning the properties of the server.
+    pub async fn hello(&mut self, protocol: i64) -> Result<Vec<(Frame, Frame)>> {
+        let reply = self.send(Request::new("HELLO").arg(protocol.to_string())).await?;
+        self.protocol = Some(protocol);
+        match reply {
+            Frame::Map(properties) => Ok(properties),
+            Frame::Array(properties) if properties.len() % 2 == 0 => Ok(pair_up(properties)),
+            frame => Err(ClientError::UnexpectedReply(frame)),
+        }
+    }
+}
+
+/// A client subscribed to channels, which can only receive messages and
+/// subscribe or unsubscribe.
+pub struct Subscriber {
+    client: Client,
+    subscribed: Vec<String>,
+    /// Messages received while waiting for the confirmations of a subscription change.
+    pending: VecDeque<Message>,
+}
+
+impl Subscriber {
+    pub fn get_subscribed(&self) -> &[String] {
+        &self.subscribed
+    }
+
+    /// Wait for the next message, `None` once the server closed the connection
+    /// if reconnection is disabled.
+    ///
+    /// Otherwise the subscriptions are made again on a new connection, and the
+    /// messages published meanwhile are lost.
+    pub async fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(Some(message));
+            }
+            let frame = match self.client.connection.read_frame().await {
+                Ok(Some(frame)) => frame,
+                Ok(None) if !self.client.can_reconnect() => return Ok(None),
+                Err(err) if !self.client.can_reconnect() => return Err(err.into()),
+                _ => {
+                    self.resubscribe().await?;
+                    continue;
+                }
+            };
+            return match into_message(frame)? {
+                Ok(message) => Ok(Some(message)),
+                Err(frame) => Err(ClientError::UnexpectedReply(frame)),
+            };
+        }
+    }
+
+    async fn resubscribe(&mut self) -> Result<()> {
+        self.client.reconnect(&mut 0).await?;
+        if !self.subscribed.is_empty() {
+            let request = Request::new("SUBSCRIBE").args(&self.subscribed).into_frame();
+            self.client.connection.write_frame(&request).await?;
+            self.confirmations(self.subscribed.len()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe(&mut self, channels: &[String]) -> Result<()> {
+        self.client.connection.write_frame(&Request::new("SUBSCRIBE").args(channels).into_frame()).await?;
+        self.confirmations(channels.len()).await?;
+        for channel in channels {
+            if !self.subscribed.contains(channel) {
+                self.subscribed.push(channel.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from `channels`, or from every channel if it is empty.
+    pub async fn unsubscribe(&mut self, channels: &[String]) -> Result<()> {
+        self.client.connection.write_frame(&Request::new("UNSUBSCRIBE").args(channels).into_frame()).await?;
+        // Without channels, every subscribed channel is confirmed, or a single
+        // confirmation is sent if there is none.
+        let expected = if channels.is_empty() { self.subscribed.len().max(1) } else { channels.len() };
+        self.confirmations(expected).await?;
+
+        if channels.is_empty() {
+            self.subscribed.clear();
+        } else {
+            self.subscribed.retain(|channel| !channels.contains(channel));
+        }
+        Ok(())
+    }
+
+    /// Read `expected` confirmations, keeping the messages received meanwhile for later.
+    async fn confirmations(&mut self, expected: usize) -> Result<()> {
+        let mut confirmed = 0;
+        while confirmed < expected {
+            match into_message(self.client.read_reply().await?)? {
+                Ok(message) => self.pending.push_back(message),
+                Err(_) => confirmed += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a frame received while subscribed to a message, or return it back
+/// if it is a confirmation.
+fn into_message(frame: Frame) -> Result<std::result::Result<Message, Frame>> {
+    let parts = match &frame {
+        Frame::Array(parts) | Frame::Push(parts) => parts,
+        _ => return Err(ClientError::UnexpectedReply(frame)),
+    };
+    match parts.as_slice() {
+        [Frame::Bulk(kind), Frame::Bulk(channel), Frame::Bulk(content)] if kind == "message" => {
+            match String::from_utf8(channel.to_vec()) {
+                Ok(channel) => Ok(Ok(Message {
+                    channel,
+                    content: content.clone(),
+                })),
+                Err(_) => Err(ClientError::UnexpectedReply(frame)),
+            }
+        }
+        [Frame::Bulk(kind), _, Frame::Integer(_)] if kind == "subscribe" || kind == "unsubscribe" => {
+            Ok(Err(frame))
+        }
+        _ => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn push(request: Request, elements: &[Bytes]) -> Request {
+    elements.iter().fold(request, |request, element| request.bulk(element.clone()))
+}
+
+fn ok(frame: Frame) -> Result<()> {
+    match frame {
+        Frame::Simple(status) if status == "OK" => Ok(()),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+/// Accept any status reply, for commands answering with a sentence.
+fn status(frame: Frame) -> Result<()> {
+    match frame {
+        Frame::Simple(_) => Ok(()),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn integer(frame: Frame) -> Result<i64> {
+    match frame {
+        Frame::Integer(value) => Ok(value),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn count(frame: Frame) -> Result<u64> {
+    match frame {
+        Frame::Integer(value) if value >= 0 => Ok(value as u64),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn optional_count(frame: Frame) -> Result<Option<u64>> {
+    match frame {
+        Frame::Null => Ok(None),
+        frame => count(frame).map(Some),
+    }
+}
+
+fn boolean(frame: Frame) -> Result<bool> {
+    match frame {
+        Frame::Integer(0) | Frame::Boolean(false) => Ok(false),
+        Frame::Integer(1) | Frame::Boolean(true) => Ok(true),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+/// Accept a double, or a bulk string holding one as RESP2 sends them.
+fn float(frame: Frame) -> Result<f64> {
+    match frame {
+        Frame::Double(value) => Ok(value),
+        Frame::Bulk(ref value) => match std::str::from_utf8(value).ok().and_then(|value| value.parse().ok()) {
+            Some(value) => Ok(value),
+            None => Err(ClientError::UnexpectedReply(frame)),
+        },
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn optional_bulk(frame: Frame) -> Result<Option<Bytes>> {
+    match frame {
+        Frame::Bulk(value) => Ok(Some(value)),
+        Frame::Null => Ok(None),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+/// Accept an array or a set of bulk strings, a null reply being empty.
+fn bulks(frame: Frame) -> Result<Vec<Bytes>> {
+    match frame {
+        Frame::Array(parts) | Frame::Set(parts) => parts
+            .into_iter()
+            .map(|part| match part {
+                Frame::Bulk(value) => Ok(value),
+                part => Err(ClientError::UnexpectedReply(part)),
+            })
+            .collect(),
+        Frame::Null => Ok(vec![]),
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+fn strings(frame: Frame) -> Result<Vec<String>> {
+    bulks(frame)?
+        .into_iter()
+        .map(|value| {
+            String::from_utf8(value.to_vec()).map_err(|_| ClientError::UnexpectedReply(Frame::Bulk(value)))
+        })
+        .collect()
+}
+
+/// Pair up the elements of a flat array, as RESP2 sends maps.
+fn pair_up(parts: Vec<Frame>) -> Vec<(Frame, Frame)> {
+    let mut parts = parts.into_iter();
+    let mut pairs = vec![];
+    while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+/// Accept a map, or a flat array of keys and values, of bulk strings.
+fn bulk_pairs(frame: Frame) -> Result<Vec<(Bytes, Bytes)>> {
+    let pairs = match frame {
+        Frame::Map(pairs) => pairs,
+        Frame::Array(parts) if parts.len() % 2 == 0 => pair_up(parts),
+        frame => return Err(ClientError::UnexpectedReply(frame)),
+    };
+    pairs
+        .into_iter()
+        .map(|pair| match pair {
+            (Frame::Bulk(key), Frame::Bulk(value)) => Ok((key, value)),
+            (key, _) => Err(ClientError::UnexpectedReply(key)),
+        })
+        .collect()
+}
+
+/// Accept the members of a sorted set each followed by its score.
+fn scored(frame: Frame) -> Result<Vec<(Bytes, f64)>> {
+    let pairs = match frame {
+        Frame::Array(parts) if parts.len() % 2 == 0 => pair_up(parts),
+        frame => return Err(ClientError::UnexpectedReply(frame)),
+    };
+    pairs
+        .into_iter()
+        .map(|(member, score)| match member {
+            Frame::Bulk(member) => Ok((member, float(score)?)),
+            member => Err(ClientError::UnexpectedReply(member)),
+        })
+        .collect()
+}
+
+/// Accept the reply of `BLPOP` and `BRPOP`.
+fn popped(frame: Frame) -> Result<Option<(String, Bytes)>> {
+    match frame {
+        Frame::Null | Frame::NullArray => Ok(None),
+        Frame::Array(parts) if parts.len() == 2 => {
+            let mut values = bulks(Frame::Array(parts))?.into_iter();
+            let (key, element) = (values.next().unwrap(), values.next().unwrap());
+            match String::from_utf8(key.to_vec()) {
+                Ok(key) => Ok(Some((key, element))),
+                Err(_) => Err(ClientError::UnexpectedReply(Frame::Bulk(key))),
+            }
+        }
+        frame => Err(ClientError::UnexpectedReply(frame)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::test_util::{any_port, start_server, TestServer};
+
+    fn bytes(values: &[&'static str]) -> Vec<Bytes> {
+        values.iter().map(|value| Bytes::from_static(value.as_bytes())).collect()
+    }
+
+    #[tokio::test]
+    async fn typed_replies() {
+        let mut client = connect(start_server().await).await.unwrap();
+
+        client.set("name", Bytes::from("ada")).await.unwrap();
+        assert_eq!(Some(Bytes::from("ada")), client.get("name").await.unwrap());
+        assert_eq!(None, client.get("missing").await.unwrap());
+        assert_eq!(5, client.incr_by("n", 5).await.unwrap());
+        assert_eq!(5.5, client.incr_by_float("n", 0.5).await.unwrap());
+        assert!(matches!(client.incr("name").await, Err(ClientError::Server(_))));
+
+        assert_eq!(None, client.ttl("missing").await.unwrap());
+        assert_eq!(Some(None), client.ttl("name").await.unwrap());
+        assert!(client.expire("name", Duration::from_secs(60)).await.unwrap());
+        assert!(client.ttl("name").await.unwrap().unwrap().unwrap() > Duration::from_secs(59));
+        assert!(client.persist("name").await.unwrap());
+
+        assert_eq!(3, client.rpush("list", &bytes(&["a", "b", "c"])).await.unwrap());
+        assert_eq!(Some(Bytes::from("a")), client.lpop("list").await.unwrap());
+        assert_eq!(bytes(&["b", "c"]), client.lrange("list", 0, -1).await.unwrap());
+        assert_eq!(
+            Some(("list".to_string(), Bytes::from("c"))),
+            client.brpop(&["empty", "list"], Duration::ZERO).await.unwrap()
+        );
+        assert_eq!(None, client.blpop(&["empty"], Duration::from_millis(10)).await.unwrap());
+
+        let pairs = vec![(Bytes::from("f"), Bytes::from("1"))];
+        assert_eq!(1, client.hset("hash", &pairs).await.unwrap());
+        assert_eq!(11, client.hincrby("hash", b"f", 10).await.unwrap());
+        assert_eq!(vec![(Bytes::from("f"), Bytes::from("11"))], client.hgetall("hash").await.unwrap());
+
+        assert_eq!(2, client.sadd("set", &bytes(&["x", "y"])).await.unwrap());
+        assert!(client.sismember("set", b"x").await.unwrap());
+        client.sadd("other", &bytes(&["x"])).await.unwrap();
+        assert_eq!(bytes(&["y"]), client.sdiff(&["set", "other"]).await.unwrap());
+
+        let scores = vec![(2.0, Bytes::from("bob")), (1.5, Bytes::from("ada"))];
+        assert_eq!(2, client.zadd("board", &scores).await.unwrap());
+        assert_eq!(
+            vec![(Bytes::from("ada"), 1.5), (Bytes::from("bob"), 2.0)],
+            client.zrange_with_scores("board", 0, -1).await.unwrap()
+        );
+        assert_eq!(Some(0), client.zrevrank("board", b"bob").await.unwrap());
+        assert_eq!(Some(1.5), client.zscore("board", b"ada").await.unwrap());
+        assert_eq!(None, client.zscore("board", b"eve").await.unwrap());
+
+        let mut keys = client.keys("*").await.unwrap();
+        keys.sort();
+        assert_eq!(vec!["board", "hash", "list", "n", "name", "other", "set"], keys);
+        let (cursor, _) = client.scan(0, Some("n*"), 1000).await.unwrap();
+        assert_eq!(0, cursor);
+
+        // Switching to RESP3 changes the shape of replies, not their types.
+        client.hello(3).await.unwrap();
+        assert_eq!(vec![(Bytes::from("f"), Bytes::from("11"))], client.hgetall("hash").await.unwrap());
+        assert_eq!(Some(1.5), client.zscore("board", b"ada").await.unwrap());
+        assert_eq!(2, client.smembers("set").await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn transactions_and_raw_calls() {
+        let addr = start_server().await;
+        let mut client = connect(addr).await.unwrap();
+        let incr = Request::new("INCR").arg("counter").into_frame();
+
+        client.multi().await.unwrap();
+        client.queue(incr.clone()).await.unwrap();
+        client.queue(incr.clone()).await.unwrap();
+        assert_eq!(Some(vec![Frame::Integer(1), Frame::Integer(2)]), client.exec().await.unwrap());
+
+        client.watch(&["counter"]).await.unwrap();
+        connect(addr).await.unwrap().incr("counter").await.unwrap();
+        client.multi().await.unwrap();
+        client.queue(incr.clone()).await.unwrap();
+        assert_eq!(None, client.exec().await.unwrap());
+
+        assert_eq!(Frame::Integer(4), client.call(incr).await.unwrap());
+        let unknown = Request::new("NOSUCHCOMMAND").into_frame();
+        assert!(matches!(client.call(unknown).await, Err(ClientError::Server(_))));
+    }
+
+    #[tokio::test]
+    async fn pipelines_keep_the_reply_order() {
+        let mut client = connect(start_server().await).await.unwrap();
+
+        // More commands than a batch, with an error in the middle.
+        let mut pipeline = Pipeline::new();
+        for i in 0..3000 {
+            pipeline.cmd(["SET", &format!("key:{}", i), &i.to_string()]);
+        }
+        pipeline.cmd(["INCR", "counter"]).cmd(["LPOP", "key:0"]).cmd(["INCR", "counter"]);
+        pipeline.frame(Request::new("GET").arg("key:2999").into_frame());
+        assert_eq!(3004, pipeline.len());
+
+        let replies = client.pipeline(&pipeline).await.unwrap();
+        assert_eq!(3004, replies.len());
+        assert!(replies[..3000].iter().all(|reply| *reply == Frame::Simple("OK".to_string())));
+        assert_eq!(Frame::Integer(1), replies[3000]);
+        assert!(matches!(&replies[3001], Frame::Error(message) if message.starts_with("WRONGTYPE")));
+        assert_eq!(Frame::Integer(2), replies[3002]);
+        assert_eq!(Frame::Bulk(Bytes::from("2999")), replies[3003]);
+
+        // The connection is in sync with the replies afterwards.
+        assert_eq!(Some(Bytes::from("1500")), client.get("key:1500").await.unwrap());
+        assert!(client.pipeline(&Pipeline::new()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_messages() {
+        let addr = start_server().await;
+        let mut subscriber = connect(addr).await.unwrap().subscribe(vec!["news".to_string()]).await.unwrap();
+        let mut publisher = connect(addr).await.unwrap();
+
+        assert_eq!(1, publisher.publish("news", Bytes::from("first")).await.unwrap());
+        // The message arrives while the subscription below waits for its confirmation.
+        subscriber.subscribe(&["sport".to_string()]).await.unwrap();
+        assert_eq!(1, publisher.publish("sport", Bytes::from("goal")).await.unwrap());
+
+        let first = subscriber.next_message().await.unwrap().unwrap();
+        assert_eq!(("news", Bytes::from("first")), (first.channel.as_str(), first.content));
+        let goal = subscriber.next_message().await.unwrap().unwrap();
+        assert_eq!(("sport", Bytes::from("goal")), (goal.channel.as_str(), goal.content));
+
+        subscriber.unsubscribe(&[]).await.unwrap();
+        assert!(subscriber.get_subscribed().is_empty());
+        assert_eq!(0, publisher.publish("news", Bytes::from("nobody")).await.unwrap());
+    }
+
+    #[test]
+    fn backoff() {
+        let mut rng = Rng::default();
+        let mut policy = ReconnectPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.0,
+        };
+        let backoffs: Vec<u128> = (0..6).map(|retry| policy.backoff(retry, &mut rng).as_millis()).collect();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000], backoffs);
+        assert_eq!(Duration::from_secs(1), policy.backoff(u32::MAX, &mut rng));
+
+        policy.jitter = 0.5;
+        for _ in 0..100 {
+            let backoff = policy.backoff(1, &mut rng);
+            assert!(backoff > Duration::from_millis(100) && backoff <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn idempotent_commands() {
+        let request = |parts: &[&str]| Pipeline::new().cmd(parts).requests.pop().unwrap();
+        assert!(is_idempotent(&request(&["get", "key"])));
+        assert!(is_idempotent(&request(&["SET", "key", "value"])));
+        assert!(!is_idempotent(&request(&["INCR", "key"])));
+        assert!(!is_idempotent(&request(&["RPUSH", "key", "value"])));
+        assert!(!is_idempotent(&request(&["PUBLISH", "channel", "message"])));
+    }
+
+    #[tokio::test]
+    async fn transactions_are_not_sent_again() {
+        let server = TestServer::start(any_port()).await;
+        let mut client = connect(server.addr).await.unwrap();
+
+        // The queued SET would be applied right away on a new connection.
+        client.multi().await.unwrap();
+        let server = server.restart().await;
+        let err = client.queue(Request::new("SET").args(["key", "value"]).into_frame()).await.unwrap_err();
+        assert!(err.is_connection_error());
+        assert_eq!(None, client.get("key").await.unwrap());
+
+        // As would the GET, and the EXEC would then fail.
+        client.watch(&["key"]).await.unwrap();
+        let _server = server.restart().await;
+        assert!(client.get("key").await.unwrap_err().is_connection_error());
+        client.multi().await.unwrap();
+        client.queue(Request::new("SET").args(["key", "value"]).into_frame()).await.unwrap();
+        assert_eq!(Some(vec![Frame::Simple("OK".to_string())]), client.exec().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_a_restart() {
+        let server = TestServer::start(any_port()).await;
+        let mut client = connect(server.addr).await.unwrap();
+        let mut fragile = connect(server.addr).await.unwrap();
+        fragile.set_reconnect_policy(ReconnectPolicy {
+            max_attempts: 0,
+            ..ReconnectPolicy::default()
+        });
+        client.set("key", Bytes::from("value")).await.unwrap();
+        fragile.ping().await.unwrap();
+
+        // Idempotent commands are sent again on a new connection.
+        let server = server.restart().await;
+        assert_eq!(None, client.get("key").await.unwrap());
+        assert!(!client.is_broken());
+        assert!(fragile.ping().await.unwrap_err().is_connection_error());
+        assert!(fragile.ping().await.unwrap_err().is_connection_error());
+
+        // Others fail, and the next request reconnects.
+        let server = server.restart().await;
+        assert!(client.incr("counter").await.unwrap_err().is_connection_error());
+        assert_eq!(1, client.incr("counter").await.unwrap());
+
+        // The server is back well before the client gives up, after about 70ms.
+        client.set_reconnect_policy(ReconnectPolicy {
+            max_attempts: 8,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(20),
+            jitter: 0.0,
+        });
+        let addr = server.stop().await;
+        let restarted = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            TestServer::start(addr).await
+        });
+        assert_eq!(None, client.get("counter").await.unwrap());
+        let _server = restarted.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribers_resubscribe_after_a_restart() {
+        let server = TestServer::start(any_port()).await;
+        let mut subscriber = connect(server.addr)
+            .await
+            .unwrap()
+            .subscribe(vec!["news".to_string(), "sport".to_string()])
+            .await
+            .unwrap();
+        let server = server.restart().await;
+
+        let receiver = tokio::spawn(async move { subscriber.next_message().await });
+        let mut publisher = connect(server.addr).await.unwrap();
+        // Published once the subscriber is back.
+        while publisher.publish("sport", Bytes::from("goal")).await.unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let message = receiver.await.unwrap().unwrap().unwrap();
+        assert_eq!("sport", message.channel);
+        assert_eq!(Bytes::from("goal"), message.content);
+    }
+}
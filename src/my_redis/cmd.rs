@@ -0,0 +1,1964 @@
+//! Commands understood by the server, parsed from [`Frame`]s and applied to a [`ShardedDb`].
+
+use super::db::{from_unix_millis, lock_shards, scan, to_unix_millis, LockedShards, ShardedDb, Value};
+use super::error::{Error, Result};
+use super::frame::{Frame, Protocol};
+use super::glob::glob_match;
+use super::parse::{Parse, ParseError};
+use super::pubsub::PubSub;
+use super::server_dbg_print;
+use super::zset::{parse_score, ScoreBound, SortedSet};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Bytes,
+        expire: Option<Duration>,
+    },
+    /// `INCR`, `DECR`, `INCRBY` and `DECRBY`, decrements being negative deltas.
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    IncrByFloat {
+        key: String,
+        increment: f64,
+    },
+    /// `EXPIRE` and `PEXPIRE`, with the timeout converted to milliseconds.
+    Expire {
+        key: String,
+        millis: i64,
+    },
+    /// `EXPIREAT` and `PEXPIREAT`, with the deadline converted to a unix timestamp in milliseconds.
+    ExpireAt {
+        key: String,
+        unix_millis: i64,
+    },
+    /// `TTL` and `PTTL`.
+    Ttl {
+        key: String,
+        in_millis: bool,
+    },
+    Persist {
+        key: String,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    /// `LPUSH` and `RPUSH`.
+    Push {
+        key: String,
+        elements: Vec<Bytes>,
+        left: bool,
+    },
+    /// `LPOP` and `RPOP`, popping a single element unless `count` is given.
+    Pop {
+        key: String,
+        count: Option<usize>,
+        left: bool,
+    },
+    /// `BLPOP` and `BRPOP`, handled by the connection, see
+    /// [`BlockedPop`](super::blocked::BlockedPop). A zero timeout, `None`, blocks forever.
+    Bpop {
+        keys: Vec<String>,
+        timeout: Option<Duration>,
+        left: bool,
+    },
+    Lrange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    Llen {
+        key: String,
+    },
+    Hset {
+        key: String,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    Hget {
+        key: String,
+        field: Bytes,
+    },
+    Hdel {
+        key: String,
+        fields: Vec<Bytes>,
+    },
+    Hgetall {
+        key: String,
+    },
+    Hincrby {
+        key: String,
+        field: Bytes,
+        increment: i64,
+    },
+    Hlen {
+        key: String,
+    },
+    Sadd {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    Srem {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    Smembers {
+        key: String,
+    },
+    Sismember {
+        key: String,
+        member: Bytes,
+    },
+    /// `SINTER`, `SUNION` and `SDIFF`, whose keys may live in different shards.
+    SetAlgebra {
+        op: SetOp,
+        keys: Vec<String>,
+    },
+    /// `ZADD key [NX | XX] [CH] score member [score member ...]`.
+    Zadd {
+        key: String,
+        pairs: Vec<(f64, Bytes)>,
+        /// Only add new members, `NX`.
+        nx: bool,
+        /// Only update existing members, `XX`.
+        xx: bool,
+        /// Reply with the number of members added or updated rather than added, `CH`.
+        ch: bool,
+    },
+    /// `ZRANGE` along with `ZREVRANGE`, `ZRANGEBYSCORE` and `ZREVRANGEBYSCORE`.
+    Zrange {
+        key: String,
+        range: ZrangeBy,
+        /// Walk the range from the highest score down.
+        rev: bool,
+        /// `LIMIT offset count`, only for score ranges.
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+    },
+    /// `ZRANK` and `ZREVRANK`.
+    Zrank {
+        key: String,
+        member: Bytes,
+        rev: bool,
+    },
+    Zrem {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    Zscore {
+        key: String,
+        member: Bytes,
+    },
+    Zcard {
+        key: String,
+    },
+    /// `KEYS pattern`, walking every shard, see [`Command::apply`].
+    Keys {
+        pattern: Bytes,
+    },
+    /// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`, see [`scan`].
+    Scan {
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+        type_name: Option<String>,
+    },
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    /// Replies `PONG`, or the message if given.
+    Ping {
+        message: Option<Bytes>,
+    },
+    /// Handled by the connection, see [`Subscriptions`](super::pubsub::Subscriptions).
+    Subscribe {
+        channels: Vec<String>,
+    },
+    /// Handled by the connection, see [`Subscriptions`](super::pubsub::Subscriptions).
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    /// Handled by the server, see [`Snapshotter`](super::snapshot::Snapshotter).
+    Save,
+    /// Handled by the server, see [`Snapshotter`](super::snapshot::Snapshotter).
+    Bgsave,
+    /// Handled by the server, see [`AppendOnlyLog::rewrite`](super::aof::AppendOnlyLog::rewrite).
+    Bgrewriteaof,
+    /// Handled by the connection, see [`Transaction`](super::transaction::Transaction).
+    Multi,
+    /// Handled by the connection, see [`Transaction`](super::transaction::Transaction).
+    Exec,
+    /// Handled by the connection, see [`Transaction`](super::transaction::Transaction).
+    Discard,
+    /// Handled by the connection, see [`Watches`](super::transaction::Watches).
+    Watch {
+        keys: Vec<String>,
+    },
+    /// Handled by the connection, see [`Watches`](super::transaction::Watches).
+    Unwatch,
+    /// `HELLO [protover]`, handled by the connection, see [`hello_reply`].
+    Hello {
+        protocol: Option<i64>,
+    },
+}
+
+/// The operation of a [`Command::SetAlgebra`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    /// The members of the first set which are in none of the others.
+    Diff,
+}
+
+/// The range of a [`Command::Zrange`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZrangeBy {
+    /// Inclusive ranks, negative ones counting from the end as in `LRANGE`.
+    Rank { start: i64, stop: i64 },
+    Score { min: ScoreBound, max: ScoreBound },
+}
+
+impl Command {
+    /// Parse a command from a received frame, which must be an array.
+    pub fn from_frame(frame: Frame) -> Result<Command> {
+        let mut parse = Parse::new(frame).map_err(|err| Error::from_parse("", err))?;
+
+        let command_name = match parse.next_string() {
+            Ok(name) => name.to_lowercase(),
+            Err(ParseError::EndOfStream) => return Err(Error::Protocol("empty command".to_string())),
+            Err(err) => return Err(Error::from_parse("", err)),
+        };
+        let arg = |err| Error::from_parse(&command_name, err);
+
+        let command = match &command_name[..] {
+            "get" => Command::Get {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "set" => parse_set(&mut parse)?,
+            "incr" | "decr" => Command::Incr {
+                key: parse.next_string().map_err(arg)?,
+                delta: if command_name == "incr" { 1 } else { -1 },
+            },
+            "incrby" => Command::Incr {
+                key: parse.next_string().map_err(arg)?,
+                delta: parse.next_int().map_err(arg)?,
+            },
+            "decrby" => Command::Incr {
+                key: parse.next_string().map_err(arg)?,
+                delta: parse
+                    .next_int()
+                    .map_err(arg)?
+                    .checked_neg()
+                    .ok_or(Error::Overflow)?,
+            },
+            "incrbyfloat" => Command::IncrByFloat {
+                key: parse.next_string().map_err(arg)?,
+                increment: std::str::from_utf8(&parse.next_bytes().map_err(arg)?)
+                    .ok()
+                    .and_then(parse_score)
+                    .ok_or(Error::NotFloat)?,
+            },
+            "expire" | "pexpire" => {
+                let key = parse.next_string().map_err(arg)?;
+                let timeout = parse.next_int().map_err(arg)?;
+                let millis = if command_name == "expire" {
+                    timeout.checked_mul(1000)
+                } else {
+                    Some(timeout)
+                };
+                match millis {
+                    Some(millis) => Command::Expire { key, millis },
+                    None => return Err(Error::InvalidExpire(command_name)),
+                }
+            }
+            "expireat" | "pexpireat" => {
+                let key = parse.next_string().map_err(arg)?;
+                let deadline = parse.next_int().map_err(arg)?;
+                let unix_millis = if command_name == "expireat" {
+                    deadline.checked_mul(1000)
+                } else {
+                    Some(deadline)
+                };
+                match unix_millis {
+                    Some(unix_millis) => Command::ExpireAt { key, unix_millis },
+                    None => return Err(Error::InvalidExpire(command_name)),
+                }
+            }
+            "ttl" | "pttl" => Command::Ttl {
+                key: parse.next_string().map_err(arg)?,
+                in_millis: command_name == "pttl",
+            },
+            "persist" => Command::Persist {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "del" => {
+                let mut keys = vec![parse.next_string().map_err(arg)?];
+                keys.extend(parse_remaining_strings(&mut parse).map_err(arg)?);
+                Command::Del { keys }
+            }
+            "lpush" | "rpush" => {
+                let key = parse.next_string().map_err(arg)?;
+                let mut elements = vec![parse.next_bytes().map_err(arg)?];
+                elements.extend(parse_remaining_bytes(&mut parse).map_err(arg)?);
+                Command::Push {
+                    key,
+                    elements,
+                    left: command_name == "lpush",
+                }
+            }
+            "lpop" | "rpop" => Command::Pop {
+                key: parse.next_string().map_err(arg)?,
+                count: match parse.next_int() {
+                    Ok(count) => Some(usize::try_from(count).map_err(|_| Error::NotPositive)?),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(arg(err)),
+                },
+                left: command_name == "lpop",
+            },
+            "blpop" | "brpop" => {
+                let mut keys = vec![parse.next_string().map_err(arg)?];
+                keys.extend(parse_remaining_strings(&mut parse).map_err(arg)?);
+                // The timeout comes last, after at least one key.
+                let timeout = match keys.pop() {
+                    Some(timeout) if !keys.is_empty() => parse_timeout(&timeout)?,
+                    _ => return Err(Error::WrongArity(command_name)),
+                };
+                Command::Bpop {
+                    keys,
+                    timeout,
+                    left: command_name == "blpop",
+                }
+            }
+            "lrange" => Command::Lrange {
+                key: parse.next_string().map_err(arg)?,
+                start: parse.next_int().map_err(arg)?,
+                stop: parse.next_int().map_err(arg)?,
+            },
+            "llen" => Command::Llen {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "hset" => {
+                let key = parse.next_string().map_err(arg)?;
+                let mut fields = vec![parse.next_bytes().map_err(arg)?, parse.next_bytes().map_err(arg)?];
+                fields.extend(parse_remaining_bytes(&mut parse).map_err(arg)?);
+                if fields.len() % 2 != 0 {
+                    return Err(Error::WrongArity(command_name));
+                }
+                let pairs = fields
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Command::Hset { key, pairs }
+            }
+            "hget" => Command::Hget {
+                key: parse.next_string().map_err(arg)?,
+                field: parse.next_bytes().map_err(arg)?,
+            },
+            "hdel" => {
+                let key = parse.next_string().map_err(arg)?;
+                let mut fields = vec![parse.next_bytes().map_err(arg)?];
+                fields.extend(parse_remaining_bytes(&mut parse).map_err(arg)?);
+                Command::Hdel { key, fields }
+            }
+            "hgetall" => Command::Hgetall {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "hincrby" => Command::Hincrby {
+                key: parse.next_string().map_err(arg)?,
+                field: parse.next_bytes().map_err(arg)?,
+                increment: parse.next_int().map_err(arg)?,
+            },
+            "hlen" => Command::Hlen {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "sadd" | "srem" => {
+                let key = parse.next_string().map_err(arg)?;
+                let mut members = vec![parse.next_bytes().map_err(arg)?];
+                members.extend(parse_remaining_bytes(&mut parse).map_err(arg)?);
+                if command_name == "sadd" {
+                    Command::Sadd { key, members }
+                } else {
+                    Command::Srem { key, members }
+                }
+            }
+            "smembers" => Command::Smembers {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "sismember" => Command::Sismember {
+                key: parse.next_string().map_err(arg)?,
+                member: parse.next_bytes().map_err(arg)?,
+            },
+            "sinter" | "sunion" | "sdiff" => {
+                let mut keys = vec![parse.next_string().map_err(arg)?];
+                keys.extend(parse_remaining_strings(&mut parse).map_err(arg)?);
+                let op = match &command_name[..] {
+                    "sinter" => SetOp::Inter,
+                    "sunion" => SetOp::Union,
+                    _ => SetOp::Diff,
+                };
+                Command::SetAlgebra { op, keys }
+            }
+            "zadd" => parse_zadd(&mut parse)?,
+            "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore" => {
+                parse_zrange(&mut parse, &command_name)?
+            }
+            "zrank" | "zrevrank" => Command::Zrank {
+                key: parse.next_string().map_err(arg)?,
+                member: parse.next_bytes().map_err(arg)?,
+                rev: command_name == "zrevrank",
+            },
+            "zrem" => {
+                let key = parse.next_string().map_err(arg)?;
+                let mut members = vec![parse.next_bytes().map_err(arg)?];
+                members.extend(parse_remaining_bytes(&mut parse).map_err(arg)?);
+                Command::Zrem { key, members }
+            }
+            "zscore" => Command::Zscore {
+                key: parse.next_string().map_err(arg)?,
+                member: parse.next_bytes().map_err(arg)?,
+            },
+            "zcard" => Command::Zcard {
+                key: parse.next_string().map_err(arg)?,
+            },
+            "keys" => Command::Keys {
+                pattern: parse.next_bytes().map_err(arg)?,
+            },
+            "scan" => parse_scan(&mut parse)?,
+            "publish" => Command::Publish {
+                channel: parse.next_string().map_err(arg)?,
+                message: parse.next_bytes().map_err(arg)?,
+            },
+            "ping" => Command::Ping {
+                message: match parse.next_bytes() {
+                    Ok(message) => Some(message),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(arg(err)),
+                },
+            },
+            "subscribe" => {
+                let mut channels = vec![parse.next_string().map_err(arg)?];
+                channels.extend(parse_remaining_strings(&mut parse).map_err(arg)?);
+                Command::Subscribe { channels }
+            }
+            "unsubscribe" => Command::Unsubscribe {
+                channels: parse_remaining_strings(&mut parse).map_err(arg)?,
+            },
+            "save" => Command::Save,
+            "bgsave" => Command::Bgsave,
+            "bgrewriteaof" => Command::Bgrewriteaof,
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "watch" => {
+                let mut keys = vec![parse.next_string().map_err(arg)?];
+                keys.extend(parse_remaining_strings(&mut parse).map_err(arg)?);
+                Command::Watch { keys }
+            }
+            "unwatch" => Command::Unwatch,
+            "hello" => Command::Hello {
+                protocol: match parse.next_int() {
+                    Ok(protocol) => Some(protocol),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(arg(err)),
+                },
+            },
+            _ => return Err(Error::UnknownCommand(command_name)),
+        };
+
+        parse.finish().map_err(arg)?;
+
+        Ok(command)
+    }
+
+    /// Return the keys the command reads or writes, whose shards must be locked to apply it.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Get { key }
+            | Command::Set { key, .. }
+            | Command::Incr { key, .. }
+            | Command::IncrByFloat { key, .. }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Ttl { key, .. }
+            | Command::Persist { key }
+            | Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::Lrange { key, .. }
+            | Command::Llen { key }
+            | Command::Hset { key, .. }
+            | Command::Hget { key, .. }
+            | Command::Hdel { key, .. }
+            | Command::Hgetall { key }
+            | Command::Hincrby { key, .. }
+            | Command::Hlen { key }
+            | Command::Sadd { key, .. }
+            | Command::Srem { key, .. }
+            | Command::Smembers { key }
+            | Command::Sismember { key, .. }
+            | Command::Zadd { key, .. }
+            | Command::Zrange { key, .. }
+            | Command::Zrank { key, .. }
+            | Command::Zrem { key, .. }
+            | Command::Zscore { key, .. }
+            | Command::Zcard { key } => vec![key],
+            Command::Del { keys } | Command::SetAlgebra { keys, .. } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            // Walk the shards one at a time rather than locking them all.
+            Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::Publish { .. }
+            | Command::Ping { .. }
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Save
+            | Command::Bgsave
+            | Command::Bgrewriteaof
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Bpop { .. }
+            | Command::Hello { .. } => vec![],
+        }
+    }
+
+    /// Whether the command may make its keys use more memory, in which case
+    /// room is made for it under `maxmemory` first, see [`make_room`](super::eviction::make_room).
+    pub fn may_grow(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Incr { .. }
+                | Command::IncrByFloat { .. }
+                | Command::Push { .. }
+                | Command::Hset { .. }
+                | Command::Hincrby { .. }
+                | Command::Sadd { .. }
+                | Command::Zadd { .. }
+        )
+    }
+
+    /// Apply the command to `shared_db` and `pub_sub`, returning the reply.
+    ///
+    /// `KEYS` and `SCAN` lock one shard at a time, so they don't see a single
+    /// point in time of the whole dataset.
+    pub fn apply(self, shared_db: &ShardedDb, pub_sub: &PubSub) -> Frame {
+        match self {
+            Command::Keys { pattern } => {
+                let mut keys = vec![];
+                for db in shared_db.iter() {
+                    let db = db.lock().unwrap();
+                    keys.extend(
+                        db.iter()
+                            .filter(|(key, _)| glob_match(&pattern, key.as_bytes()))
+                            .map(|(key, _)| Frame::Bulk(Bytes::copy_from_slice(key.as_bytes()))),
+                    );
+                }
+                Frame::Array(keys)
+            }
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+                type_name,
+            } => {
+                let (cursor, keys) = scan(shared_db, cursor, count, |key, value| {
+                    pattern.as_ref().is_none_or(|pattern| glob_match(pattern, key.as_bytes()))
+                        && type_name.as_ref().is_none_or(|type_name| value.type_name() == type_name)
+                });
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(cursor.to_string())),
+                    Frame::Array(keys.into_iter().map(|key| Frame::Bulk(Bytes::from(key))).collect()),
+                ])
+            }
+            command => {
+                let mut shards = lock_shards(shared_db, &command.keys());
+                command.execute(&mut shards, pub_sub).unwrap_or_else(Frame::from)
+            }
+        }
+    }
+
+    /// Apply `commands` in order to the locked `shards`, which must hold all their
+    /// keys, returning their replies.
+    ///
+    /// No other client sees the dataset halfway through, as long as the shards
+    /// stay locked for the whole run.
+    pub fn apply_all(commands: Vec<Command>, shards: &mut LockedShards, pub_sub: &PubSub) -> Vec<Frame> {
+        commands
+            .into_iter()
+            .map(|command| command.execute(shards, pub_sub).unwrap_or_else(Frame::from))
+            .collect()
+    }
+
+    /// Apply the command to the locked `shards`, which must hold its [`keys`](Command::keys).
+    fn execute(self, shards: &mut LockedShards, pub_sub: &PubSub) -> Result<Frame> {
+        let reply = match self {
+            Command::Get { key } => {
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Get key:[{}]", key));
+                match db.get(&key)? {
+                    Some(value) => Frame::Bulk(value),
+                    None => Frame::Null,
+                }
+            }
+            Command::Set { key, value, expire } => {
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Set key:[{}]", key));
+                db.set(key, value, expire);
+                Frame::Simple("OK".to_string())
+            }
+            Command::Incr { key, delta } => {
+                let db = shards.shard_mut(&key);
+                // The key keeps its expiration, if any.
+                let value = db
+                    .value_or_insert_with(&key, || Value::String(Bytes::from_static(b"0")))
+                    .as_string_mut()?;
+                let current = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or(Error::NotInteger)?;
+                let updated = current.checked_add(delta).ok_or(Error::Overflow)?;
+                *value = Bytes::from(updated.to_string());
+                Frame::Integer(updated)
+            }
+            Command::IncrByFloat { key, increment } => {
+                // Checked first, so that a missing key isn't left behind.
+                if !increment.is_finite() {
+                    return Err(Error::NanOrInfinity);
+                }
+                let db = shards.shard_mut(&key);
+                let value = db
+                    .value_or_insert_with(&key, || Value::String(Bytes::from_static(b"0")))
+                    .as_string_mut()?;
+                let current = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(parse_score)
+                    .ok_or(Error::NotFloat)?;
+                let updated = current + increment;
+                if !updated.is_finite() {
+                    return Err(Error::NanOrInfinity);
+                }
+                *value = Bytes::from(updated.to_string());
+                Frame::Bulk(value.clone())
+            }
+            Command::Expire { key, millis } => {
+                let now = Instant::now();
+                let when = if millis <= 0 {
+                    Some(now)
+                } else {
+                    now.checked_add(Duration::from_millis(millis as u64))
+                };
+                let when = when.ok_or_else(|| Error::InvalidExpire("expire".to_string()))?;
+
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Expire key:[{}] in {}ms", key, millis));
+                Frame::Integer(db.expire_at(&key, when) as i64)
+            }
+            Command::ExpireAt { key, unix_millis } => {
+                let when = from_unix_millis(unix_millis).unwrap_or_else(Instant::now);
+
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Expire key:[{}] at {}", key, unix_millis));
+                Frame::Integer(db.expire_at(&key, when) as i64)
+            }
+            Command::Ttl { key, in_millis } => {
+                let db = shards.shard_mut(&key);
+                match db.ttl(&key) {
+                    None => Frame::Integer(-2),
+                    Some(None) => Frame::Integer(-1),
+                    Some(Some(ttl)) if in_millis => Frame::Integer(ttl.as_millis() as i64),
+                    Some(Some(ttl)) => Frame::Integer(((ttl.as_millis() + 500) / 1000) as i64),
+                }
+            }
+            Command::Persist { key } => {
+                let db = shards.shard_mut(&key);
+                Frame::Integer(db.persist(&key) as i64)
+            }
+            Command::Del { keys } => {
+                server_dbg_print(&format!("Del keys:{:?}", keys));
+                let removed = keys
+                    .iter()
+                    .filter(|key| shards.shard_mut(key).remove(key).is_some())
+                    .count();
+                Frame::Integer(removed as i64)
+            }
+            Command::Push { key, elements, left } => {
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Push {} elements to key:[{}]", elements.len(), key));
+                let list = db
+                    .value_or_insert_with(&key, || Value::List(VecDeque::new()))
+                    .as_list_mut()?;
+                for element in elements {
+                    if left {
+                        list.push_front(element);
+                    } else {
+                        list.push_back(element);
+                    }
+                }
+                let len = list.len();
+                db.wake_blocked(&key);
+                Frame::Integer(len as i64)
+            }
+            Command::Pop { key, count, left } => {
+                let db = shards.shard_mut(&key);
+                let list = match db.value_mut(&key) {
+                    Some(value) => value.as_list_mut()?,
+                    None => return Ok(Frame::Null),
+                };
+                let mut pop = || if left { list.pop_front() } else { list.pop_back() };
+                let reply = match count {
+                    None => pop().map(Frame::Bulk).unwrap_or(Frame::Null),
+                    Some(count) => Frame::Array(
+                        std::iter::from_fn(pop).take(count).map(Frame::Bulk).collect(),
+                    ),
+                };
+                db.remove_if_empty(&key);
+                reply
+            }
+            Command::Lrange { key, start, stop } => {
+                let db = shards.shard_mut(&key);
+                let list = match db.value(&key) {
+                    Some(value) => value.as_list()?,
+                    None => return Ok(Frame::Array(vec![])),
+                };
+                let elements = match list_range(list.len(), start, stop) {
+                    Some((start, stop)) => list
+                        .range(start..=stop)
+                        .cloned()
+                        .map(Frame::Bulk)
+                        .collect(),
+                    None => vec![],
+                };
+                Frame::Array(elements)
+            }
+            Command::Llen { key } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => Frame::Integer(value.as_list()?.len() as i64),
+                    None => Frame::Integer(0),
+                }
+            }
+            Command::Hset { key, pairs } => {
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Hset {} fields of key:[{}]", pairs.len(), key));
+                let hash = db
+                    .value_or_insert_with(&key, || Value::Hash(HashMap::new()))
+                    .as_hash_mut()?;
+                let mut added = 0;
+                for (field, value) in pairs {
+                    if hash.insert(field, value).is_none() {
+                        added += 1;
+                    }
+                }
+                Frame::Integer(added as i64)
+            }
+            Command::Hget { key, field } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => match value.as_hash()?.get(&field) {
+                        Some(value) => Frame::Bulk(value.clone()),
+                        None => Frame::Null,
+                    },
+                    None => Frame::Null,
+                }
+            }
+            Command::Hdel { key, fields } => {
+                let db = shards.shard_mut(&key);
+                let hash = match db.value_mut(&key) {
+                    Some(value) => value.as_hash_mut()?,
+                    None => return Ok(Frame::Integer(0)),
+                };
+                let removed = fields.iter().filter(|field| hash.remove(*field).is_some()).count();
+                db.remove_if_empty(&key);
+                Frame::Integer(removed as i64)
+            }
+            Command::Hgetall { key } => {
+                let db = shards.shard_mut(&key);
+                // A flat array of fields and values for RESP2 connections.
+                let pairs = match db.value(&key) {
+                    Some(value) => value
+                        .as_hash()?
+                        .iter()
+                        .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+                        .collect(),
+                    None => vec![],
+                };
+                Frame::Map(pairs)
+            }
+            Command::Hincrby { key, field, increment } => {
+                let db = shards.shard_mut(&key);
+                let hash = db
+                    .value_or_insert_with(&key, || Value::Hash(HashMap::new()))
+                    .as_hash_mut()?;
+                let current = match hash.get(&field) {
+                    Some(value) => std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse::<i64>().ok())
+                        .ok_or(Error::HashNotInteger)?,
+                    None => 0,
+                };
+                let updated = current.checked_add(increment).ok_or(Error::Overflow)?;
+                hash.insert(field, Bytes::from(updated.to_string()));
+                Frame::Integer(updated)
+            }
+            Command::Hlen { key } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => Frame::Integer(value.as_hash()?.len() as i64),
+                    None => Frame::Integer(0),
+                }
+            }
+            Command::Sadd { key, members } => {
+                let db = shards.shard_mut(&key);
+                let set = db
+                    .value_or_insert_with(&key, || Value::Set(HashSet::new()))
+                    .as_set_mut()?;
+                let added = members.into_iter().filter(|member| set.insert(member.clone())).count();
+                Frame::Integer(added as i64)
+            }
+            Command::Srem { key, members } => {
+                let db = shards.shard_mut(&key);
+                let set = match db.value_mut(&key) {
+                    Some(value) => value.as_set_mut()?,
+                    None => return Ok(Frame::Integer(0)),
+                };
+                let removed = members.iter().filter(|member| set.remove(*member)).count();
+                db.remove_if_empty(&key);
+                Frame::Integer(removed as i64)
+            }
+            Command::Smembers { key } => {
+                let db = shards.shard_mut(&key);
+                let members = match db.value(&key) {
+                    Some(value) => value.as_set()?.iter().cloned().map(Frame::Bulk).collect(),
+                    None => vec![],
+                };
+                Frame::Set(members)
+            }
+            Command::Sismember { key, member } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => Frame::Integer(value.as_set()?.contains(&member) as i64),
+                    None => Frame::Integer(0),
+                }
+            }
+            Command::SetAlgebra { op, keys } => {
+                // Missing keys are empty sets.
+                let sets = keys
+                    .iter()
+                    .map(|key| shards.shard(key).peek(key).map(Value::as_set).transpose())
+                    .collect::<Result<Vec<Option<&HashSet<Bytes>>>>>()?;
+                Frame::Set(set_algebra(op, &sets).into_iter().map(Frame::Bulk).collect())
+            }
+            Command::Zadd { key, pairs, nx, xx, ch } => {
+                let db = shards.shard_mut(&key);
+                server_dbg_print(&format!("Zadd {} members to key:[{}]", pairs.len(), key));
+                let zset = db
+                    .value_or_insert_with(&key, || Value::SortedSet(SortedSet::new()))
+                    .as_zset_mut()?;
+                let (mut added, mut updated) = (0, 0);
+                for (score, member) in pairs {
+                    match zset.score(&member) {
+                        Some(_) if nx => {}
+                        None if xx => {}
+                        Some(current) if current == score => {}
+                        Some(_) => {
+                            zset.insert(member, score);
+                            updated += 1;
+                        }
+                        None => {
+                            zset.insert(member, score);
+                            added += 1;
+                        }
+                    }
+                }
+                // `XX` on a missing key leaves an empty set behind.
+                db.remove_if_empty(&key);
+                Frame::Integer(if ch { added + updated } else { added })
+            }
+            Command::Zrange {
+                key,
+                range,
+                rev,
+                limit,
+                with_scores,
+            } => {
+                let db = shards.shard_mut(&key);
+                let zset = match db.value(&key) {
+                    Some(value) => value.as_zset()?,
+                    None => return Ok(Frame::Array(vec![])),
+                };
+                let len = zset.len();
+                let ranks = match range {
+                    // With `REV`, rank 0 is the highest score.
+                    ZrangeBy::Rank { start, stop } => match list_range(len, start, stop) {
+                        Some((start, stop)) if rev => len - 1 - stop..len - start,
+                        Some((start, stop)) => start..stop + 1,
+                        None => 0..0,
+                    },
+                    ZrangeBy::Score { min, max } => zset.score_range(min, max),
+                };
+                let ranks = match limit {
+                    Some((offset, count)) => limit_ranks(ranks, offset, count, rev),
+                    None => ranks,
+                };
+
+                let mut entries: Vec<(&Bytes, f64)> = zset.range(ranks).collect();
+                if rev {
+                    entries.reverse();
+                }
+                let mut reply = vec![];
+                for (member, score) in entries {
+                    reply.push(Frame::Bulk(member.clone()));
+                    if with_scores {
+                        reply.push(Frame::Double(score));
+                    }
+                }
+                Frame::Array(reply)
+            }
+            Command::Zrank { key, member, rev } => {
+                let db = shards.shard_mut(&key);
+                let zset = match db.value(&key) {
+                    Some(value) => value.as_zset()?,
+                    None => return Ok(Frame::Null),
+                };
+                match zset.rank(&member) {
+                    Some(rank) if rev => Frame::Integer((zset.len() - 1 - rank) as i64),
+                    Some(rank) => Frame::Integer(rank as i64),
+                    None => Frame::Null,
+                }
+            }
+            Command::Zrem { key, members } => {
+                let db = shards.shard_mut(&key);
+                let zset = match db.value_mut(&key) {
+                    Some(value) => value.as_zset_mut()?,
+                    None => return Ok(Frame::Integer(0)),
+                };
+                let removed = members.iter().filter(|member| zset.remove(member)).count();
+                db.remove_if_empty(&key);
+                Frame::Integer(removed as i64)
+            }
+            Command::Zscore { key, member } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => match value.as_zset()?.score(&member) {
+                        Some(score) => Frame::Double(score),
+                        None => Frame::Null,
+                    },
+                    None => Frame::Null,
+                }
+            }
+            Command::Zcard { key } => {
+                let db = shards.shard_mut(&key);
+                match db.value(&key) {
+                    Some(value) => Frame::Integer(value.as_zset()?.len() as i64),
+                    None => Frame::Integer(0),
+                }
+            }
+            Command::Publish { channel, message } => {
+                server_dbg_print(&format!("Publish channel:[{}]", channel));
+                Frame::Integer(pub_sub.publish(&channel, message) as i64)
+            }
+            Command::Ping { message: Some(message) } => Frame::Bulk(message),
+            Command::Ping { message: None } => Frame::Simple("PONG".to_string()),
+            Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Hello { .. }
+            | Command::Save
+            | Command::Bgsave
+            | Command::Bgrewriteaof
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Bpop { .. }
+            | Command::Keys { .. }
+            | Command::Scan { .. } => {
+                Frame::Error("ERR server commands are unsupported in this context".to_string())
+            }
+        };
+
+        Ok(reply)
+    }
+
+    /// Return the arguments to append to the log for this command, `None` if it
+    /// doesn't modify the dataset.
+    ///
+    /// Relative expirations are turned into unix timestamps, so that replaying the
+    /// log later on restores the same deadlines.
+    pub fn to_log_entry(&self) -> Option<Vec<Bytes>> {
+        let arg = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+        let deadline = |millis: i64| {
+            let now = to_unix_millis(Instant::now());
+            arg(&now.saturating_add(millis).to_string())
+        };
+
+        match self {
+            Command::Set { key, value, expire } => {
+                let mut entry = vec![arg("SET"), arg(key), value.clone()];
+                if let Some(expire) = expire {
+                    entry.push(arg("PXAT"));
+                    entry.push(deadline(expire.as_millis() as i64));
+                }
+                Some(entry)
+            }
+            Command::Incr { key, delta } => Some(vec![arg("INCRBY"), arg(key), arg(&delta.to_string())]),
+            Command::IncrByFloat { key, increment } => Some(vec![
+                arg("INCRBYFLOAT"),
+                arg(key),
+                arg(&increment.to_string()),
+            ]),
+            Command::Expire { key, millis } => Some(vec![arg("PEXPIREAT"), arg(key), deadline(*millis)]),
+            Command::ExpireAt { key, unix_millis } => Some(vec![
+                arg("PEXPIREAT"),
+                arg(key),
+                arg(&unix_millis.to_string()),
+            ]),
+            Command::Persist { key } => Some(vec![arg("PERSIST"), arg(key)]),
+            Command::Del { keys } => {
+                let mut entry = vec![arg("DEL")];
+                entry.extend(keys.iter().map(|key| arg(key)));
+                Some(entry)
+            }
+            Command::Push { key, elements, left } => {
+                let mut entry = vec![arg(if *left { "LPUSH" } else { "RPUSH" }), arg(key)];
+                entry.extend(elements.iter().cloned());
+                Some(entry)
+            }
+            Command::Hset { key, pairs } => {
+                let mut entry = vec![arg("HSET"), arg(key)];
+                for (field, value) in pairs {
+                    entry.push(field.clone());
+                    entry.push(value.clone());
+                }
+                Some(entry)
+            }
+            Command::Hdel { key, fields } => {
+                let mut entry = vec![arg("HDEL"), arg(key)];
+                entry.extend(fields.iter().cloned());
+                Some(entry)
+            }
+            Command::Hincrby { key, field, increment } => Some(vec![
+                arg("HINCRBY"),
+                arg(key),
+                field.clone(),
+                arg(&increment.to_string()),
+            ]),
+            Command::Sadd { key, members } | Command::Srem { key, members } => {
+                let name = if matches!(self, Command::Sadd { .. }) { "SADD" } else { "SREM" };
+                let mut entry = vec![arg(name), arg(key)];
+                entry.extend(members.iter().cloned());
+                Some(entry)
+            }
+            Command::Zadd { key, pairs, nx, xx, .. } => {
+                let mut entry = vec![arg("ZADD"), arg(key)];
+                if *nx {
+                    entry.push(arg("NX"));
+                }
+                if *xx {
+                    entry.push(arg("XX"));
+                }
+                for (score, member) in pairs {
+                    entry.push(arg(&score.to_string()));
+                    entry.push(member.clone());
+                }
+                Some(entry)
+            }
+            Command::Zrem { key, members } => {
+                let mut entry = vec![arg("ZREM"), arg(key)];
+                entry.extend(members.iter().cloned());
+                Some(entry)
+            }
+            Command::Pop { key, count, left } => {
+                let mut entry = vec![arg(if *left { "LPOP" } else { "RPOP" }), arg(key)];
+                entry.extend(count.map(|count| arg(&count.to_string())));
+                Some(entry)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Return the protocol requested by `HELLO`, or a `NOPROTO` error for unsupported versions.
+pub fn hello_protocol(version: i64) -> Result<Protocol> {
+    match version {
+        2 => Ok(Protocol::Resp2),
+        3 => Ok(Protocol::Resp3),
+        _ => Err(Error::NoProto),
+    }
+}
+
+/// Return the server properties `HELLO` replies with, for a connection with the given `id`.
+pub fn hello_reply(protocol: Protocol, id: u64) -> Frame {
+    let field = |name: &'static str| Frame::Bulk(Bytes::from_static(name.as_bytes()));
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+
+    Frame::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), Frame::Integer(proto)),
+        (field("id"), Frame::Integer(id as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), Frame::Array(vec![])),
+    ])
+}
+
+/// Parse the timeout of `BLPOP` and `BRPOP`, in seconds, zero meaning none.
+fn parse_timeout(timeout: &str) -> Result<Option<Duration>> {
+    let seconds = timeout
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or(Error::TimeoutNotFloat)?;
+    if seconds < 0.0 {
+        return Err(Error::NegativeTimeout);
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| Error::TimeoutNotFloat)
+}
+
+/// Parse `SET key value [EX seconds | PX milliseconds | EXAT unix-seconds | PXAT unix-milliseconds]`.
+fn parse_set(parse: &mut Parse) -> Result<Command> {
+    let arg = |err| Error::from_parse("set", err);
+
+    let key = parse.next_string().map_err(arg)?;
+    let value = parse.next_bytes().map_err(arg)?;
+
+    let option = match parse.next_string() {
+        Ok(option) => option.to_uppercase(),
+        Err(ParseError::EndOfStream) => return Ok(Command::Set { key, value, expire: None }),
+        Err(err) => return Err(arg(err)),
+    };
+    let scale = match &option[..] {
+        "EX" | "EXAT" => 1000,
+        "PX" | "PXAT" => 1,
+        _ => return Err(Error::Syntax),
+    };
+    let millis = match parse.next_int() {
+        Ok(millis) => millis.checked_mul(scale),
+        Err(ParseError::EndOfStream) => return Err(Error::Syntax),
+        Err(err) => return Err(arg(err)),
+    };
+    let millis = match millis {
+        Some(millis) if millis > 0 => millis,
+        _ => return Err(Error::InvalidExpire("set".to_string())),
+    };
+
+    let expire = if option.ends_with("AT") {
+        // A deadline which is already past expires the key right away.
+        from_unix_millis(millis)
+            .map(|when| when.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::ZERO)
+    } else {
+        Duration::from_millis(millis as u64)
+    };
+
+    Ok(Command::Set {
+        key,
+        value,
+        expire: Some(expire),
+    })
+}
+
+/// Parse `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`.
+fn parse_scan(parse: &mut Parse) -> Result<Command> {
+    let arg = |err| Error::from_parse("scan", err);
+
+    let cursor = parse
+        .next_string()
+        .map_err(arg)?
+        .parse::<u64>()
+        .map_err(|_| Error::InvalidCursor)?;
+    let (mut pattern, mut count, mut type_name) = (None, 10, None);
+    loop {
+        let option = match parse.next_string() {
+            Ok(option) => option.to_uppercase(),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(arg(err)),
+        };
+        match &option[..] {
+            "MATCH" => pattern = Some(parse.next_bytes().map_err(|_| Error::Syntax)?),
+            "COUNT" => {
+                count = parse.next_int().map_err(|_| Error::Syntax)?;
+                if count < 1 {
+                    return Err(Error::Syntax);
+                }
+            }
+            "TYPE" => type_name = Some(parse.next_string().map_err(|_| Error::Syntax)?.to_lowercase()),
+            _ => return Err(Error::Syntax),
+        }
+    }
+
+    Ok(Command::Scan {
+        cursor,
+        pattern,
+        count: count as usize,
+        type_name,
+    })
+}
+
+/// Parse `ZADD key [NX | XX] [CH] score member [score member ...]`.
+fn parse_zadd(parse: &mut Parse) -> Result<Command> {
+    let arg = |err| Error::from_parse("zadd", err);
+
+    let key = parse.next_string().map_err(arg)?;
+    let args = parse_remaining_bytes(parse).map_err(arg)?;
+
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut args = args.into_iter().peekable();
+    while let Some(option) = args.peek() {
+        match &option.to_ascii_uppercase()[..] {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"CH" => ch = true,
+            _ => break,
+        }
+        args.next();
+    }
+    if nx && xx {
+        return Err(Error::Syntax);
+    }
+
+    let args: Vec<Bytes> = args.collect();
+    if args.is_empty() {
+        return Err(Error::WrongArity("zadd".to_string()));
+    }
+    if !args.len().is_multiple_of(2) {
+        return Err(Error::Syntax);
+    }
+    let pairs = args
+        .chunks(2)
+        .map(|pair| {
+            let score = std::str::from_utf8(&pair[0])
+                .ok()
+                .and_then(parse_score)
+                .ok_or(Error::NotFloat)?;
+            Ok((score, pair[1].clone()))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Command::Zadd { key, pairs, nx, xx, ch })
+}
+
+/// Parse `ZRANGE key start stop [BYSCORE] [REV] [LIMIT offset count] [WITHSCORES]`,
+/// or one of the older `ZREVRANGE`, `ZRANGEBYSCORE` and `ZREVRANGEBYSCORE`.
+///
+/// Reverse score ranges take the maximum first, as in `ZREVRANGEBYSCORE key max min`.
+fn parse_zrange(parse: &mut Parse, command_name: &str) -> Result<Command> {
+    let arg = |err| Error::from_parse(command_name, err);
+
+    let key = parse.next_string().map_err(arg)?;
+    let start = parse.next_string().map_err(arg)?;
+    let stop = parse.next_string().map_err(arg)?;
+
+    let mut by_score = command_name.ends_with("byscore");
+    let mut rev = command_name.starts_with("zrev");
+    let mut limit = None;
+    let mut with_scores = false;
+    loop {
+        let option = match parse.next_string() {
+            Ok(option) => option.to_uppercase(),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(arg(err)),
+        };
+        match &option[..] {
+            "WITHSCORES" => with_scores = true,
+            "BYSCORE" if command_name == "zrange" => by_score = true,
+            "REV" if command_name == "zrange" => rev = true,
+            "LIMIT" if command_name != "zrevrange" => {
+                let offset = parse.next_int().map_err(|_| Error::Syntax)?;
+                let count = parse.next_int().map_err(|_| Error::Syntax)?;
+                limit = Some((offset, count));
+            }
+            _ => return Err(Error::Syntax),
+        }
+    }
+
+    let range = if by_score {
+        let (min, max) = if rev { (stop, start) } else { (start, stop) };
+        ZrangeBy::Score {
+            min: min.parse()?,
+            max: max.parse()?,
+        }
+    } else if limit.is_some() {
+        return Err(Error::Syntax);
+    } else {
+        let index = |s: String| s.parse::<i64>().map_err(|_| Error::NotInteger);
+        ZrangeBy::Rank {
+            start: index(start)?,
+            stop: index(stop)?,
+        }
+    };
+
+    Ok(Command::Zrange {
+        key,
+        range,
+        rev,
+        limit,
+        with_scores,
+    })
+}
+
+/// Apply `LIMIT offset count` to the ascending `ranks` of a score range, the
+/// offset counting from the end of the range when walking it in reverse.
+///
+/// A negative offset selects nothing, a negative count everything past the offset.
+fn limit_ranks(ranks: Range<usize>, offset: i64, count: i64, rev: bool) -> Range<usize> {
+    let offset = match usize::try_from(offset) {
+        Ok(offset) => offset.min(ranks.len()),
+        Err(_) => return ranks.start..ranks.start,
+    };
+    let remaining = ranks.len() - offset;
+    let count = usize::try_from(count).map_or(remaining, |count| count.min(remaining));
+
+    if rev {
+        ranks.end - offset - count..ranks.end - offset
+    } else {
+        ranks.start + offset..ranks.start + offset + count
+    }
+}
+
+/// Return the members of the result of `op` over `sets`, `None` standing for
+/// missing keys.
+fn set_algebra(op: SetOp, sets: &[Option<&HashSet<Bytes>>]) -> Vec<Bytes> {
+    let empty = HashSet::new();
+    let mut sets = sets.iter().map(|set| set.unwrap_or(&empty));
+
+    match op {
+        SetOp::Inter => {
+            let sets: Vec<&HashSet<Bytes>> = sets.collect();
+            let smallest = match sets.iter().min_by_key(|set| set.len()) {
+                Some(smallest) => smallest,
+                None => return vec![],
+            };
+            smallest
+                .iter()
+                .filter(|member| sets.iter().all(|set| set.contains(*member)))
+                .cloned()
+                .collect()
+        }
+        SetOp::Union => {
+            let union: HashSet<&Bytes> = sets.flatten().collect();
+            union.into_iter().cloned().collect()
+        }
+        SetOp::Diff => {
+            let first = match sets.next() {
+                Some(first) => first,
+                None => return vec![],
+            };
+            let others: Vec<&HashSet<Bytes>> = sets.collect();
+            first
+                .iter()
+                .filter(|member| !others.iter().any(|set| set.contains(*member)))
+                .cloned()
+                .collect()
+        }
+    }
+}
+
+/// Return the inclusive bounds of `LRANGE start stop` over a list of `len`
+/// elements, `None` if the range is empty.
+///
+/// Negative indexes count from the end of the list, and out of range indexes
+/// are clamped to the list.
+fn list_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Parse every remaining entry as raw bytes.
+fn parse_remaining_bytes(parse: &mut Parse) -> std::result::Result<Vec<Bytes>, ParseError> {
+    let mut entries = vec![];
+    loop {
+        match parse.next_bytes() {
+            Ok(bytes) => entries.push(bytes),
+            Err(ParseError::EndOfStream) => return Ok(entries),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Parse every remaining entry as a string.
+fn parse_remaining_strings(parse: &mut Parse) -> std::result::Result<Vec<String>, ParseError> {
+    let mut strings = vec![];
+    loop {
+        match parse.next_string() {
+            Ok(s) => strings.push(s),
+            Err(ParseError::EndOfStream) => return Ok(strings),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::db::new_shared_db;
+    use crate::my_redis::pubsub::new_shared_pub_sub;
+    use crate::my_redis::test_util::frame;
+
+    fn run(shared_db: &ShardedDb, parts: &[&str]) -> Frame {
+        Command::from_frame(frame(parts))
+            .unwrap()
+            .apply(shared_db, &new_shared_pub_sub())
+    }
+
+    #[test]
+    fn expire_ttl_persist() {
+        let db = new_shared_db(4);
+
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "k"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["EXPIRE", "k", "10"]));
+
+        run(&db, &["SET", "k", "v"]);
+        assert_eq!(Frame::Integer(-1), run(&db, &["TTL", "k"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["EXPIRE", "k", "10"]));
+        assert_eq!(Frame::Integer(10), run(&db, &["TTL", "k"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["PEXPIRE", "k", "5000"]));
+        match run(&db, &["PTTL", "k"]) {
+            Frame::Integer(ms) => assert!(ms > 4900 && ms <= 5000),
+            frame => panic!("unexpected {:?}", frame),
+        }
+
+        assert_eq!(Frame::Integer(1), run(&db, &["PERSIST", "k"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["PERSIST", "k"]));
+        assert_eq!(Frame::Integer(-1), run(&db, &["TTL", "k"]));
+
+        assert_eq!(Frame::Integer(1), run(&db, &["EXPIRE", "k", "-1"]));
+        assert_eq!(Frame::Null, run(&db, &["GET", "k"]));
+    }
+
+    #[test]
+    fn del() {
+        let db = new_shared_db(4);
+        run(&db, &["SET", "a", "1"]);
+        run(&db, &["RPUSH", "b", "x"]);
+        run(&db, &["SET", "c", "3", "PX", "1"]);
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(Frame::Integer(2), run(&db, &["DEL", "a", "b", "c", "d"]));
+        assert_eq!(Frame::Null, run(&db, &["GET", "a"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["DEL", "a"]));
+
+        let command = Command::from_frame(frame(&["DEL", "a", "b"])).unwrap();
+        assert_eq!(vec!["a", "b"], command.keys());
+        assert_eq!(Some(vec![Bytes::from("DEL"), Bytes::from("a"), Bytes::from("b")]), command.to_log_entry());
+    }
+
+    #[test]
+    fn ping() {
+        let db = new_shared_db(4);
+        assert_eq!(Frame::Simple("PONG".to_string()), run(&db, &["PING"]));
+        assert_eq!(Frame::Bulk(Bytes::from("hi")), run(&db, &["PING", "hi"]));
+    }
+
+    #[test]
+    fn expire_at_and_log_entries() {
+        let db = new_shared_db(4);
+        let now = to_unix_millis(Instant::now());
+
+        run(&db, &["SET", "k", "v", "PXAT", &(now + 10_000).to_string()]);
+        match run(&db, &["PTTL", "k"]) {
+            Frame::Integer(ms) => assert!(ms > 9900 && ms <= 10_000),
+            frame => panic!("unexpected {:?}", frame),
+        }
+        assert_eq!(Frame::Integer(1), run(&db, &["EXPIREAT", "k", &(now / 1000 + 100).to_string()]));
+        assert_eq!(Frame::Integer(1), run(&db, &["PEXPIREAT", "k", &(now - 1).to_string()]));
+        assert_eq!(Frame::Null, run(&db, &["GET", "k"]));
+
+        let entry = Command::from_frame(frame(&["EXPIRE", "k", "100"]))
+            .unwrap()
+            .to_log_entry()
+            .unwrap();
+        assert_eq!(&entry[0][..], b"PEXPIREAT");
+        let deadline: i64 = std::str::from_utf8(&entry[2]).unwrap().parse().unwrap();
+        assert!((deadline - (now + 100_000)).abs() < 1000);
+
+        let get = Command::from_frame(frame(&["GET", "k"])).unwrap();
+        assert!(get.to_log_entry().is_none());
+    }
+
+    #[test]
+    fn hello() {
+        match Command::from_frame(frame(&["HELLO", "3"])).unwrap() {
+            Command::Hello { protocol: Some(3) } => {}
+            command => panic!("unexpected {:?}", command),
+        }
+        assert!(matches!(hello_protocol(3), Ok(Protocol::Resp3)));
+        assert!(hello_protocol(4).is_err());
+
+        match hello_reply(Protocol::Resp3, 7) {
+            Frame::Map(pairs) => {
+                assert!(pairs.contains(&(Frame::Bulk(Bytes::from("proto")), Frame::Integer(3))));
+                assert!(pairs.contains(&(Frame::Bulk(Bytes::from("id")), Frame::Integer(7))));
+            }
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn set_with_expiration() {
+        let db = new_shared_db(4);
+
+        run(&db, &["SET", "k", "v", "PX", "10"]);
+        assert_eq!(Frame::Bulk(Bytes::from("v")), run(&db, &["GET", "k"]));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(Frame::Null, run(&db, &["GET", "k"]));
+
+        run(&db, &["SET", "k", "v", "EX", "100"]);
+        run(&db, &["SET", "k", "w"]);
+        assert_eq!(Frame::Integer(-1), run(&db, &["TTL", "k"]));
+
+        assert!(Command::from_frame(frame(&["SET", "k", "v", "EX", "0"])).is_err());
+    }
+
+    #[test]
+    fn error_replies() {
+        let reply = |parts: &[&str]| match Command::from_frame(frame(parts)) {
+            Ok(command) => panic!("unexpected {:?}", command),
+            Err(err) => Frame::from(err),
+        };
+        let error = |msg: &str| Frame::Error(msg.to_string());
+
+        assert_eq!(error("ERR unknown command 'foo'"), reply(&["FOO", "bar"]));
+        assert_eq!(error("ERR wrong number of arguments for 'get' command"), reply(&["GET"]));
+        assert_eq!(error("ERR wrong number of arguments for 'get' command"), reply(&["GET", "a", "b"]));
+        assert_eq!(error("ERR value is not an integer or out of range"), reply(&["EXPIRE", "k", "soon"]));
+        assert_eq!(error("ERR syntax error"), reply(&["SET", "k", "v", "KEEPTTL"]));
+        assert_eq!(error("ERR invalid expire time in 'set' command"), reply(&["SET", "k", "v", "EX", "0"]));
+        assert_eq!(error("ERR Protocol error: empty command"), reply(&[]));
+
+        match Command::from_frame(Frame::Integer(1)) {
+            Err(Error::Protocol(_)) => {}
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let db = new_shared_db(4);
+        let error = |msg: &str| Frame::Error(msg.to_string());
+
+        assert_eq!(Frame::Integer(1), run(&db, &["INCR", "n"]));
+        assert_eq!(Frame::Integer(11), run(&db, &["INCRBY", "n", "10"]));
+        assert_eq!(Frame::Integer(10), run(&db, &["DECR", "n"]));
+        assert_eq!(Frame::Integer(-5), run(&db, &["DECRBY", "n", "15"]));
+        assert_eq!(Frame::Integer(-1), run(&db, &["DECR", "missing"]));
+        assert_eq!(Frame::Bulk(Bytes::from("-5")), run(&db, &["GET", "n"]));
+
+        // Counters keep their expiration.
+        run(&db, &["EXPIRE", "n", "100"]);
+        run(&db, &["INCR", "n"]);
+        assert_eq!(Frame::Integer(100), run(&db, &["TTL", "n"]));
+
+        run(&db, &["SET", "max", &i64::MAX.to_string()]);
+        assert_eq!(error("ERR increment or decrement would overflow"), run(&db, &["INCR", "max"]));
+        run(&db, &["SET", "text", "abc"]);
+        assert_eq!(error("ERR value is not an integer or out of range"), run(&db, &["INCR", "text"]));
+        run(&db, &["SET", "float", "1.5"]);
+        assert_eq!(error("ERR value is not an integer or out of range"), run(&db, &["INCRBY", "float", "1"]));
+        assert!(Command::from_frame(frame(&["DECRBY", "n", &i64::MIN.to_string()])).is_err());
+        assert!(Command::from_frame(frame(&["INCRBY", "n", "1.5"])).is_err());
+
+        assert_eq!(Frame::Bulk(Bytes::from("1.75")), run(&db, &["INCRBYFLOAT", "float", "0.25"]));
+        assert_eq!(Frame::Bulk(Bytes::from("-0.25")), run(&db, &["INCRBYFLOAT", "float", "-2"]));
+        assert_eq!(Frame::Bulk(Bytes::from("5000")), run(&db, &["INCRBYFLOAT", "new", "5e3"]));
+        assert_eq!(Frame::Integer(5001), run(&db, &["INCR", "new"]));
+        assert_eq!(error("ERR value is not a valid float"), run(&db, &["INCRBYFLOAT", "text", "1"]));
+        assert_eq!(
+            error("ERR increment would produce NaN or Infinity"),
+            run(&db, &["INCRBYFLOAT", "other", "inf"])
+        );
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "other"]));
+        assert!(Command::from_frame(frame(&["INCRBYFLOAT", "float", "abc"])).is_err());
+
+        run(&db, &["RPUSH", "l", "a"]);
+        assert!(matches!(run(&db, &["INCR", "l"]), Frame::Error(msg) if msg.starts_with("WRONGTYPE")));
+    }
+
+    #[test]
+    fn lists() {
+        let db = new_shared_db(4);
+
+        assert_eq!(Frame::Integer(3), run(&db, &["RPUSH", "l", "b", "c", "d"]));
+        assert_eq!(Frame::Integer(5), run(&db, &["LPUSH", "l", "a", "z"]));
+        assert_eq!(Frame::Integer(5), run(&db, &["LLEN", "l"]));
+        assert_eq!(frame(&["z", "a", "b", "c", "d"]), run(&db, &["LRANGE", "l", "0", "-1"]));
+        assert_eq!(frame(&["c", "d"]), run(&db, &["LRANGE", "l", "-2", "100"]));
+        assert_eq!(frame(&["z", "a"]), run(&db, &["LRANGE", "l", "-100", "1"]));
+        assert_eq!(frame(&[]), run(&db, &["LRANGE", "l", "3", "1"]));
+        assert_eq!(frame(&[]), run(&db, &["LRANGE", "l", "5", "10"]));
+        assert_eq!(frame(&[]), run(&db, &["LRANGE", "missing", "0", "-1"]));
+
+        assert_eq!(Frame::Bulk(Bytes::from("z")), run(&db, &["LPOP", "l"]));
+        assert_eq!(Frame::Bulk(Bytes::from("d")), run(&db, &["RPOP", "l"]));
+        assert_eq!(frame(&["a", "b"]), run(&db, &["LPOP", "l", "2"]));
+        assert_eq!(frame(&["c"]), run(&db, &["RPOP", "l", "10"]));
+
+        // The list is gone with its last element.
+        assert_eq!(Frame::Integer(0), run(&db, &["LLEN", "l"]));
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "l"]));
+        assert_eq!(Frame::Null, run(&db, &["LPOP", "l"]));
+        assert_eq!(Frame::Null, run(&db, &["RPOP", "l", "2"]));
+        assert!(Command::from_frame(frame(&["LPOP", "l", "-1"])).is_err());
+    }
+
+    #[test]
+    fn wrong_type() {
+        let db = new_shared_db(4);
+        let wrong_type =
+            Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+
+        run(&db, &["SET", "s", "v"]);
+        run(&db, &["RPUSH", "l", "a"]);
+
+        assert_eq!(wrong_type, run(&db, &["GET", "l"]));
+        assert_eq!(wrong_type, run(&db, &["LPUSH", "s", "a"]));
+        assert_eq!(wrong_type, run(&db, &["RPOP", "s"]));
+        assert_eq!(wrong_type, run(&db, &["LRANGE", "s", "0", "-1"]));
+        assert_eq!(wrong_type, run(&db, &["LLEN", "s"]));
+
+        // SET replaces values of any type.
+        assert_eq!(Frame::Simple("OK".to_string()), run(&db, &["SET", "l", "v"]));
+        assert_eq!(Frame::Bulk(Bytes::from("v")), run(&db, &["GET", "l"]));
+    }
+
+    #[test]
+    fn hashes() {
+        let db = new_shared_db(4);
+        let bulk = |s: &'static str| Frame::Bulk(Bytes::from(s));
+
+        assert_eq!(Frame::Integer(2), run(&db, &["HSET", "h", "name", "ada", "age", "36"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["HSET", "h", "name", "grace", "lang", "cobol"]));
+        assert_eq!(bulk("grace"), run(&db, &["HGET", "h", "name"]));
+        assert_eq!(Frame::Null, run(&db, &["HGET", "h", "missing"]));
+        assert_eq!(Frame::Null, run(&db, &["HGET", "missing", "name"]));
+        assert_eq!(Frame::Integer(3), run(&db, &["HLEN", "h"]));
+
+        assert_eq!(Frame::Integer(37), run(&db, &["HINCRBY", "h", "age", "1"]));
+        assert_eq!(Frame::Integer(-5), run(&db, &["HINCRBY", "h", "score", "-5"]));
+        assert_eq!(
+            Frame::Error("ERR hash value is not an integer".to_string()),
+            run(&db, &["HINCRBY", "h", "name", "1"])
+        );
+        run(&db, &["HSET", "h", "big", &i64::MAX.to_string()]);
+        assert_eq!(
+            Frame::Error("ERR increment or decrement would overflow".to_string()),
+            run(&db, &["HINCRBY", "h", "big", "1"])
+        );
+
+        match run(&db, &["HGETALL", "h"]) {
+            Frame::Map(mut pairs) => {
+                pairs.sort_by_key(|(field, _)| field.to_string());
+                assert_eq!(
+                    vec![
+                        (bulk("age"), bulk("37")),
+                        (bulk("big"), Frame::Bulk(Bytes::from(i64::MAX.to_string()))),
+                        (bulk("lang"), bulk("cobol")),
+                        (bulk("name"), bulk("grace")),
+                        (bulk("score"), bulk("-5")),
+                    ],
+                    pairs
+                );
+            }
+            frame => panic!("unexpected {:?}", frame),
+        }
+        assert_eq!(Frame::Map(vec![]), run(&db, &["HGETALL", "missing"]));
+
+        assert_eq!(Frame::Integer(2), run(&db, &["HDEL", "h", "age", "missing", "score"]));
+        assert_eq!(Frame::Integer(3), run(&db, &["HDEL", "h", "big", "lang", "name"]));
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "h"]));
+
+        assert!(Command::from_frame(frame(&["HSET", "h", "field"])).is_err());
+        assert!(Command::from_frame(frame(&["HSET", "h", "a", "1", "b"])).is_err());
+
+        run(&db, &["RPUSH", "l", "a"]);
+        for command in [&["HGET", "l", "f"][..], &["HSET", "l", "f", "v"], &["HGETALL", "l"], &["HLEN", "l"]] {
+            assert!(matches!(run(&db, command), Frame::Error(msg) if msg.starts_with("WRONGTYPE")));
+        }
+    }
+
+    fn sorted_members(frame: Frame) -> Vec<String> {
+        match frame {
+            Frame::Set(members) => {
+                let mut members: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+                members.sort();
+                members
+            }
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn sets() {
+        let db = new_shared_db(4);
+
+        assert_eq!(Frame::Integer(3), run(&db, &["SADD", "a", "1", "2", "3", "2"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["SADD", "a", "3", "4"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["SISMEMBER", "a", "4"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["SISMEMBER", "a", "5"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["SISMEMBER", "missing", "5"]));
+        assert_eq!(vec!["1", "2", "3", "4"], sorted_members(run(&db, &["SMEMBERS", "a"])));
+        assert_eq!(Vec::<String>::new(), sorted_members(run(&db, &["SMEMBERS", "missing"])));
+
+        run(&db, &["SADD", "b", "3", "4", "5"]);
+        run(&db, &["SADD", "c", "4", "5", "6"]);
+        assert_eq!(vec!["4"], sorted_members(run(&db, &["SINTER", "a", "b", "c"])));
+        assert_eq!(Vec::<String>::new(), sorted_members(run(&db, &["SINTER", "a", "missing"])));
+        assert_eq!(
+            vec!["1", "2", "3", "4", "5", "6"],
+            sorted_members(run(&db, &["SUNION", "a", "b", "c", "missing"]))
+        );
+        assert_eq!(vec!["1", "2"], sorted_members(run(&db, &["SDIFF", "a", "b", "c"])));
+        assert_eq!(vec!["1", "2", "3", "4"], sorted_members(run(&db, &["SDIFF", "a", "missing"])));
+        assert_eq!(Vec::<String>::new(), sorted_members(run(&db, &["SDIFF", "missing", "a"])));
+        assert_eq!(vec!["1", "2", "3", "4"], sorted_members(run(&db, &["SINTER", "a", "a"])));
+
+        assert_eq!(Frame::Integer(2), run(&db, &["SREM", "a", "1", "2", "9"]));
+        assert_eq!(Frame::Integer(2), run(&db, &["SREM", "a", "3", "4"]));
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "a"]));
+
+        run(&db, &["SET", "s", "v"]);
+        for command in [&["SADD", "s", "m"][..], &["SMEMBERS", "s"], &["SINTER", "b", "s"], &["SDIFF", "missing", "s"]] {
+            assert!(matches!(run(&db, command), Frame::Error(msg) if msg.starts_with("WRONGTYPE")));
+        }
+    }
+
+    #[test]
+    fn sorted_sets() {
+        let db = new_shared_db(4);
+        let scored = |pairs: &[(&str, f64)]| {
+            Frame::Array(
+                pairs
+                    .iter()
+                    .flat_map(|(member, score)| {
+                        [Frame::Bulk(Bytes::from(member.to_string())), Frame::Double(*score)]
+                    })
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            Frame::Integer(4),
+            run(&db, &["ZADD", "board", "30", "carol", "10", "alice", "10", "bob", "-inf", "dave"])
+        );
+        assert_eq!(Frame::Integer(0), run(&db, &["ZADD", "board", "40", "alice"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["ZADD", "board", "CH", "40", "alice", "50", "erin"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["ZADD", "board", "NX", "0", "alice", "60", "erin"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["ZADD", "board", "XX", "CH", "20", "bob", "1", "frank"]));
+        assert_eq!(Frame::Integer(5), run(&db, &["ZCARD", "board"]));
+        assert_eq!(Frame::Double(40.0), run(&db, &["ZSCORE", "board", "alice"]));
+        assert_eq!(Frame::Null, run(&db, &["ZSCORE", "board", "frank"]));
+
+        assert_eq!(
+            frame(&["dave", "bob", "carol", "alice", "erin"]),
+            run(&db, &["ZRANGE", "board", "0", "-1"])
+        );
+        assert_eq!(
+            scored(&[("alice", 40.0), ("erin", 50.0)]),
+            run(&db, &["ZRANGE", "board", "-2", "10", "WITHSCORES"])
+        );
+        assert_eq!(frame(&["erin", "alice"]), run(&db, &["ZREVRANGE", "board", "0", "1"]));
+        assert_eq!(frame(&["alice", "carol"]), run(&db, &["ZRANGE", "board", "1", "2", "REV"]));
+        assert_eq!(frame(&[]), run(&db, &["ZRANGE", "board", "3", "1"]));
+        assert_eq!(frame(&[]), run(&db, &["ZRANGE", "missing", "0", "-1"]));
+
+        assert_eq!(frame(&["bob", "carol", "alice"]), run(&db, &["ZRANGEBYSCORE", "board", "20", "40"]));
+        assert_eq!(
+            scored(&[("carol", 30.0)]),
+            run(&db, &["ZRANGEBYSCORE", "board", "(20", "(40", "WITHSCORES"])
+        );
+        assert_eq!(
+            frame(&["carol", "alice"]),
+            run(&db, &["ZRANGEBYSCORE", "board", "-inf", "+inf", "LIMIT", "2", "2"])
+        );
+        assert_eq!(
+            frame(&["alice", "carol", "bob"]),
+            run(&db, &["ZREVRANGEBYSCORE", "board", "+inf", "(-inf", "LIMIT", "1", "-1"])
+        );
+        assert_eq!(
+            frame(&["carol", "bob"]),
+            run(&db, &["ZRANGE", "board", "40", "0", "BYSCORE", "REV", "LIMIT", "1", "2"])
+        );
+        assert_eq!(frame(&[]), run(&db, &["ZRANGEBYSCORE", "board", "100", "200"]));
+
+        assert_eq!(Frame::Integer(3), run(&db, &["ZRANK", "board", "alice"]));
+        assert_eq!(Frame::Integer(1), run(&db, &["ZREVRANK", "board", "alice"]));
+        assert_eq!(Frame::Null, run(&db, &["ZRANK", "board", "frank"]));
+
+        assert_eq!(Frame::Integer(2), run(&db, &["ZREM", "board", "alice", "frank", "dave"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["ZRANK", "board", "bob"]));
+        assert_eq!(Frame::Integer(0), run(&db, &["ZADD", "new", "XX", "1", "a"]));
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "new"]));
+        assert_eq!(Frame::Integer(3), run(&db, &["ZREM", "board", "bob", "carol", "erin"]));
+        assert_eq!(Frame::Integer(-2), run(&db, &["TTL", "board"]));
+
+        let error = |parts: &[&str]| match Command::from_frame(frame(parts)) {
+            Ok(command) => panic!("unexpected {:?}", command),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!("ERR value is not a valid float", error(&["ZADD", "z", "nan", "a"]));
+        assert_eq!("ERR syntax error", error(&["ZADD", "z", "1", "a", "2"]));
+        assert_eq!("ERR syntax error", error(&["ZADD", "z", "NX", "XX", "1", "a"]));
+        assert_eq!("ERR wrong number of arguments for 'zadd' command", error(&["ZADD", "z", "CH"]));
+        assert_eq!("ERR min or max is not a float", error(&["ZRANGEBYSCORE", "z", "(a", "1"]));
+        assert_eq!("ERR syntax error", error(&["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]));
+        assert_eq!("ERR value is not an integer or out of range", error(&["ZRANGE", "z", "a", "1"]));
+
+        run(&db, &["SET", "s", "v"]);
+        for command in [&["ZADD", "s", "1", "m"][..], &["ZRANGE", "s", "0", "-1"], &["ZSCORE", "s", "m"]] {
+            assert!(matches!(run(&db, command), Frame::Error(msg) if msg.starts_with("WRONGTYPE")));
+        }
+    }
+
+    /// Many threads running set algebra over keys spread across every shard, in
+    /// every order, while others keep modifying the sets.
+    #[test]
+    fn concurrent_cross_shard_set_algebra() {
+        const THREADS: usize = 16;
+        const ROUNDS: usize = 200;
+
+        let db = new_shared_db(8);
+        let keys: Vec<String> = (0..16).map(|i| format!("set:{}", i)).collect();
+        for key in &keys {
+            run(&db, &["SADD", key, "common"]);
+        }
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for thread in 0..THREADS {
+            let db = db.clone();
+            let keys = keys.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                let pub_sub = new_shared_pub_sub();
+                for round in 0..ROUNDS {
+                    // A different rotation and direction of the keys for every round.
+                    let mut picked: Vec<&str> = keys.iter().map(String::as_str).collect();
+                    picked.rotate_left((thread * 7 + round) % keys.len());
+                    if round % 2 == 1 {
+                        picked.reverse();
+                    }
+                    picked.truncate(2 + round % 6);
+
+                    let member = format!("{}:{}", thread, round % 10);
+                    let write = if round % 3 == 0 { "SREM" } else { "SADD" };
+                    Command::from_frame(frame(&[write, picked[0], &member]))
+                        .unwrap()
+                        .apply(&db, &pub_sub);
+
+                    for op in ["SINTER", "SUNION", "SDIFF"] {
+                        let mut parts = vec![op];
+                        parts.extend(&picked);
+                        let reply = Command::from_frame(frame(&parts)).unwrap().apply(&db, &pub_sub);
+                        let members = sorted_members(reply);
+                        match op {
+                            "SINTER" => assert!(members.contains(&"common".to_string())),
+                            "SUNION" => assert!(members.contains(&"common".to_string())),
+                            _ => assert!(!members.contains(&"common".to_string())),
+                        }
+                    }
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        drop(done_tx);
+
+        for _ in 0..THREADS {
+            done_rx
+                .recv_timeout(Duration::from_secs(30))
+                .expect("set algebra deadlocked or a thread panicked");
+        }
+    }
+
+    /// Return the sorted keys of a `KEYS` reply, or of a full `SCAN` run with `options`.
+    fn listed_keys(db: &ShardedDb, command: &[&str], options: &[&str]) -> Vec<String> {
+        let to_string = |frame: &Frame| frame.to_string();
+        let mut keys = vec![];
+        if command[0] == "KEYS" {
+            match run(db, command) {
+                Frame::Array(frames) => keys.extend(frames.iter().map(to_string)),
+                frame => panic!("unexpected {:?}", frame),
+            }
+        } else {
+            let mut cursor = "0".to_string();
+            loop {
+                let mut parts = vec!["SCAN", &cursor];
+                parts.extend(options);
+                match run(db, &parts) {
+                    Frame::Array(reply) => match &reply[..] {
+                        [next, Frame::Array(frames)] => {
+                            keys.extend(frames.iter().map(to_string));
+                            cursor = next.to_string();
+                        }
+                        _ => panic!("unexpected {:?}", reply),
+                    },
+                    frame => panic!("unexpected {:?}", frame),
+                }
+                if cursor == "0" {
+                    break;
+                }
+            }
+        }
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn keys_and_scan() {
+        let db = new_shared_db(4);
+        for i in 0..50 {
+            run(&db, &["SET", &format!("user:{}", i), "v"]);
+        }
+        run(&db, &["RPUSH", "user:list", "a"]);
+        run(&db, &["SADD", "tags", "a"]);
+
+        let mut users: Vec<String> = (0..50).map(|i| format!("user:{}", i)).collect();
+        users.push("user:list".to_string());
+        users.sort();
+        assert_eq!(users, listed_keys(&db, &["KEYS", "user:*"], &[]));
+        assert_eq!(10, listed_keys(&db, &["KEYS", "user:?"], &[]).len());
+        assert_eq!(vec!["tags"], listed_keys(&db, &["KEYS", "*s"], &[]));
+
+        let mut all = users.clone();
+        all.push("tags".to_string());
+        all.sort();
+        assert_eq!(all, listed_keys(&db, &["SCAN"], &[]));
+        assert_eq!(all, listed_keys(&db, &["SCAN"], &["COUNT", "3"]));
+        assert_eq!(users, listed_keys(&db, &["SCAN"], &["MATCH", "user:*", "COUNT", "1000"]));
+        assert_eq!(vec!["user:list"], listed_keys(&db, &["SCAN"], &["MATCH", "user:*", "TYPE", "LIST"]));
+        assert_eq!(vec!["tags"], listed_keys(&db, &["SCAN"], &["TYPE", "set"]));
+
+        let run_err = |parts: &[&str]| Frame::from(Command::from_frame(frame(parts)).unwrap_err());
+        let error = |msg: &str| Frame::Error(msg.to_string());
+        assert_eq!(error("ERR invalid cursor"), run_err(&["SCAN", "-1"]));
+        assert_eq!(error("ERR syntax error"), run_err(&["SCAN", "0", "COUNT", "0"]));
+        assert_eq!(error("ERR syntax error"), run_err(&["SCAN", "0", "MATCH"]));
+        assert_eq!(error("ERR syntax error"), run_err(&["SCAN", "0", "SORT"]));
+    }
+}
@@ -0,0 +1,430 @@
+//! Settings of the `server` and `client` binaries, read from a `redis.conf`
+//! style file and from the command line.
+//!
+//! A config file holds one `directive value` per line, blank lines and lines
+//! starting with `#` are skipped. On the command line every directive is also
+//! accepted as `--directive value`, and overrides the config file given as the
+//! first argument, as in `server my.conf --port 6380`.
+
+use super::aof::FsyncPolicy;
+use super::db::MAX_SHARDS;
+use super::eviction::EvictionPolicy;
+use super::server::Options;
+use super::LogLevel;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Error raised by an invalid config file or command line.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    UnknownDirective(String),
+    MissingValue(String),
+    InvalidValue {
+        directive: String,
+        value: String,
+        reason: String,
+    },
+    /// An error on a line of a config file.
+    Line {
+        path: PathBuf,
+        line: usize,
+        err: Box<ConfigError>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "can't read {:?}: {}", path, err),
+            ConfigError::UnknownDirective(directive) => write!(f, "unknown directive '{}'", directive),
+            ConfigError::MissingValue(directive) => write!(f, "missing value for '{}'", directive),
+            ConfigError::InvalidValue {
+                directive,
+                value,
+                reason,
+            } => write!(f, "invalid {} '{}': {}", directive, value, reason),
+            ConfigError::Line { path, line, err } => write!(f, "{}:{}: {}", path.display(), line, err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings which can be read from a config file and the command line.
+pub trait Configure: Sized + Default {
+    /// The help printed for `--help`.
+    const USAGE: &'static str;
+
+    /// Apply one `directive value` setting.
+    fn set(&mut self, directive: &str, value: &str) -> Result<(), ConfigError>;
+
+    /// Apply the settings of the config file at `path`.
+    fn load_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let content = fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (directive, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            let result = if value.is_empty() {
+                Err(ConfigError::MissingValue(directive.to_string()))
+            } else {
+                self.set(&directive.to_lowercase(), unquote(value))
+            };
+            result.map_err(|err| ConfigError::Line {
+                path: path.to_path_buf(),
+                line: i + 1,
+                err: Box::new(err),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Build the settings from the command line, without the program name.
+    ///
+    /// Returns `None` when `--help` is asked for.
+    fn from_args<I>(args: I) -> Result<Option<Self>, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with('-')) {
+            config.load_file(path.as_ref())?;
+        }
+
+        while let Some(arg) = args.next() {
+            let directive = match arg.strip_prefix("--") {
+                Some("help") => return Ok(None),
+                Some(directive) => directive.to_lowercase(),
+                None if arg == "-h" => return Ok(None),
+                None => return Err(ConfigError::UnknownDirective(arg)),
+            };
+            let value = args.next().ok_or_else(|| ConfigError::MissingValue(directive.clone()))?;
+            config.set(&directive, &value)?;
+        }
+
+        Ok(Some(config))
+    }
+}
+
+/// Settings of the server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    /// How many shards the keys are spread over.
+    pub shards: usize,
+    pub loglevel: LogLevel,
+    /// Whether the dataset is loaded from a snapshot on startup and saved to it on shutdown.
+    pub save: bool,
+    /// Where snapshots are saved to and loaded from.
+    pub dbfilename: PathBuf,
+    pub appendonly: bool,
+    pub appendfilename: PathBuf,
+    pub appendfsync: FsyncPolicy,
+    /// The bytes the dataset may use before keys are evicted, 0 without a limit.
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub options: Options,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            shards: 8,
+            loglevel: LogLevel::default(),
+            save: false,
+            dbfilename: PathBuf::from("dump.snapshot"),
+            appendonly: false,
+            appendfilename: PathBuf::from("appendonly.aof"),
+            appendfsync: FsyncPolicy::EverySec,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            options: Options::default(),
+        }
+    }
+}
+
+impl Configure for ServerConfig {
+    const USAGE: &'static str = "\
+Usage: server [config-file] [--directive value]...
+
+Directives, in the config file as `directive value`:
+    bind <address>           address to listen on (127.0.0.1)
+    port <port>              port to listen on (6379)
+    shards <count>           number of database shards, at most 65536 (8)
+    loglevel <level>         debug, verbose, notice or warning (verbose)
+    save <yes|no>            load a snapshot on startup and save one on shutdown,
+                             \"\" for no (no)
+    dbfilename <path>        snapshot file, \"\" to disable snapshots (dump.snapshot)
+    appendonly <yes|no>      enable the append-only log (no)
+    appendfilename <path>    append-only log file (appendonly.aof)
+    appendfsync <policy>     always, everysec or no (everysec)
+    maxmemory <bytes>        memory limit of the dataset, with a k, kb, m, mb, g or gb
+                             unit, 0 for none (0)
+    maxmemory-policy <name>  noeviction, allkeys-lru, allkeys-lfu, volatile-ttl or
+                             allkeys-random (noeviction)
+    maxclients <count>       maximum number of connected clients (10000)
+    timeout <seconds>        close connections idle for this long, 0 never (0)
+    shutdown-timeout <secs>  how long connections get to finish on shutdown (10)";
+
+    fn set(&mut self, directive: &str, value: &str) -> Result<(), ConfigError> {
+        match directive {
+            "bind" => self.bind = value.to_string(),
+            "port" => self.port = parse(directive, value)?,
+            "shards" => {
+                self.shards = parse(directive, value)?;
+                if self.shards == 0 || self.shards > MAX_SHARDS {
+                    return Err(invalid(directive, value, format!("expected 1 to {} shards", MAX_SHARDS)));
+                }
+            }
+            "loglevel" => self.loglevel = parse(directive, value)?,
+            // `save ""` disables snapshots in Redis too.
+            "save" => self.save = !value.is_empty() && parse_yes_no(directive, value)?,
+            "dbfilename" => self.dbfilename = PathBuf::from(value),
+            "appendonly" => self.appendonly = parse_yes_no(directive, value)?,
+            "appendfilename" => self.appendfilename = PathBuf::from(value),
+            "appendfsync" => self.appendfsync = parse(directive, value)?,
+            "maxmemory" => self.maxmemory = parse_memory(directive, value)?,
+            "maxmemory-policy" => self.maxmemory_policy = parse(directive, value)?,
+            "maxclients" => {
+                self.options.max_clients = parse(directive, value)?;
+                if self.options.max_clients == 0 {
+                    return Err(invalid(directive, value, "expected at least one client"));
+                }
+            }
+            "timeout" => {
+                let timeout = Duration::from_secs(parse(directive, value)?);
+                self.options.idle_timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+            }
+            "shutdown-timeout" => self.options.drain_timeout = Duration::from_secs(parse(directive, value)?),
+            _ => return Err(ConfigError::UnknownDirective(directive.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /// The address to listen on.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    /// The snapshot file, `None` unless enabled by `save yes` with a `dbfilename`.
+    pub fn snapshot_path(&self) -> Option<&Path> {
+        (self.save && !self.dbfilename.as_os_str().is_empty()).then_some(self.dbfilename.as_path())
+    }
+
+    /// The append-only log, `None` unless enabled by `appendonly yes`.
+    pub fn aof_path(&self) -> Option<&Path> {
+        self.appendonly.then_some(self.appendfilename.as_path())
+    }
+}
+
+/// Settings of the client.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    pub loglevel: LogLevel,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            loglevel: LogLevel::default(),
+        }
+    }
+}
+
+impl Configure for ClientConfig {
+    const USAGE: &'static str = "\
+Usage: client [config-file] [--directive value]...
+
+Directives, in the config file as `directive value`:
+    host <address>    server address (127.0.0.1)
+    port <port>       server port (6379)
+    loglevel <level>  debug, verbose, notice or warning (verbose)";
+
+    fn set(&mut self, directive: &str, value: &str) -> Result<(), ConfigError> {
+        match directive {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = parse(directive, value)?,
+            "loglevel" => self.loglevel = parse(directive, value)?,
+            _ => return Err(ConfigError::UnknownDirective(directive.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    /// The address of the server.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn invalid(directive: &str, value: &str, reason: impl ToString) -> ConfigError {
+    ConfigError::InvalidValue {
+        directive: directive.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn parse<T>(directive: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err| invalid(directive, value, err))
+}
+
+fn parse_yes_no(directive: &str, value: &str) -> Result<bool, ConfigError> {
+    match &value.to_lowercase()[..] {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(invalid(directive, value, "expected yes or no")),
+    }
+}
+
+/// Parse an amount of memory such as `100mb`, with the units of Redis: `k`,
+/// `m` and `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+fn parse_memory(directive: &str, value: &str) -> Result<usize, ConfigError> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: usize = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1 << 10,
+        "m" => 1000 * 1000,
+        "mb" => 1 << 20,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1 << 30,
+        _ => return Err(invalid(directive, value, "expected a k, kb, m, mb, g or gb unit")),
+    };
+    let count: usize = parse(directive, digits)?;
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| invalid(directive, value, "too large"))
+}
+
+/// Strip the double quotes around a config file value, if any.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = std::env::temp_dir().join(format!("learn_rust_config_{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            "# test instance\n\nport 7000\nshards 4\nloglevel warning\nappendonly yes\nappendfsync always\n\
+             save yes\ndbfilename \"/tmp/my dump.snapshot\"\ntimeout 30\nmaxmemory 100mb\n\
+             maxmemory-policy allkeys-lru\n",
+        )
+        .unwrap();
+
+        let config = ServerConfig::from_args(args(&[path.to_str().unwrap(), "--port", "7001", "--maxclients", "2"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!("127.0.0.1:7001", config.addr());
+        assert_eq!(4, config.shards);
+        assert_eq!(LogLevel::Warning, config.loglevel);
+        assert_eq!(Some(Path::new("appendonly.aof")), config.aof_path());
+        assert_eq!(FsyncPolicy::Always, config.appendfsync);
+        assert_eq!(Some(Path::new("/tmp/my dump.snapshot")), config.snapshot_path());
+        assert_eq!(2, config.options.max_clients);
+        assert_eq!(Some(Duration::from_secs(30)), config.options.idle_timeout);
+        assert_eq!(100 << 20, config.maxmemory);
+        assert_eq!(EvictionPolicy::AllKeysLru, config.maxmemory_policy);
+
+        fs::write(&path, "port 7000\nbogus 1\n").unwrap();
+        match ServerConfig::from_args(args(&[path.to_str().unwrap()])) {
+            Err(ConfigError::Line { line: 2, .. }) => {}
+            config => panic!("unexpected {:?}", config),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(ClientConfig::from_args(args(&["--help"])).unwrap().is_none());
+        assert!(matches!(
+            ClientConfig::from_args(args(&["--port"])),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            ClientConfig::from_args(args(&["--port", "99999"])),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            ServerConfig::from_args(args(&["--shards", "0"])),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            ServerConfig::from_args(args(&["--appendonly", "maybe"])),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        for (directive, value) in [
+            ("--maxmemory", "1tb"),
+            ("--maxmemory", "mb"),
+            ("--maxmemory-policy", "lru"),
+        ] {
+            assert!(matches!(
+                ServerConfig::from_args(args(&[directive, value])),
+                Err(ConfigError::InvalidValue { .. })
+            ));
+        }
+        let config = ServerConfig::from_args(args(&["--maxmemory", "2k"])).unwrap().unwrap();
+        assert_eq!(2000, config.maxmemory);
+
+        let config = ClientConfig::from_args(args(&["--host", "10.0.0.1", "--port", "7000"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!("10.0.0.1:7000", config.addr());
+    }
+
+    #[test]
+    fn snapshots_are_opt_in() {
+        assert_eq!(None, ServerConfig::default().snapshot_path());
+        let config = ServerConfig::from_args(args(&["--save", "yes"])).unwrap().unwrap();
+        assert_eq!(Some(Path::new("dump.snapshot")), config.snapshot_path());
+        for disabling in [&["--save", ""][..], &["--save", "no"], &["--dbfilename", ""]] {
+            let config = ServerConfig::from_args(args(&[&["--save", "yes"][..], disabling].concat()))
+                .unwrap()
+                .unwrap();
+            assert_eq!(None, config.snapshot_path());
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use super::error::{Error, Result};
+use super::frame::{self, Frame, Protocol};
+use bytes::{Buf, BytesMut};
+use futures::future::BoxFuture;
+use std::io::Cursor;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+
+/// The default size past which [`Connection::read_frame`] gives up on an incomplete frame.
+pub const DEFAULT_MAX_BUFFER: usize = 64 * 1024 * 1024;
+
+/// Send and receive [`Frame`]s over a `TcpStream`.
+///
+/// Outgoing frames are written into a `BufWriter` and flushed once per frame.
+/// RESP3 frames are downgraded to their RESP2 counterparts unless the
+/// connection has switched to [`Protocol::Resp3`].
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+    max_buffer: usize,
+    max_depth: usize,
+    /// The length of the complete frame at the start of `buffer`, once checked
+    /// by [`parse_frame`](Connection::parse_frame) after the previous frame.
+    next_frame: Option<usize>,
+    /// Whether the input left after the last parsed frame holds another
+    /// complete frame, or an invalid one.
+    buffered_frame: bool,
+    protocol: Protocol,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream: BufWriter::new(stream),
+            buffer: BytesMut::with_capacity(4096),
+            max_buffer: DEFAULT_MAX_BUFFER,
+            max_depth: frame::MAX_DEPTH,
+            next_frame: None,
+            buffered_frame: false,
+            protocol: Protocol::Resp2,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Limit how many bytes of a single incoming frame are buffered.
+    pub fn set_max_buffer(&mut self, max_buffer: usize) {
+        self.max_buffer = max_buffer;
+    }
+
+    /// Limit how deep aggregates may be nested in a single incoming frame, up
+    /// to [`frame::MAX_DEPTH`].
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Read the next frame, `None` once the peer closed the connection.
+    ///
+    /// On [`Error::Protocol`] the buffered input is discarded, so that the
+    /// connection can keep reading frames sent after the error is answered.
+    /// [`Error::RequestTooLarge`] is returned once the buffer holds more than
+    /// the limit set by [`set_max_buffer`](Connection::set_max_buffer) without a
+    /// complete frame, or as soon as a string announces more bytes than the limit.
+    /// [`Error::RequestTooDeep`] is returned for a frame nesting aggregates deeper
+    /// than the limit set by [`set_max_depth`](Connection::set_max_depth).
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            match self.parse_frame() {
+                Ok(Some(frame)) => return Ok(Some(frame)),
+                Ok(None) => {}
+                Err(err @ (Error::RequestTooLarge | Error::RequestTooDeep)) => {
+                    self.buffer = BytesMut::new();
+                    return Err(err);
+                }
+                Err(err) => {
+                    self.buffer.clear();
+                    return Err(err);
+                }
+            }
+
+            if self.buffer.len() >= self.max_buffer {
+                self.buffer = BytesMut::new();
+                return Err(Error::RequestTooLarge);
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+                }
+            }
+        }
+    }
+
+    /// Wait until the peer closes the connection, buffering whatever it sends
+    /// meanwhile for [`read_frame`](Connection::read_frame) to parse later on.
+    ///
+    /// Returns [`Error::RequestTooLarge`] once the buffer holds more than the limit.
+    pub async fn closed(&mut self) -> Result<()> {
+        loop {
+            if self.buffer.len() >= self.max_buffer {
+                return Err(Error::RequestTooLarge);
+            }
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether the input left after the last parsed frame holds a complete
+    /// frame, or an invalid one, so that [`read_frame`](Connection::read_frame)
+    /// returns without reading.
+    ///
+    /// Input buffered since then is only looked at by the next parse.
+    pub fn has_buffered_frame(&self) -> bool {
+        self.buffered_frame
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.buffer_frame(frame).await?;
+        self.flush().await
+    }
+
+    /// Write `frames` and flush them at once, for pipelines.
+    pub async fn write_frames(&mut self, frames: &[Frame]) -> Result<()> {
+        for frame in frames {
+            self.buffer_frame(frame).await?;
+        }
+        self.flush().await
+    }
+
+    /// Encode `frame` into the write buffer, to be sent by the next
+    /// [`flush`](Connection::flush), or earlier once the buffer is full.
+    pub async fn buffer_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.write_value(frame).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Encode `frame` into the write buffer, recursing into aggregates.
+    fn write_value<'a>(&'a mut self, frame: &'a Frame) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let resp3 = self.protocol == Protocol::Resp3;
+
+            match frame {
+                Frame::Simple(val) => {
+                    self.stream.write_u8(b'+').await?;
+                    self.stream.write_all(val.as_bytes()).await?;
+                    self.stream.write_all(b"\r\n").await?;
+                }
+                Frame::Error(val) => {
+                    self.stream.write_u8(b'-').await?;
+                    self.stream.write_all(val.as_bytes()).await?;
+                    self.stream.write_all(b"\r\n").await?;
+                }
+                Frame::Integer(val) => {
+                    self.stream.write_u8(b':').await?;
+                    self.write_decimal(*val).await?;
+                }
+                Frame::Null | Frame::NullArray if resp3 => {
+                    self.stream.write_all(b"_\r\n").await?;
+                }
+                Frame::Null => {
+                    self.stream.write_all(b"$-1\r\n").await?;
+                }
+                Frame::NullArray => {
+                    self.stream.write_all(b"*-1\r\n").await?;
+                }
+                Frame::Bulk(val) => self.write_bulk(val).await?,
+                Frame::Array(val) => self.write_aggregate(b'*', val).await?,
+                Frame::Set(val) => self.write_aggregate(if resp3 { b'~' } else { b'*' }, val).await?,
+                Frame::Push(val) => self.write_aggregate(if resp3 { b'>' } else { b'*' }, val).await?,
+                Frame::Map(pairs) => {
+                    // RESP2 has no maps, they are flattened into an array of keys and values.
+                    if resp3 {
+                        self.stream.write_u8(b'%').await?;
+                        self.write_decimal(pairs.len() as i64).await?;
+                    } else {
+                        self.stream.write_u8(b'*').await?;
+                        self.write_decimal(2 * pairs.len() as i64).await?;
+                    }
+                    for (key, value) in pairs {
+                        self.write_value(key).await?;
+                        self.write_value(value).await?;
+                    }
+                }
+                Frame::Double(val) => {
+                    let val = format_double(*val);
+                    if resp3 {
+                        self.stream.write_u8(b',').await?;
+                        self.stream.write_all(val.as_bytes()).await?;
+                        self.stream.write_all(b"\r\n").await?;
+                    } else {
+                        self.write_bulk(val.as_bytes()).await?;
+                    }
+                }
+                Frame::Boolean(val) if resp3 => {
+                    self.stream.write_all(if *val { b"#t\r\n" } else { b"#f\r\n" }).await?;
+                }
+                Frame::Boolean(val) => {
+                    self.stream.write_u8(b':').await?;
+                    self.write_decimal(*val as i64).await?;
+                }
+                Frame::BigNumber(val) if resp3 => {
+                    self.stream.write_u8(b'(').await?;
+                    self.stream.write_all(val.as_bytes()).await?;
+                    self.stream.write_all(b"\r\n").await?;
+                }
+                Frame::BigNumber(val) => self.write_bulk(val.as_bytes()).await?,
+                Frame::Verbatim { format, data } if resp3 => {
+                    self.stream.write_u8(b'=').await?;
+                    self.write_decimal((data.len() + 4) as i64).await?;
+                    self.stream.write_all(format.as_bytes()).await?;
+                    self.stream.write_u8(b':').await?;
+                    self.stream.write_all(data).await?;
+                    self.stream.write_all(b"\r\n").await?;
+                }
+                Frame::Verbatim { data, .. } => self.write_bulk(data).await?,
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn write_aggregate(&mut self, prefix: u8, entries: &[Frame]) -> io::Result<()> {
+        self.stream.write_u8(prefix).await?;
+        self.write_decimal(entries.len() as i64).await?;
+        for entry in entries {
+            self.write_value(entry).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_bulk(&mut self, val: &[u8]) -> io::Result<()> {
+        self.stream.write_u8(b'$').await?;
+        self.write_decimal(val.len() as i64).await?;
+        self.stream.write_all(val).await?;
+        self.stream.write_all(b"\r\n").await?;
+
+        Ok(())
+    }
+
+    async fn write_decimal(&mut self, val: i64) -> io::Result<()> {
+        use std::io::Write;
+
+        let mut buf = [0u8; 20];
+        let mut buf = Cursor::new(&mut buf[..]);
+        write!(&mut buf, "{}", val)?;
+
+        let pos = buf.position() as usize;
+        self.stream.write_all(&buf.get_ref()[..pos]).await?;
+        self.stream.write_all(b"\r\n").await?;
+
+        Ok(())
+    }
+
+    pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
+        self.buffered_frame = false;
+        let len = match self.next_frame.take() {
+            Some(len) => len,
+            None => match self.check_frame() {
+                Ok(len) => len,
+                Err(frame::Error::Incomplete) => return Ok(None),
+                Err(e) => return Err(Error::from(e)),
+            },
+        };
+
+        let frame = Frame::parse(&mut Cursor::new(&self.buffer[..len]))?;
+        self.buffer.advance(len);
+
+        // Checked once here, the next frame is not checked again by the next parse.
+        if !self.buffer.is_empty() {
+            match self.check_frame() {
+                Ok(len) => {
+                    self.next_frame = Some(len);
+                    self.buffered_frame = true;
+                }
+                Err(frame::Error::Incomplete) => {}
+                Err(_) => self.buffered_frame = true,
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    /// Return the length of the complete frame at the start of the buffer.
+    fn check_frame(&self) -> std::result::Result<usize, frame::Error> {
+        let mut buf = Cursor::new(&self.buffer[..]);
+        Frame::check_within(&mut buf, self.max_buffer, self.max_depth)?;
+        Ok(buf.position() as usize)
+    }
+}
+
+/// Format a double the way Redis does, with `inf`, `-inf` and `nan` for the special values.
+fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "nan".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    async fn connection_pair() -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (Connection::new(client.unwrap()), Connection::new(server.unwrap().0))
+    }
+
+    fn nested(depth: usize) -> Frame {
+        (0..depth).fold(Frame::Integer(0), |inner, i| {
+            Frame::Array(vec![Frame::Integer(i as i64), inner, Frame::Null])
+        })
+    }
+
+    #[tokio::test]
+    async fn round_trip_every_variant() {
+        let (mut tx, mut rx) = connection_pair().await;
+
+        let frames = vec![
+            Frame::Simple("OK".to_string()),
+            Frame::Simple("".to_string()),
+            Frame::Error("ERR something went wrong".to_string()),
+            Frame::Integer(0),
+            Frame::Integer(42),
+            Frame::Integer(-2),
+            Frame::Integer(i64::MIN),
+            Frame::Integer(i64::MAX),
+            Frame::Bulk(Bytes::from("hello")),
+            Frame::Bulk(Bytes::new()),
+            Frame::Bulk(Bytes::from_static(b"line\r\nbreak\0")),
+            Frame::Null,
+            Frame::NullArray,
+            Frame::Array(vec![]),
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from("message")),
+                Frame::Null,
+                Frame::Bulk(Bytes::new()),
+                Frame::Integer(-1),
+                Frame::Array(vec![Frame::Simple("inner".to_string()), Frame::Array(vec![])]),
+            ]),
+            nested(64),
+        ];
+
+        for frame in &frames {
+            tx.write_frame(frame).await.unwrap();
+        }
+        for frame in &frames {
+            assert_eq!(Some(frame.clone()), rx.read_frame().await.unwrap());
+        }
+
+        drop(tx);
+        assert_eq!(None, rx.read_frame().await.unwrap());
+    }
+
+    fn resp3_frames() -> Vec<Frame> {
+        vec![
+            Frame::Map(vec![
+                (Frame::Bulk(Bytes::from("proto")), Frame::Integer(3)),
+                (Frame::Simple("nested".to_string()), Frame::Map(vec![])),
+            ]),
+            Frame::Set(vec![Frame::Bulk(Bytes::from("a")), Frame::Bulk(Bytes::from("b"))]),
+            Frame::Double(1.5),
+            Frame::Double(f64::INFINITY),
+            Frame::Double(f64::NEG_INFINITY),
+            Frame::Boolean(true),
+            Frame::Boolean(false),
+            Frame::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            Frame::Verbatim {
+                format: "txt".to_string(),
+                data: Bytes::from("Some string"),
+            },
+            Frame::Push(vec![
+                Frame::Bulk(Bytes::from("message")),
+                Frame::Bulk(Bytes::from("channel")),
+                Frame::Null,
+            ]),
+        ]
+    }
+
+    #[tokio::test]
+    async fn round_trip_resp3() {
+        let (mut tx, mut rx) = connection_pair().await;
+        tx.set_protocol(Protocol::Resp3);
+
+        for frame in &resp3_frames() {
+            tx.write_frame(frame).await.unwrap();
+        }
+        for frame in &resp3_frames() {
+            assert_eq!(Some(frame.clone()), rx.read_frame().await.unwrap());
+        }
+
+        tx.write_frame(&Frame::Double(f64::NAN)).await.unwrap();
+        match rx.read_frame().await.unwrap() {
+            Some(Frame::Double(val)) => assert!(val.is_nan()),
+            frame => panic!("unexpected {:?}", frame),
+        }
+
+        // RESP3 has a single null, whatever the type of the reply.
+        tx.write_frame(&Frame::NullArray).await.unwrap();
+        assert_eq!(Some(Frame::Null), rx.read_frame().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn resp2_downgrades_resp3_frames() {
+        let (mut tx, mut rx) = connection_pair().await;
+
+        for frame in &resp3_frames() {
+            tx.write_frame(frame).await.unwrap();
+        }
+
+        let bulk = |s: &'static str| Frame::Bulk(Bytes::from(s));
+        let expected = vec![
+            Frame::Array(vec![
+                bulk("proto"),
+                Frame::Integer(3),
+                Frame::Simple("nested".to_string()),
+                Frame::Array(vec![]),
+            ]),
+            Frame::Array(vec![bulk("a"), bulk("b")]),
+            bulk("1.5"),
+            bulk("inf"),
+            bulk("-inf"),
+            Frame::Integer(1),
+            Frame::Integer(0),
+            bulk("3492890328409238509324850943850943825024385"),
+            bulk("Some string"),
+            Frame::Array(vec![bulk("message"), bulk("channel"), Frame::Null]),
+        ];
+        for frame in expected {
+            assert_eq!(Some(frame), rx.read_frame().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_frame_waits_for_complete_frame() {
+        let (_tx, mut rx) = connection_pair().await;
+
+        let encoded = b"*2\r\n$3\r\nfoo\r\n*1\r\n:-7\r\n";
+        for (i, byte) in encoded.iter().enumerate() {
+            rx.buffer.extend_from_slice(&[*byte]);
+            let parsed = rx.parse_frame().unwrap();
+            if i + 1 < encoded.len() {
+                assert_eq!(None, parsed);
+            } else {
+                let expected = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("foo")),
+                    Frame::Array(vec![Frame::Integer(-7)]),
+                ]);
+                assert_eq!(Some(expected), parsed);
+            }
+        }
+        assert!(rx.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_frame_caps_the_buffer() {
+        let (mut tx, mut rx) = connection_pair().await;
+        rx.set_max_buffer(1024);
+
+        tx.write_frame(&Frame::Bulk(Bytes::from(vec![b'x'; 512]))).await.unwrap();
+        assert!(matches!(rx.read_frame().await, Ok(Some(Frame::Bulk(_)))));
+
+        tx.write_frame(&Frame::Bulk(Bytes::from(vec![b'x'; 4096]))).await.unwrap();
+        assert!(matches!(rx.read_frame().await, Err(Error::RequestTooLarge)));
+        assert!(rx.buffer.capacity() < 1024);
+    }
+
+    #[tokio::test]
+    async fn parse_frame_rejects_invalid_lengths() {
+        let (_tx, mut rx) = connection_pair().await;
+        rx.set_max_buffer(usize::MAX);
+
+        for encoded in [&b"$18446744073709551615\r\n"[..], b"%9223372036854775808\r\n"] {
+            rx.buffer.extend_from_slice(encoded);
+            assert!(matches!(rx.parse_frame(), Err(Error::Protocol(_))));
+            rx.buffer.clear();
+        }
+
+        // Refused as soon as the length is known, before the string is buffered.
+        rx.set_max_buffer(1024);
+        rx.buffer.extend_from_slice(b"*2\r\n$3\r\nset\r\n$4096\r\n");
+        assert!(matches!(rx.parse_frame(), Err(Error::RequestTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn read_frame_refuses_deep_nesting() {
+        let (_tx, mut rx) = connection_pair().await;
+
+        // Deep enough to overflow the stack if every level took a call.
+        rx.buffer.extend_from_slice(&b"*1\r\n".repeat(2_000_000));
+        assert!(matches!(rx.read_frame().await, Err(Error::RequestTooDeep)));
+        assert!(rx.buffer.is_empty());
+
+        // Just as deep as allowed.
+        let mut encoded = b"*1\r\n".repeat(frame::MAX_DEPTH);
+        encoded.extend_from_slice(b":1\r\n");
+        rx.buffer.extend_from_slice(&encoded);
+        assert!(matches!(rx.parse_frame(), Ok(Some(Frame::Array(_)))));
+
+        rx.set_max_depth(2);
+        rx.buffer.extend_from_slice(b"*1\r\n*1\r\n:1\r\n*1\r\n*1\r\n*1\r\n:1\r\n");
+        assert!(matches!(rx.parse_frame(), Ok(Some(Frame::Array(_)))));
+        assert!(matches!(rx.read_frame().await, Err(Error::RequestTooDeep)));
+    }
+
+    #[tokio::test]
+    async fn parse_frame_tells_whether_a_frame_follows() {
+        let (_tx, mut rx) = connection_pair().await;
+
+        rx.buffer.extend_from_slice(b":1\r\n:2\r\n:3");
+        assert!(!rx.has_buffered_frame());
+        assert_eq!(Some(Frame::Integer(1)), rx.parse_frame().unwrap());
+        assert!(rx.has_buffered_frame());
+        assert_eq!(Some(Frame::Integer(2)), rx.parse_frame().unwrap());
+        assert!(!rx.has_buffered_frame());
+
+        rx.buffer.extend_from_slice(b"\r\n:x\r\n");
+        assert_eq!(Some(Frame::Integer(3)), rx.parse_frame().unwrap());
+        assert!(rx.has_buffered_frame());
+        assert!(matches!(rx.parse_frame(), Err(Error::Protocol(_))));
+        assert!(!rx.has_buffered_frame());
+    }
+}
@@ -0,0 +1,1195 @@
+//! The sharded key-value store behind the server.
+//!
+//! Every shard keeps its entries together with their expiration deadline. Expired
+//! keys are dropped lazily when they are read, and eagerly by the task started
+//! with [`spawn_purge_task`]. Shards also keep the versions of the keys watched
+//! by `WATCH` and the queues of the clients blocked on lists by `BLPOP`.
+//!
+//! Shards estimate the memory their entries use and track how they are accessed,
+//! for the eviction of keys under `maxmemory`, see the [`eviction`](super::eviction)
+//! module.
+//!
+//! # Locking
+//!
+//! Commands lock the shards of their keys with [`lock_shards`], and `EXEC` the
+//! shards of the keys of every queued command at once. [`lock_shards`] always
+//! locks them in ascending index order. A thread holding shard `i` therefore
+//! only ever waits for shards above `i`, which rules out deadlocks between
+//! concurrent multi-key commands and transactions whatever their keys.
+
+use super::error::{Error, Result};
+use super::eviction::{approx_size, Access, EvictionPolicy, MemoryLimit, Rng, EVICTION_SAMPLES};
+use super::zset::SortedSet;
+use bytes::Bytes;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Notify, task::JoinHandle};
+
+pub type ShardedDb = Arc<Vec<Db>>;
+pub type Db = Mutex<Shard>;
+
+/// The value of a key, one variant per data type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    /// The name of the type, as `TYPE` reports it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    /// Whether the value is an empty aggregate, which Redis never keeps around.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(zset) => zset.is_empty(),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Bytes> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Bytes> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Bytes>> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&HashMap<Bytes, Bytes>> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut HashMap<Bytes, Bytes>> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&HashSet<Bytes>> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut HashSet<Bytes>> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_zset(&self) -> Result<&SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Result<&mut SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+}
+
+/// A value stored in a shard.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: Value,
+
+    /// The instant at which the entry expires, `None` if it lives forever.
+    pub expires_at: Option<Instant>,
+
+    access: Access,
+    /// The estimated bytes used by the entry, see [`approx_size`].
+    size: usize,
+    /// The index of the key in [`Shard::slots`].
+    slot: usize,
+    /// The index of the key in [`Shard::volatile`], if it has an expiration.
+    volatile: Option<usize>,
+}
+
+impl Entry {
+    pub fn new(value: Value, expires_at: Option<Instant>) -> Entry {
+        Entry {
+            value,
+            expires_at,
+            access: Access::new(Instant::now()),
+            size: 0,
+            slot: 0,
+            volatile: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(when) if when <= now)
+    }
+
+    /// Return how good a victim for eviction under `policy` the entry is, the
+    /// higher the better, `None` if the policy never evicts it.
+    fn eviction_score(&self, policy: EvictionPolicy, now: Instant) -> Option<u128> {
+        // Expired entries are as good as gone already.
+        if self.is_expired(now) {
+            return Some(u128::MAX);
+        }
+        match policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => Some(self.access.idle(now).as_nanos()),
+            EvictionPolicy::AllKeysLfu => Some((u8::MAX - self.access.frequency(now)) as u128),
+            EvictionPolicy::VolatileTtl => self
+                .expires_at
+                .map(|when| u128::MAX - 1 - (when - now).as_nanos()),
+            EvictionPolicy::AllKeysRandom => Some(0),
+        }
+    }
+}
+
+/// A key watched by `WATCH`, see [`Shard::watch`].
+#[derive(Debug)]
+struct Watched {
+    /// How many connections watch the key.
+    watchers: usize,
+    /// Bumped on every modification of the key.
+    version: u64,
+}
+
+/// A client blocked on a key by `BLPOP` or `BRPOP`, see [`Shard::block`].
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    notify: Arc<Notify>,
+}
+
+/// One shard of a [`ShardedDb`].
+#[derive(Debug, Default)]
+pub struct Shard {
+    entries: HashMap<String, Entry>,
+    /// Versions of the watched keys, only tracked while some connection watches them.
+    watched: HashMap<String, Watched>,
+    /// The clients blocked on each key, longest waiting first.
+    blocked: HashMap<String, VecDeque<Waiter>>,
+    /// Every key, in no particular order, to sample victims for eviction from.
+    slots: Vec<String>,
+    /// The keys with an expiration, in no particular order, to sample victims
+    /// for eviction under `volatile-ttl` from.
+    volatile: Vec<String>,
+    /// Every key along with its [scan position](scan_position), in the order
+    /// [`Shard::scan`] walks them in.
+    positions: BTreeSet<(u64, String)>,
+    /// The estimated bytes used by the entries.
+    used: usize,
+    /// The estimated bytes used by the entries of every shard of the
+    /// [`ShardedDb`], shared between them.
+    total: Arc<AtomicUsize>,
+    /// Keys whose value was handed out for modification, to measure again.
+    stale: Vec<String>,
+    /// The `maxmemory` of the [`ShardedDb`], `None` without a limit.
+    limit: Option<MemoryLimit>,
+    rng: Rng,
+}
+
+impl Shard {
+    pub fn new() -> Shard {
+        Shard::default()
+    }
+
+    /// Return the entry of `key`, removing it first if it has expired.
+    ///
+    /// The access counts as a use for eviction.
+    fn live_entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = Instant::now();
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove_entry(key);
+            self.touch(key);
+            return None;
+        }
+        let random = self.rng.next_f64();
+        let entry = self.entries.get_mut(key)?;
+        entry.access.record(now, random);
+        Some(entry)
+    }
+
+    /// Insert `entry` for `key`, replacing any previous one, and account for its size.
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        entry.size = approx_size(&key, &entry.value);
+        let previous_size = match self.entries.get(&key) {
+            Some(previous) => {
+                entry.slot = previous.slot;
+                entry.volatile = previous.volatile;
+                previous.size
+            }
+            None => {
+                entry.slot = self.slots.len();
+                self.slots.push(key.clone());
+                self.positions.insert((scan_position(&key), key.clone()));
+                0
+            }
+        };
+        self.resize(previous_size, entry.size);
+        self.entries.insert(key.clone(), entry);
+        self.index_expiration(&key);
+    }
+
+    /// Remove the entry of `key`, whether it has expired or not.
+    fn remove_entry(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.resize(entry.size, 0);
+        self.positions.remove(&(scan_position(key), key.to_string()));
+        self.slots.swap_remove(entry.slot);
+        if let Some(moved) = self.slots.get(entry.slot) {
+            self.entries.get_mut(moved).expect("slot of a missing key").slot = entry.slot;
+        }
+        if let Some(index) = entry.volatile {
+            self.remove_volatile(index);
+        }
+        Some(entry)
+    }
+
+    /// Add `key` to [`Shard::volatile`] if it has an expiration, or remove it
+    /// from there if it has none.
+    fn index_expiration(&mut self, key: &str) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        match (entry.expires_at, entry.volatile) {
+            (Some(_), None) => {
+                entry.volatile = Some(self.volatile.len());
+                self.volatile.push(key.to_string());
+            }
+            (None, Some(index)) => {
+                entry.volatile = None;
+                self.remove_volatile(index);
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove the key at `index` of [`Shard::volatile`].
+    fn remove_volatile(&mut self, index: usize) {
+        self.volatile.swap_remove(index);
+        if let Some(moved) = self.volatile.get(index) {
+            self.entries.get_mut(moved).expect("volatile missing key").volatile = Some(index);
+        }
+    }
+
+    /// Record a modification of `key`.
+    fn touch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    /// Return the string value of `key` if it exists and has not expired.
+    ///
+    /// # Error
+    ///
+    /// [`Error::WrongType`] if `key` holds another type.
+    pub fn get(&mut self, key: &str) -> Result<Option<Bytes>> {
+        match self.live_entry(key) {
+            Some(entry) => entry.value.as_string().map(|value| Some(value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Set `key` to the string `value`, replacing any previous value and expiration.
+    ///
+    /// The key expires after `expire` if given.
+    pub fn set(&mut self, key: String, value: Bytes, expire: Option<Duration>) {
+        let expires_at = expire.and_then(|expire| Instant::now().checked_add(expire));
+        self.touch(&key);
+        self.insert(key, Entry::new(Value::String(value), expires_at));
+    }
+
+    /// Return the value of `key` if it exists and has not expired.
+    pub fn value(&mut self, key: &str) -> Option<&Value> {
+        self.live_entry(key).map(|entry| &entry.value)
+    }
+
+    /// Return the value of `key` if it exists and has not expired, without
+    /// removing it if it has.
+    pub fn peek(&self, key: &str) -> Option<&Value> {
+        self.entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| &entry.value)
+    }
+
+    /// Return the value of `key` for modification, if it exists and has not expired.
+    ///
+    /// The key counts as modified for `WATCH`, whether it is actually modified or not.
+    pub fn value_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.live_entry(key)?;
+        self.touch(key);
+        self.stale.push(key.to_string());
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    /// Return the value of `key`, inserting the one returned by `default` if the
+    /// key does not exist.
+    ///
+    /// The key counts as modified for `WATCH`, as with [`value_mut`](Shard::value_mut).
+    pub fn value_or_insert_with(&mut self, key: &str, default: impl FnOnce() -> Value) -> &mut Value {
+        self.touch(key);
+        if self.live_entry(key).is_none() {
+            self.insert(key.to_string(), Entry::new(default(), None));
+        }
+        self.stale.push(key.to_string());
+        &mut self.entries.get_mut(key).unwrap().value
+    }
+
+    /// Remove `key` if it holds an empty aggregate, such as a list whose last
+    /// element was popped.
+    pub fn remove_if_empty(&mut self, key: &str) {
+        if matches!(self.entries.get(key), Some(entry) if entry.value.is_empty()) {
+            self.remove_entry(key);
+        }
+    }
+
+    /// Remove `key`, returning its entry if it was alive.
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.live_entry(key)?;
+        self.touch(key);
+        self.remove_entry(key)
+    }
+
+    /// Make `key` expire at `when`. A deadline that is already due deletes the key.
+    ///
+    /// Return whether the key exists.
+    pub fn expire_at(&mut self, key: &str, when: Instant) -> bool {
+        if when <= Instant::now() {
+            return self.remove(key).is_some();
+        }
+        let exists = match self.live_entry(key) {
+            Some(entry) => {
+                entry.expires_at = Some(when);
+                true
+            }
+            None => false,
+        };
+        if exists {
+            self.index_expiration(key);
+            self.touch(key);
+        }
+        exists
+    }
+
+    /// Remove the expiration of `key`.
+    ///
+    /// Return whether the key existed and had an expiration.
+    pub fn persist(&mut self, key: &str) -> bool {
+        let persisted = match self.live_entry(key) {
+            Some(entry) => entry.expires_at.take().is_some(),
+            None => false,
+        };
+        if persisted {
+            self.index_expiration(key);
+            self.touch(key);
+        }
+        persisted
+    }
+
+    /// Return the remaining time to live of `key`.
+    ///
+    /// `None` if the key does not exist, `Some(None)` if it exists without an expiration.
+    pub fn ttl(&mut self, key: &str) -> Option<Option<Duration>> {
+        let now = Instant::now();
+        self.live_entry(key)
+            .map(|entry| entry.expires_at.map(|when| when.saturating_duration_since(now)))
+    }
+
+    /// Insert an entry as it is, such as one loaded from disk.
+    pub fn insert_entry(&mut self, key: String, entry: Entry) {
+        self.touch(&key);
+        self.insert(key, entry);
+    }
+
+    /// Iterate over the entries which have not expired.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        let now = Instant::now();
+        self.entries.iter().filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    /// Return the live keys whose [scan position](scan_position) is at least
+    /// `position`, up to `count` of them in position order, with their values,
+    /// along with the position to resume from, `None` past the last key.
+    ///
+    /// Keys sharing a position are returned together, even past `count`.
+    pub fn scan(&self, position: u64, count: usize) -> (Vec<(&String, &Value)>, Option<u64>) {
+        let now = Instant::now();
+        let count = count.max(1);
+        let mut keys = vec![];
+        let mut last = None;
+        for (key_position, key) in self.positions.range((position, String::new())..) {
+            if keys.len() >= count && last != Some(*key_position) {
+                return (keys, Some(*key_position));
+            }
+            let entry = &self.entries[key];
+            if !entry.is_expired(now) {
+                keys.push((key, &entry.value));
+                last = Some(*key_position);
+            }
+        }
+        (keys, None)
+    }
+
+    /// Remove every expired entry, returning how many were removed.
+    ///
+    /// The purge task samples keys instead, see [`purge_sample`](Shard::purge_sample).
+    #[cfg(test)]
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_entry(key);
+            self.touch(key);
+        }
+        expired.len()
+    }
+
+    /// Remove the expired entries among [`PURGE_SAMPLES`] keys drawn at random,
+    /// returning how many were removed.
+    pub fn purge_sample(&mut self) -> usize {
+        let now = Instant::now();
+        let mut expired = 0;
+        for _ in 0..PURGE_SAMPLES {
+            if self.slots.is_empty() {
+                break;
+            }
+            let slot = (self.rng.next_u64() % self.slots.len() as u64) as usize;
+            if self.entries[&self.slots[slot]].is_expired(now) {
+                let key = self.slots[slot].clone();
+                self.remove_entry(&key);
+                self.touch(&key);
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    /// Start watching `key` for modifications, returning its current version.
+    ///
+    /// Every call must be matched by one to [`unwatch`](Shard::unwatch).
+    pub fn watch(&mut self, key: &str) -> u64 {
+        // An already expired key is removed now, rather than counting as modified later on.
+        self.live_entry(key);
+        let watched = self
+            .watched
+            .entry(key.to_string())
+            .or_insert(Watched { watchers: 0, version: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Return the version of the watched `key`, which changes whenever the key is
+    /// modified, removed or expires.
+    pub fn version(&mut self, key: &str) -> Option<u64> {
+        self.live_entry(key);
+        self.watched.get(key).map(|watched| watched.version)
+    }
+
+    /// Queue the client `id` up behind those already blocked on `key`, to be
+    /// woken through `notify` once the list of `key` holds an element for it.
+    ///
+    /// Every call must be matched by one to [`unblock`](Shard::unblock).
+    pub fn block(&mut self, key: &str, id: u64, notify: Arc<Notify>) {
+        self.blocked
+            .entry(key.to_string())
+            .or_default()
+            .push_back(Waiter { id, notify });
+    }
+
+    /// Remove the client `id` from the queue of `key`, waking those behind it
+    /// which the list now holds an element for.
+    pub fn unblock(&mut self, key: &str, id: u64) {
+        if let Some(waiters) = self.blocked.get_mut(key) {
+            waiters.retain(|waiter| waiter.id != id);
+            if waiters.is_empty() {
+                self.blocked.remove(key);
+            }
+        }
+        self.wake_blocked(key);
+    }
+
+    /// Return whether the list of `key` holds an element for the blocked client
+    /// `id`, that is one for every client queued before it and one for it.
+    ///
+    /// # Error
+    ///
+    /// [`Error::WrongType`] if `key` holds another type than a list.
+    pub fn first_in_line(&mut self, key: &str, id: u64) -> Result<bool> {
+        let len = match self.value(key) {
+            Some(value) => value.as_list()?.len(),
+            None => 0,
+        };
+        let position = self
+            .blocked
+            .get(key)
+            .and_then(|waiters| waiters.iter().position(|waiter| waiter.id == id));
+        Ok(matches!(position, Some(position) if position < len))
+    }
+
+    /// Return the number of clients blocked on `key`.
+    pub fn blocked_clients(&self, key: &str) -> usize {
+        self.blocked.get(key).map_or(0, VecDeque::len)
+    }
+
+    /// Wake the clients blocked on `key` which its list holds an element for,
+    /// longest waiting first.
+    pub fn wake_blocked(&mut self, key: &str) {
+        let len = match self.peek(key) {
+            Some(Value::List(list)) => list.len(),
+            _ => return,
+        };
+        if let Some(waiters) = self.blocked.get(key) {
+            for waiter in waiters.iter().take(len) {
+                waiter.notify.notify_one();
+            }
+        }
+    }
+
+    /// Set the `maxmemory` of the [`ShardedDb`], `None` lifting the limit.
+    pub fn set_limit(&mut self, limit: Option<MemoryLimit>) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> Option<MemoryLimit> {
+        self.limit
+    }
+
+    /// Return the estimated bytes used by the entries, see [`approx_size`].
+    pub fn used_memory(&mut self) -> usize {
+        self.account();
+        self.used
+    }
+
+    /// Return the estimated bytes used by the entries of every shard of the
+    /// [`ShardedDb`], as last measured by each of them.
+    pub fn total_memory(&mut self) -> usize {
+        self.account();
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Whether the entries of every shard use more than `maxmemory`.
+    pub fn is_over_limit(&mut self) -> bool {
+        let total = self.total_memory();
+        matches!(self.limit, Some(limit) if total > limit.max)
+    }
+
+    /// Return the best victim under `policy` among [`EVICTION_SAMPLES`] keys
+    /// drawn at random, along with its score, higher scores going first.
+    ///
+    /// `volatile-ttl` only draws keys with an expiration, `None` if there are none.
+    pub fn sample_victim(&mut self, policy: EvictionPolicy) -> Option<(u128, String)> {
+        self.account();
+        let candidates = match policy {
+            EvictionPolicy::NoEviction => return None,
+            EvictionPolicy::VolatileTtl => self.volatile.len(),
+            _ => self.slots.len(),
+        };
+        if candidates == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let mut best: Option<(u128, &String)> = None;
+        for _ in 0..EVICTION_SAMPLES {
+            let index = (self.rng.next_u64() % candidates as u64) as usize;
+            let key = match policy {
+                EvictionPolicy::VolatileTtl => &self.volatile[index],
+                _ => &self.slots[index],
+            };
+            if let Some(score) = self.entries[key].eviction_score(policy, now) {
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, key));
+                }
+            }
+        }
+        let (score, key) = best?;
+        let key = key.clone();
+        // Random victims are as good as each other, whichever shard they come from.
+        let score = match policy {
+            EvictionPolicy::AllKeysRandom if score == 0 => self.rng.next_u64() as u128,
+            _ => score,
+        };
+        Some((score, key))
+    }
+
+    /// Evict `key`, returning whether it was there.
+    pub fn evict(&mut self, key: &str) -> bool {
+        if self.remove_entry(key).is_none() {
+            return false;
+        }
+        self.touch(key);
+        true
+    }
+
+    /// Account for an entry of `previous_size` bytes now using `size` bytes.
+    fn resize(&mut self, previous_size: usize, size: usize) {
+        self.used = self.used + size - previous_size;
+        // Added first, so that the total never goes below zero meanwhile.
+        self.total.fetch_add(size, Ordering::Relaxed);
+        self.total.fetch_sub(previous_size, Ordering::Relaxed);
+    }
+
+    /// Measure again the entries handed out for modification since the last call.
+    fn account(&mut self) {
+        for key in std::mem::take(&mut self.stale) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                let (previous_size, size) = (entry.size, approx_size(&key, &entry.value));
+                entry.size = size;
+                self.resize(previous_size, size);
+            }
+        }
+    }
+
+    /// Return the number of entries, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+pub fn new_shared_db(num_shareds: usize) -> ShardedDb {
+    let total = Arc::new(AtomicUsize::new(0));
+    let mut db: Vec<Db> = Vec::with_capacity(num_shareds);
+    for index in 0..num_shareds {
+        db.push(Mutex::new(Shard {
+            total: total.clone(),
+            // Shards sampling the same keys as each other would evict from the first one.
+            rng: Rng::new((index as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+            ..Shard::new()
+        }));
+    }
+    Arc::new(db)
+}
+
+/// Return the index of the shard holding `key`.
+pub fn shard_index(shared_db: &ShardedDb, key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() as usize) % shared_db.len()
+}
+
+/// The low bits of a [`scan`] cursor, holding a position within a shard, the
+/// high bits holding the index of the shard.
+pub const SCAN_POSITION_BITS: u32 = 48;
+
+const MAX_SCAN_POSITION: u64 = (1 << SCAN_POSITION_BITS) - 1;
+
+/// The most shards whose index fits in a [`scan`] cursor.
+pub const MAX_SHARDS: usize = 1 << (64 - SCAN_POSITION_BITS);
+
+/// Return the position of `key` in the order [`scan`] walks a shard in.
+///
+/// The position only depends on the key, so it doesn't move as other keys come
+/// and go, unlike the iteration order of a `HashMap` which changes when it grows.
+fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (SCAN_POSITION_BITS, key).hash(&mut hasher);
+    hasher.finish() >> (64 - SCAN_POSITION_BITS)
+}
+
+/// Visit about `count` keys of `shared_db` from `cursor`, locking one shard at a
+/// time, and return the cursor to resume from, 0 once the last shard was
+/// walked, along with the keys visited for which `filter` holds.
+///
+/// Shards are walked in index order and their keys by [scan position](scan_position),
+/// so a key present for the whole scan is returned at least once.
+pub fn scan<F>(shared_db: &ShardedDb, cursor: u64, count: usize, mut filter: F) -> (u64, Vec<String>)
+where
+    F: FnMut(&str, &Value) -> bool,
+{
+    let mut index = (cursor >> SCAN_POSITION_BITS) as usize;
+    let mut position = cursor & MAX_SCAN_POSITION;
+    let mut keys = vec![];
+    let mut visited = 0;
+
+    while index < shared_db.len() && visited < count {
+        let shard = shared_db[index].lock().unwrap();
+        let (visiting, next) = shard.scan(position, count - visited);
+        visited += visiting.len();
+        keys.extend(
+            visiting
+                .into_iter()
+                .filter(|(key, value)| filter(key, value))
+                .map(|(key, _)| key.clone()),
+        );
+        match next {
+            Some(next) => position = next,
+            None => {
+                index += 1;
+                position = 0;
+            }
+        }
+    }
+
+    if index < shared_db.len() {
+        (((index as u64) << SCAN_POSITION_BITS) | position, keys)
+    } else {
+        (0, keys)
+    }
+}
+
+pub fn get_db<'a>(shared_db: &'a ShardedDb, key: &str) -> &'a Db {
+    &shared_db[shard_index(shared_db, key)]
+}
+
+/// The shards of several keys, locked together by [`lock_shards`].
+pub struct LockedShards<'a> {
+    shared_db: &'a ShardedDb,
+    /// Sorted by shard index.
+    guards: Vec<(usize, MutexGuard<'a, Shard>)>,
+}
+
+impl<'a> LockedShards<'a> {
+    fn position(&self, key: &str) -> usize {
+        let index = shard_index(self.shared_db, key);
+        self.guards
+            .binary_search_by_key(&index, |(index, _)| *index)
+            .expect("the shard of the key is not locked")
+    }
+
+    /// Return the shard of `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was not among the keys the shards were locked for.
+    pub fn shard(&self, key: &str) -> &Shard {
+        &self.guards[self.position(key)].1
+    }
+
+    /// Return the shard of `key` for modification, see [`shard`](LockedShards::shard).
+    pub fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let position = self.position(key);
+        &mut self.guards[position].1
+    }
+
+    /// Iterate over the locked shards for modification, in ascending index order.
+    pub fn shards_mut(&mut self) -> impl Iterator<Item = &mut Shard> + use<'_, 'a> {
+        self.guards.iter_mut().map(|(_, shard)| &mut **shard)
+    }
+}
+
+impl Drop for LockedShards<'_> {
+    /// Account for the values the commands modified, before the shards are unlocked.
+    fn drop(&mut self) {
+        for shard in self.shards_mut() {
+            shard.account();
+        }
+    }
+}
+
+/// Lock the shards of every key in `keys`, in ascending index order.
+///
+/// Each shard is locked once, however many of the keys it holds.
+pub fn lock_shards<'a, K: AsRef<str>>(shared_db: &'a ShardedDb, keys: &[K]) -> LockedShards<'a> {
+    let mut indexes: Vec<usize> = keys
+        .iter()
+        .map(|key| shard_index(shared_db, key.as_ref()))
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+
+    let guards = indexes
+        .into_iter()
+        .map(|index| (index, shared_db[index].lock().unwrap()))
+        .collect();
+    LockedShards { shared_db, guards }
+}
+
+/// Return the unix timestamp in milliseconds of `when`.
+pub fn to_unix_millis(when: Instant) -> i64 {
+    let now = Instant::now();
+    let wall = if when >= now {
+        SystemTime::now() + (when - now)
+    } else {
+        SystemTime::now() - (now - when)
+    };
+    wall.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Return the instant matching a unix timestamp in milliseconds, `None` if it is already past.
+pub fn from_unix_millis(millis: i64) -> Option<Instant> {
+    let wall = UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64);
+    let remaining = wall.duration_since(SystemTime::now()).ok()?;
+    Instant::now().checked_add(remaining)
+}
+
+/// The number of keys [`Shard::purge_sample`] draws.
+pub const PURGE_SAMPLES: usize = 20;
+
+/// The most samples the purge task draws from a shard on every tick.
+const PURGE_ROUNDS: usize = 16;
+
+/// Spawn a task purging the expired keys of `shared_db` every `period`.
+///
+/// Like Redis, the task samples keys rather than going through all of them, and
+/// samples a shard again while more than a quarter of the sampled keys had expired,
+/// up to [`PURGE_ROUNDS`] times. Shards are locked for one sample at a time.
+///
+/// The task only holds a weak reference to the database and stops once every
+/// other handle to it has been dropped.
+pub fn spawn_purge_task(shared_db: &ShardedDb, period: Duration) -> JoinHandle<()> {
+    let weak_db = Arc::downgrade(shared_db);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+
+            let shared_db = match weak_db.upgrade() {
+                Some(shared_db) => shared_db,
+                None => break,
+            };
+            for db in shared_db.iter() {
+                for _ in 0..PURGE_ROUNDS {
+                    if db.lock().unwrap().purge_sample() * 4 <= PURGE_SAMPLES {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shard_lazy_expiration() {
+        let mut shard = Shard::new();
+        shard.set("a".to_string(), Bytes::from("1"), Some(Duration::from_millis(10)));
+        shard.set("b".to_string(), Bytes::from("2"), None);
+
+        assert_eq!(Some(Bytes::from("1")), shard.get("a").unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, shard.get("a").unwrap());
+        assert_eq!(Some(Bytes::from("2")), shard.get("b").unwrap());
+        assert_eq!(1, shard.len());
+    }
+
+    #[test]
+    fn shard_ttl_and_persist() {
+        let mut shard = Shard::new();
+        shard.set("a".to_string(), Bytes::from("1"), None);
+
+        assert_eq!(None, shard.ttl("missing"));
+        assert_eq!(Some(None), shard.ttl("a"));
+        assert!(!shard.persist("a"));
+
+        assert!(shard.expire_at("a", Instant::now() + Duration::from_secs(100)));
+        let ttl = shard.ttl("a").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(99) && ttl <= Duration::from_secs(100));
+
+        assert!(shard.persist("a"));
+        assert_eq!(Some(None), shard.ttl("a"));
+
+        assert!(shard.expire_at("a", Instant::now()));
+        assert_eq!(None, shard.get("a").unwrap());
+        assert!(!shard.expire_at("a", Instant::now() + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn shard_purge_expired() {
+        let mut shard = Shard::new();
+        for i in 0..10 {
+            let expire = if i % 2 == 0 { Some(Duration::from_millis(1)) } else { None };
+            shard.set(i.to_string(), Bytes::from("v"), expire);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(5, shard.purge_expired());
+        assert_eq!(5, shard.len());
+    }
+
+    #[test]
+    fn shard_purge_sample() {
+        let mut shard = Shard::new();
+        for i in 0..100 {
+            let expire = if i % 2 == 0 { Some(Duration::from_millis(1)) } else { None };
+            shard.set(i.to_string(), Bytes::from("v"), expire);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+
+        let purged = shard.purge_sample();
+        assert!(purged > 0 && purged <= PURGE_SAMPLES);
+        assert_eq!(100 - purged, shard.len());
+        while shard.purge_sample() > 0 {}
+        assert!(shard.len() >= 50);
+
+        for i in 0..100 {
+            shard.set(i.to_string(), Bytes::from("v"), Some(Duration::from_millis(1)));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        while shard.purge_sample() > 0 {}
+        assert!(shard.is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_task_samples_until_few_keys_expired() {
+        let shared_db = new_shared_db(1);
+        for i in 0..1000 {
+            let key = i.to_string();
+            let expire = if i < 100 { None } else { Some(Duration::from_millis(1)) };
+            get_db(&shared_db, &key).lock().unwrap().set(key, Bytes::from("v"), expire);
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let task = spawn_purge_task(&shared_db, Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Once at most a quarter of the keys have expired, the task may leave them.
+        let len = shared_db[0].lock().unwrap().len();
+        assert!((100..=200).contains(&len), "{} keys left", len);
+
+        drop(shared_db);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn purge_task_stops_with_db() {
+        let shared_db = new_shared_db(4);
+        get_db(&shared_db, "a")
+            .lock()
+            .unwrap()
+            .set("a".to_string(), Bytes::from("1"), Some(Duration::from_millis(1)));
+
+        let task = spawn_purge_task(&shared_db, Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(shared_db.iter().all(|db| db.lock().unwrap().is_empty()));
+
+        drop(shared_db);
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn shard_typed_values() {
+        let mut shard = Shard::new();
+        shard.set("s".to_string(), Bytes::from("v"), None);
+
+        let list = shard
+            .value_or_insert_with("l", || Value::List(VecDeque::new()))
+            .as_list_mut()
+            .unwrap();
+        list.push_back(Bytes::from("a"));
+        assert_eq!(Some("list"), shard.value("l").map(Value::type_name));
+
+        assert!(matches!(shard.get("l"), Err(Error::WrongType)));
+        assert!(matches!(
+            shard.value_or_insert_with("s", || Value::List(VecDeque::new())).as_list_mut(),
+            Err(Error::WrongType)
+        ));
+
+        shard.value_mut("l").unwrap().as_list_mut().unwrap().pop_front();
+        shard.remove_if_empty("l");
+        shard.remove_if_empty("s");
+        assert!(shard.value("l").is_none());
+        assert_eq!(Some(Bytes::from("v")), shard.get("s").unwrap());
+    }
+
+    #[test]
+    fn shard_watched_versions() {
+        let mut shard = Shard::new();
+        shard.set("a".to_string(), Bytes::from("1"), Some(Duration::from_millis(10)));
+        assert_eq!(None, shard.version("a"));
+
+        let version = shard.watch("a");
+        assert_eq!(version, shard.watch("a"));
+        shard.get("a").unwrap();
+        shard.persist("a");
+        assert!(shard.version("a") > Some(version));
+
+        // Expiring counts as a modification, reading doesn't.
+        let version = shard.version("a");
+        shard.expire_at("a", Instant::now());
+        assert!(shard.version("a") > version);
+        let version = shard.version("a");
+        shard.get("a").unwrap();
+        assert_eq!(version, shard.version("a"));
+
+        shard.set("a".to_string(), Bytes::from("2"), None);
+        assert!(shard.version("a") > version);
+
+        // The version is dropped with the last watcher.
+        shard.unwatch("a");
+        assert!(shard.version("a").is_some());
+        shard.unwatch("a");
+        assert_eq!(None, shard.version("a"));
+    }
+
+    #[test]
+    fn shard_memory_accounting() {
+        let mut shard = Shard::new();
+        shard.set("s".to_string(), Bytes::from("value"), None);
+        assert_eq!(approx_size("s", &Value::String(Bytes::from("value"))), shard.used_memory());
+
+        // Values modified in place are measured again.
+        let list = shard
+            .value_or_insert_with("l", || Value::List(VecDeque::new()))
+            .as_list_mut()
+            .unwrap();
+        list.extend((0..100).map(|i| Bytes::from(format!("element {}", i))));
+        let list_size = approx_size("l", shard.peek("l").unwrap());
+        assert!(list_size > 100 * "element 0".len());
+        assert_eq!(approx_size("s", &Value::String(Bytes::from("value"))) + list_size, shard.used_memory());
+
+        // The slots of the keys follow them as others are removed.
+        for i in 0..50 {
+            shard.set(format!("k{}", i), Bytes::from("v"), Some(Duration::from_secs(60)));
+        }
+        for i in (0..50).step_by(3) {
+            shard.remove(&format!("k{}", i));
+        }
+        shard.expire_at("s", Instant::now());
+        shard.purge_expired();
+        assert_eq!(shard.entries.len(), shard.slots.len());
+        assert_eq!(shard.entries.len(), shard.positions.len());
+        for (slot, key) in shard.slots.iter().enumerate() {
+            assert_eq!(slot, shard.entries[key].slot);
+        }
+        // As do the indexes of those with an expiration.
+        shard.persist("k1");
+        shard.set("k2".to_string(), Bytes::from("v"), None);
+        shard.expire_at("l", Instant::now() + Duration::from_secs(60));
+        let volatile = shard.entries.values().filter(|entry| entry.expires_at.is_some()).count();
+        assert_eq!(volatile, shard.volatile.len());
+        for (index, key) in shard.volatile.iter().enumerate() {
+            assert_eq!(Some(index), shard.entries[key].volatile);
+        }
+
+        for key in shard.slots.clone() {
+            shard.remove(&key);
+        }
+        assert_eq!(0, shard.used_memory());
+        assert!(shard.slots.is_empty());
+        assert!(shard.positions.is_empty());
+        assert!(shard.volatile.is_empty());
+    }
+
+    /// Scan every key with `count` keys per call, calling `between` after each call.
+    fn scan_all(shared_db: &ShardedDb, count: usize, mut between: impl FnMut()) -> Vec<String> {
+        let mut keys = vec![];
+        let mut cursor = 0;
+        loop {
+            let (next, visited) = scan(shared_db, cursor, count, |_, _| true);
+            keys.extend(visited);
+            between();
+            cursor = next;
+            if cursor == 0 {
+                return keys;
+            }
+        }
+    }
+
+    #[test]
+    fn scan_returns_stable_keys() {
+        let shared_db = new_shared_db(4);
+        let set = |key: String| get_db(&shared_db, &key).lock().unwrap().set(key, Bytes::from("v"), None);
+        for i in 0..1000 {
+            set(format!("stable:{}", i));
+            set(format!("gone:{}", i));
+        }
+
+        // Without changes, every key is returned exactly once.
+        let keys = scan_all(&shared_db, 7, || {});
+        assert_eq!(2000, keys.len());
+        assert_eq!(2000, keys.iter().collect::<HashSet<_>>().len());
+
+        // Keys come and go between calls, growing and shrinking the shards.
+        let mut round = 0;
+        let keys: HashSet<String> = scan_all(&shared_db, 25, || {
+            for i in 0..20 {
+                let gone = format!("gone:{}", round * 20 + i);
+                get_db(&shared_db, &gone).lock().unwrap().remove(&gone);
+                set(format!("new:{}:{}", round, i));
+            }
+            round += 1;
+        })
+        .into_iter()
+        .collect();
+        assert!((0..1000).all(|i| keys.contains(&format!("stable:{}", i))));
+    }
+
+    #[test]
+    fn lock_shards_once_each() {
+        let shared_db = new_shared_db(4);
+        let keys: Vec<String> = (0..32).map(|i| format!("key:{}", i)).collect();
+
+        let mut shards = lock_shards(&shared_db, &keys);
+        assert!(shards.guards.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(4, shards.guards.len());
+
+        shards.shard_mut("key:1").set("key:1".to_string(), Bytes::from("v"), None);
+        assert!(shards.shard("key:1").peek("key:1").is_some());
+        drop(shards);
+
+        let mut db = get_db(&shared_db, "key:1").lock().unwrap();
+        assert_eq!(Some(Bytes::from("v")), db.get("key:1").unwrap());
+    }
+}
@@ -0,0 +1,139 @@
+//! Errors raised while serving a connection, and the replies they turn into.
+
+use super::frame::{self, Frame};
+use super::parse::ParseError;
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The socket failed, the connection can't be used anymore.
+    Io(io::Error),
+    /// The peer sent something which is not a valid request.
+    Protocol(String),
+    /// The peer sent a frame larger than the connection buffers.
+    RequestTooLarge,
+    /// The peer sent a frame nesting aggregates deeper than the connection allows.
+    RequestTooDeep,
+    /// The server already serves as many connections as it is allowed to.
+    MaxClients,
+    UnknownCommand(String),
+    /// The named command was given too few or too many arguments.
+    WrongArity(String),
+    /// The key holds a value of another type than the command works on.
+    WrongType,
+    NotInteger,
+    /// A score or increment which is not a number.
+    NotFloat,
+    /// A bound of a score range which is not a number.
+    MinMaxNotFloat,
+    /// `HINCRBY` on a field which doesn't hold an integer.
+    HashNotInteger,
+    /// An increment would overflow a 64 bits integer.
+    Overflow,
+    /// `INCRBYFLOAT` would store a NaN or an infinity.
+    NanOrInfinity,
+    /// A count was negative.
+    NotPositive,
+    /// The timeout of `BLPOP` or `BRPOP` is not a number.
+    TimeoutNotFloat,
+    NegativeTimeout,
+    /// The cursor given to `SCAN` is not an unsigned integer.
+    InvalidCursor,
+    /// A write was refused because the dataset uses more than `maxmemory`.
+    OutOfMemory,
+    Syntax,
+    /// `HELLO` asked for a protocol version the server doesn't speak.
+    NoProto,
+    /// The named command was given an out of range expiration.
+    InvalidExpire(String),
+    NestedMulti,
+    /// `EXEC` or `DISCARD` outside of a transaction.
+    WithoutMulti(String),
+    /// A command which can't be queued by `MULTI`.
+    ForbiddenInMulti,
+    /// `EXEC` of a transaction in which a command failed to queue.
+    ExecAbort,
+}
+
+impl Error {
+    /// Map the error `parse` returned while parsing the arguments of `command`.
+    pub fn from_parse(command: &str, err: ParseError) -> Error {
+        match err {
+            ParseError::EndOfStream | ParseError::Trailing => Error::WrongArity(command.to_string()),
+            ParseError::NotInteger => Error::NotInteger,
+            ParseError::Other(err) => Error::Protocol(err.to_string()),
+        }
+    }
+
+    /// Whether the connection must be closed, after replying with the error
+    /// unless it is an [`Error::Io`]. Every other error is answered and the
+    /// connection kept open.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::Io(_) | Error::RequestTooLarge | Error::RequestTooDeep | Error::MaxClients)
+    }
+}
+
+impl fmt::Display for Error {
+    /// Write the message of the error reply, in Redis wording.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "ERR I/O error: {}", err),
+            Error::Protocol(reason) => write!(f, "ERR Protocol error: {}", reason),
+            Error::RequestTooLarge => write!(f, "ERR Protocol error: request too large"),
+            Error::RequestTooDeep => write!(f, "ERR Protocol error: too many nested aggregates"),
+            Error::MaxClients => write!(f, "ERR max number of clients reached"),
+            Error::UnknownCommand(name) => write!(f, "ERR unknown command '{}'", name),
+            Error::WrongArity(name) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", name)
+            }
+            Error::WrongType => {
+                write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value")
+            }
+            Error::NotInteger => write!(f, "ERR value is not an integer or out of range"),
+            Error::NotFloat => write!(f, "ERR value is not a valid float"),
+            Error::MinMaxNotFloat => write!(f, "ERR min or max is not a float"),
+            Error::HashNotInteger => write!(f, "ERR hash value is not an integer"),
+            Error::Overflow => write!(f, "ERR increment or decrement would overflow"),
+            Error::NanOrInfinity => write!(f, "ERR increment would produce NaN or Infinity"),
+            Error::NotPositive => write!(f, "ERR value is out of range, must be positive"),
+            Error::TimeoutNotFloat => write!(f, "ERR timeout is not a float or out of range"),
+            Error::NegativeTimeout => write!(f, "ERR timeout is negative"),
+            Error::InvalidCursor => write!(f, "ERR invalid cursor"),
+            Error::OutOfMemory => write!(f, "OOM command not allowed when used memory > 'maxmemory'."),
+            Error::Syntax => write!(f, "ERR syntax error"),
+            Error::NoProto => write!(f, "NOPROTO unsupported protocol version"),
+            Error::InvalidExpire(name) => write!(f, "ERR invalid expire time in '{}' command", name),
+            Error::NestedMulti => write!(f, "ERR MULTI calls can not be nested"),
+            Error::WithoutMulti(name) => write!(f, "ERR {} without MULTI", name),
+            Error::ForbiddenInMulti => write!(f, "ERR Command not allowed inside a transaction"),
+            Error::ExecAbort => write!(f, "EXECABORT Transaction discarded because of previous errors."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Frame {
+    fn from(err: Error) -> Frame {
+        Frame::Error(err.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<frame::Error> for Error {
+    fn from(err: frame::Error) -> Self {
+        match err {
+            frame::Error::Incomplete => Error::Protocol("incomplete frame".to_string()),
+            frame::Error::TooLarge => Error::RequestTooLarge,
+            frame::Error::TooDeep => Error::RequestTooDeep,
+            frame::Error::Other(err) => Error::Protocol(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Eviction of keys once the dataset outgrows `maxmemory`.
+//!
+//! Every shard keeps an estimate of the memory its entries use, see
+//! [`approx_size`], and adds it to a total shared by the shards, which the limit
+//! set by [`set_max_memory`] applies to. Before a command which may use more
+//! memory, [`make_room`] evicts keys from any shard until the total fits again,
+//! or fails with an OOM error under [`EvictionPolicy::NoEviction`]. As in Redis,
+//! each victim is the best of a few keys sampled at random from every shard
+//! rather than the best of all keys.
+//!
+//! Evictions are appended to the log as expirations in the past, which delete
+//! the keys when the log is replayed.
+
+use super::aof::SharedAof;
+use super::db::{lock_shards, ShardedDb, Value};
+use super::error::{Error, Result};
+use bytes::Bytes;
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// How keys are picked for eviction, with the names of the Redis `maxmemory-policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse writes which may use more memory instead.
+    #[default]
+    NoEviction,
+    /// The least recently used key.
+    AllKeysLru,
+    /// The least frequently used key.
+    AllKeysLfu,
+    /// The key closest to expiring, among those with an expiration.
+    VolatileTtl,
+    AllKeysRandom,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            _ => Err(format!(
+                "invalid eviction policy '{}', expected noeviction, allkeys-lru, allkeys-lfu, \
+                 volatile-ttl or allkeys-random",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The `maxmemory` of a [`ShardedDb`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLimit {
+    /// In bytes, as estimated by [`approx_size`].
+    pub max: usize,
+    pub policy: EvictionPolicy,
+}
+
+/// How many keys are sampled to pick each victim, `maxmemory-samples` in Redis.
+pub const EVICTION_SAMPLES: usize = 5;
+
+/// The bytes an entry uses besides its key and value, for the map slot, the
+/// expiration and the eviction bookkeeping.
+const ENTRY_OVERHEAD: usize = 96;
+
+/// The bytes an element of an aggregate uses besides its content.
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// How many elements of an aggregate are measured to estimate the size of all of them.
+const SIZE_SAMPLES: usize = 8;
+
+/// Estimate the bytes used by the entry of `key` holding `value`.
+///
+/// Aggregates are estimated from their first few elements, so the estimate
+/// takes constant time whatever their length.
+pub fn approx_size(key: &str, value: &Value) -> usize {
+    let content = match value {
+        Value::String(value) => value.len(),
+        Value::List(list) => estimate(list.len(), list.iter().map(|element| element.len() + ELEMENT_OVERHEAD)),
+        Value::Hash(hash) => estimate(
+            hash.len(),
+            hash.iter()
+                .map(|(field, value)| field.len() + value.len() + 2 * ELEMENT_OVERHEAD),
+        ),
+        Value::Set(set) => estimate(set.len(), set.iter().map(|member| member.len() + ELEMENT_OVERHEAD)),
+        // A member is both in the hash map and in a skip list node.
+        Value::SortedSet(zset) => {
+            estimate(zset.len(), zset.iter().map(|(member, _)| member.len() + 4 * ELEMENT_OVERHEAD))
+        }
+    };
+    ENTRY_OVERHEAD + key.len() + content
+}
+
+/// Estimate the total of `len` sizes from the first [`SIZE_SAMPLES`] of them.
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (count, total) = sizes
+        .take(SIZE_SAMPLES)
+        .fold((0, 0), |(count, total), size| (count + 1, total + size));
+    (total * len).checked_div(count).unwrap_or(0)
+}
+
+/// The value of the access counter of new keys, so that they aren't evicted
+/// before they get a chance to be used, `LFU_INIT_VAL` in Redis.
+const LFU_INIT: u8 = 5;
+
+/// How slowly the access counter grows, `lfu-log-factor` in Redis.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// How long it takes for the access counter to lose one, `lfu-decay-time` in Redis.
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+/// When a key was last used and how often, for the LRU and LFU policies.
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    last: Instant,
+    /// A logarithmic counter of the uses, as in Redis: a million uses bring it
+    /// to about 255, and it loses one per [`LFU_DECAY`] without use.
+    counter: u8,
+}
+
+impl Access {
+    pub fn new(now: Instant) -> Access {
+        Access {
+            last: now,
+            counter: LFU_INIT,
+        }
+    }
+
+    /// Record a use at `now`, `random` being drawn uniformly from `[0, 1)`.
+    pub fn record(&mut self, now: Instant, random: f64) {
+        let counter = self.frequency(now);
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        self.counter = if random < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            counter.saturating_add(1)
+        } else {
+            counter
+        };
+        self.last = now;
+    }
+
+    /// Return the access counter at `now`, decayed since the last use.
+    pub fn frequency(&self, now: Instant) -> u8 {
+        let periods = self.idle(now).as_secs() / LFU_DECAY.as_secs();
+        self.counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    /// Return how long the key has not been used at `now`.
+    pub fn idle(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last)
+    }
+}
+
+/// A xorshift generator, good enough to sample keys and draw access counter increments,
+/// or the jitter of reconnections.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Rng {
+    /// Start from `seed`, which the generator can't start from if it is 0.
+    pub fn new(seed: u64) -> Rng {
+        if seed == 0 {
+            Rng::default()
+        } else {
+            Rng(seed)
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return a number drawn uniformly from `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Limit the shards of `shared_db` to `max` bytes together, 0 lifting the limit.
+pub fn set_max_memory(shared_db: &ShardedDb, max: usize, policy: EvictionPolicy) {
+    let limit = (max > 0).then_some(MemoryLimit { max, policy });
+    for db in shared_db.iter() {
+        db.lock().unwrap().set_limit(limit);
+    }
+}
+
+/// Evict keys from the shards of `shared_db` until they fit `maxmemory`,
+/// before a command writing to `keys` which may use more memory.
+///
+/// # Error
+///
+/// [`Error::OutOfMemory`] if the shards can't be brought back under
+/// `maxmemory`, the command must then be refused.
+pub fn make_room<K: AsRef<str>>(shared_db: &ShardedDb, keys: &[K], aof: Option<&SharedAof>) -> Result<()> {
+    // Most writes find room, and don't need to enter the log.
+    let limit = lock_shards(shared_db, keys)
+        .shards_mut()
+        .find_map(|shard| if shard.is_over_limit() { shard.limit() } else { None });
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let evict = || {
+        let mut evicted = vec![];
+        let result = evict(shared_db, limit, &mut evicted);
+        let entries = evicted
+            .iter()
+            .map(|key| {
+                vec![
+                    Bytes::from_static(b"PEXPIREAT"),
+                    Bytes::copy_from_slice(key.as_bytes()),
+                    Bytes::from_static(b"0"),
+                ]
+            })
+            .collect();
+        (result, entries)
+    };
+    match aof {
+        Some(aof) => aof.apply_logged_with(evict),
+        None => evict().0,
+    }
+}
+
+/// Evict the best of the victims sampled from every shard, one shard locked at
+/// a time, until the shards use at most `limit.max` bytes together, appending
+/// the evicted keys to `evicted`.
+fn evict(shared_db: &ShardedDb, limit: MemoryLimit, evicted: &mut Vec<String>) -> Result<()> {
+    loop {
+        let mut total = 0;
+        let mut best: Option<(u128, usize, String)> = None;
+        for (index, db) in shared_db.iter().enumerate() {
+            let mut shard = db.lock().unwrap();
+            total = shard.total_memory();
+            if total <= limit.max {
+                return Ok(());
+            }
+            if let Some((score, key)) = shard.sample_victim(limit.policy) {
+                if best.as_ref().is_none_or(|(best_score, ..)| score > *best_score) {
+                    best = Some((score, index, key));
+                }
+            }
+        }
+        debug_assert!(total > limit.max);
+
+        // The victim may be gone by now, the next round then samples again.
+        let (_, index, key) = best.ok_or(Error::OutOfMemory)?;
+        if shared_db[index].lock().unwrap().evict(&key) {
+            evicted.push(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::aof::{self, AppendOnlyLog, FsyncPolicy};
+    use crate::my_redis::db::{get_db, new_shared_db};
+    use crate::my_redis::error::Error;
+    use crate::my_redis::pubsub::PubSub;
+    use crate::my_redis::test_util::command;
+
+    /// Set the keys `key:0` to `key:{count - 1}` to 100 bytes values in a single shard database.
+    fn filled_db(count: usize) -> ShardedDb {
+        let shared_db = new_shared_db(1);
+        for i in 0..count {
+            command(&["SET", &format!("key:{}", i), &"v".repeat(100)]).apply(&shared_db, &PubSub::default());
+        }
+        shared_db
+    }
+
+    fn used_memory(shared_db: &ShardedDb) -> usize {
+        shared_db[0].lock().unwrap().used_memory()
+    }
+
+    fn exists(shared_db: &ShardedDb, key: &str) -> bool {
+        get_db(shared_db, key).lock().unwrap().value(key).is_some()
+    }
+
+    #[test]
+    fn policy_names() {
+        for name in ["noeviction", "allkeys-lru", "allkeys-lfu", "volatile-ttl", "allkeys-random"] {
+            assert_eq!(name, name.parse::<EvictionPolicy>().unwrap().to_string());
+        }
+        assert_eq!(Ok(EvictionPolicy::AllKeysLru), "ALLKEYS-LRU".parse());
+        assert!("volatile-lru".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn access_counter() {
+        let now = Instant::now();
+        let mut access = Access::new(now);
+        assert_eq!(LFU_INIT, access.frequency(now));
+
+        // The first uses always count, the following ones less and less often.
+        access.record(now, 0.99);
+        assert_eq!(LFU_INIT + 1, access.frequency(now));
+        access.record(now, 0.5);
+        assert_eq!(LFU_INIT + 1, access.frequency(now));
+        access.record(now, 0.05);
+        assert_eq!(LFU_INIT + 2, access.frequency(now));
+
+        let later = now + 3 * LFU_DECAY;
+        assert_eq!(LFU_INIT - 1, access.frequency(later));
+        assert_eq!(3 * LFU_DECAY, access.idle(later));
+        // Below its initial value, the counter always grows again.
+        access.record(later, 0.99);
+        assert_eq!(LFU_INIT, access.frequency(later));
+    }
+
+    #[test]
+    fn lru_and_lfu_keep_hot_keys() {
+        for policy in [EvictionPolicy::AllKeysLru, EvictionPolicy::AllKeysLfu] {
+            let shared_db = filled_db(100);
+            std::thread::sleep(Duration::from_millis(2));
+            for _ in 0..20 {
+                for i in 0..10 {
+                    command(&["GET", &format!("key:{}", i)]).apply(&shared_db, &PubSub::default());
+                }
+            }
+
+            let max = used_memory(&shared_db) * 4 / 5;
+            set_max_memory(&shared_db, max, policy);
+            make_room(&shared_db, &["key:0"], None).unwrap();
+            assert!(used_memory(&shared_db) <= max);
+            assert!((0..10).all(|i| exists(&shared_db, &format!("key:{}", i))), "{}", policy);
+            assert!((10..100).any(|i| !exists(&shared_db, &format!("key:{}", i))));
+        }
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_keys_closest_to_expiring() {
+        let shared_db = filled_db(50);
+        for i in 0..5 {
+            command(&["SET", &format!("session:{}", i), &"v".repeat(100), "EX", &(100 + i).to_string()])
+                .apply(&shared_db, &PubSub::default());
+        }
+
+        // Room for all but one key: the session expiring first goes.
+        set_max_memory(&shared_db, used_memory(&shared_db) - 1, EvictionPolicy::VolatileTtl);
+        make_room(&shared_db, &["key:0"], None).unwrap();
+        assert!(!exists(&shared_db, "session:0"));
+        assert!((1..5).all(|i| exists(&shared_db, &format!("session:{}", i))));
+
+        // Without volatile keys left to evict, writes are refused.
+        set_max_memory(&shared_db, 1000, EvictionPolicy::VolatileTtl);
+        assert!(matches!(make_room(&shared_db, &["key:0"], None), Err(Error::OutOfMemory)));
+        assert!((0..5).all(|i| !exists(&shared_db, &format!("session:{}", i))));
+        assert!((0..50).all(|i| exists(&shared_db, &format!("key:{}", i))));
+    }
+
+    #[test]
+    fn the_limit_applies_to_every_shard_together() {
+        let shared_db = new_shared_db(4);
+        for i in 0..200 {
+            command(&["SET", &format!("key:{}", i), &"v".repeat(100)]).apply(&shared_db, &PubSub::default());
+        }
+        let total = shared_db[0].lock().unwrap().total_memory();
+        let lens = |shared_db: &ShardedDb| -> Vec<usize> {
+            shared_db.iter().map(|db| db.lock().unwrap().len()).collect()
+        };
+        let before = lens(&shared_db);
+        let used: usize = shared_db.iter().map(|db| db.lock().unwrap().used_memory()).sum();
+        assert_eq!(used, total);
+
+        // Victims are drawn from every shard, not only from the one written to.
+        set_max_memory(&shared_db, total / 2, EvictionPolicy::AllKeysRandom);
+        make_room(&shared_db, &["key:0"], None).unwrap();
+        assert!(shared_db[0].lock().unwrap().total_memory() <= total / 2);
+        let after = lens(&shared_db);
+        assert!(before.iter().zip(&after).all(|(before, after)| after < before));
+        assert!((95..=100).contains(&after.iter().sum::<usize>()), "{:?}", after);
+    }
+
+    #[test]
+    fn noeviction_refuses_writes() {
+        let shared_db = filled_db(10);
+        let used = used_memory(&shared_db);
+        set_max_memory(&shared_db, used, EvictionPolicy::NoEviction);
+        make_room(&shared_db, &["key:0"], None).unwrap();
+
+        command(&["RPUSH", "list", "a"]).apply(&shared_db, &PubSub::default());
+        assert!(matches!(make_room(&shared_db, &["key:0"], None), Err(Error::OutOfMemory)));
+        assert_eq!(11, shared_db[0].lock().unwrap().len());
+
+        // Lifting the limit lets writes through again.
+        set_max_memory(&shared_db, 0, EvictionPolicy::NoEviction);
+        make_room(&shared_db, &["key:0"], None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evictions_are_logged() {
+        let path = std::env::temp_dir().join(format!("learn_rust_eviction_{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let aof = AppendOnlyLog::open(&path, FsyncPolicy::Always).unwrap();
+
+        let shared_db = new_shared_db(1);
+        for i in 0..20 {
+            let key = format!("key:{}", i);
+            aof.apply_logged(command(&["SET", &key, "value"]), |command| {
+                command.apply(&shared_db, &PubSub::default())
+            });
+        }
+        set_max_memory(&shared_db, used_memory(&shared_db) / 2, EvictionPolicy::AllKeysRandom);
+        make_room(&shared_db, &["key:0"], Some(&aof)).unwrap();
+
+        let replayed = new_shared_db(1);
+        aof::replay(&replayed, &path).unwrap();
+        assert_eq!(shared_db[0].lock().unwrap().len(), replayed[0].lock().unwrap().len());
+        assert!(replayed[0].lock().unwrap().len() <= 10);
+        for i in 0..20 {
+            let key = format!("key:{}", i);
+            assert_eq!(exists(&shared_db, &key), exists(&replayed, &key));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+//! A frame of the Redis serialization protocol (RESP), modeled after `mini_redis::frame`.
+//!
+//! `mini_redis::Frame` stores integers as `u64`, which can't carry the negative
+//! replies Redis uses for things like `TTL` (`-1` for no expiration, `-2` for a
+//! missing key), so the server speaks this type instead.
+//!
+//! Besides the RESP2 types, the frame model covers the RESP3 ones (maps, sets,
+//! doubles, booleans, big numbers, verbatim strings and pushes). Whether they go
+//! on the wire as such or downgraded to their RESP2 counterparts is up to the
+//! [`Protocol`] of the [`Connection`](super::Connection).
+
+use bytes::{Buf, Bytes};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::Cursor;
+use std::num::TryFromIntError;
+use std::string::FromUtf8Error;
+
+/// A frame in the Redis protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    /// The null reply of commands otherwise replying with an array, such as an
+    /// aborted `EXEC`, which RESP2 tells apart from a null string.
+    NullArray,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// A string along with its three characters long format, such as `txt` or `mkd`.
+    Verbatim {
+        format: String,
+        data: Bytes,
+    },
+    /// Out-of-band data, such as pub/sub messages.
+    Push(Vec<Frame>),
+}
+
+/// The version of the protocol spoken on a connection, negotiated with `HELLO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// How deep aggregates may be nested in a frame, so that checking or parsing
+/// one doesn't overflow the stack. [`Frame::check_within`] may allow less.
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a message.
+    Incomplete,
+
+    /// A string is longer than the limit given to [`Frame::check_within`].
+    TooLarge,
+
+    /// Aggregates are nested deeper than the limit given to [`Frame::check_within`].
+    TooDeep,
+
+    /// Invalid message encoding.
+    Other(mini_redis::Error),
+}
+
+impl Frame {
+    /// Return an empty array.
+    pub fn array() -> Frame {
+        Frame::Array(vec![])
+    }
+
+    /// Push a bulk frame into the array.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self` is not an array.
+    pub fn push_bulk(&mut self, bytes: Bytes) {
+        match self {
+            Frame::Array(vec) => vec.push(Frame::Bulk(bytes)),
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// Push an integer frame into the array.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self` is not an array.
+    pub fn push_int(&mut self, value: i64) {
+        match self {
+            Frame::Array(vec) => vec.push(Frame::Integer(value)),
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// Check if an entire message can be decoded from `src`.
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Frame::check_within(src, usize::MAX, MAX_DEPTH)
+    }
+
+    /// Check if an entire message can be decoded from `src`, failing with
+    /// [`Error::TooLarge`] as soon as a string announces more than `max_len`
+    /// bytes, rather than once they are all buffered.
+    ///
+    /// Fails with [`Error::TooDeep`] if aggregates are nested deeper than
+    /// `max_depth`, or than [`MAX_DEPTH`] which [`parse`](Frame::parse) allows.
+    pub fn check_within(
+        src: &mut Cursor<&[u8]>,
+        max_len: usize,
+        max_depth: usize,
+    ) -> Result<(), Error> {
+        Frame::check_nested(src, max_len, max_depth.min(MAX_DEPTH))
+    }
+
+    /// Parse a message which has already been validated with `check`.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        Frame::parse_nested(src, MAX_DEPTH)
+    }
+
+    /// Check a frame in which aggregates may nest `max_depth` deep.
+    fn check_nested(
+        src: &mut Cursor<&[u8]>,
+        max_len: usize,
+        max_depth: usize,
+    ) -> Result<(), Error> {
+        match get_u8(src)? {
+            b'+' | b'-' | b'(' | b'_' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b':' => {
+                get_integer(src)?;
+                Ok(())
+            }
+            b',' => {
+                get_double(src)?;
+                Ok(())
+            }
+            b'#' => {
+                get_boolean(src)?;
+                Ok(())
+            }
+            b'$' | b'=' => {
+                if b'-' == peek_u8(src)? {
+                    // Skip '-1\r\n'
+                    skip(src, 4)
+                } else {
+                    let len: usize = get_decimal(src)?.try_into()?;
+                    if len > max_len {
+                        return Err(Error::TooLarge);
+                    }
+
+                    // Skip the data and the trailing \r\n.
+                    skip(src, blob_len(len)?)
+                }
+            }
+            b'*' if b'-' == peek_u8(src)? => {
+                // Skip '-1\r\n'
+                skip(src, 4)
+            }
+            b'*' | b'~' | b'>' => {
+                let len = get_decimal(src)?;
+                let max_depth = nested(max_depth)?;
+
+                for _ in 0..len {
+                    Frame::check_nested(src, max_len, max_depth)?;
+                }
+
+                Ok(())
+            }
+            b'%' => {
+                let len = get_decimal(src)?;
+                let entries = len.checked_mul(2).ok_or_else(invalid_format)?;
+                let max_depth = nested(max_depth)?;
+
+                for _ in 0..entries {
+                    Frame::check_nested(src, max_len, max_depth)?;
+                }
+
+                Ok(())
+            }
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+        }
+    }
+
+    /// Parse a frame in which aggregates may nest `max_depth` deep.
+    fn parse_nested(src: &mut Cursor<&[u8]>, max_depth: usize) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::Simple(String::from_utf8(line)?))
+            }
+            b'-' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::Error(String::from_utf8(line)?))
+            }
+            b':' => Ok(Frame::Integer(get_integer(src)?)),
+            b'$' => {
+                if b'-' == peek_u8(src)? {
+                    let line = get_line(src)?;
+
+                    if line != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+
+                    Ok(Frame::Null)
+                } else {
+                    Ok(Frame::Bulk(get_blob(src)?))
+                }
+            }
+            b'*' => {
+                if b'-' == peek_u8(src)? {
+                    if get_line(src)? != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+
+                    Ok(Frame::NullArray)
+                } else {
+                    Ok(Frame::Array(get_aggregate(src, max_depth)?))
+                }
+            }
+            b'_' => {
+                if !get_line(src)?.is_empty() {
+                    return Err("protocol error; invalid frame format".into());
+                }
+                Ok(Frame::Null)
+            }
+            b'%' => {
+                let len = get_decimal(src)?.try_into()?;
+                let max_depth = nested(max_depth)?;
+                let mut out = Vec::with_capacity(len);
+
+                for _ in 0..len {
+                    let key = Frame::parse_nested(src, max_depth)?;
+                    let value = Frame::parse_nested(src, max_depth)?;
+                    out.push((key, value));
+                }
+
+                Ok(Frame::Map(out))
+            }
+            b'~' => Ok(Frame::Set(get_aggregate(src, max_depth)?)),
+            b'>' => Ok(Frame::Push(get_aggregate(src, max_depth)?)),
+            b',' => Ok(Frame::Double(get_double(src)?)),
+            b'#' => Ok(Frame::Boolean(get_boolean(src)?)),
+            b'(' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::BigNumber(String::from_utf8(line)?))
+            }
+            b'=' => {
+                let mut data = get_blob(src)?;
+                if data.len() < 4 || data[3] != b':' {
+                    return Err("protocol error; invalid verbatim string".into());
+                }
+                let format = String::from_utf8(data.split_to(4)[..3].to_vec())?;
+                Ok(Frame::Verbatim { format, data })
+            }
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+        }
+    }
+}
+
+impl PartialEq<&str> for Frame {
+    fn eq(&self, other: &&str) -> bool {
+        match self {
+            Frame::Simple(s) => s.eq(other),
+            Frame::Bulk(s) => s.eq(other),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use std::str;
+
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) => match str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null | Frame::NullArray => "(nil)".fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} => {}", key, value)?;
+                }
+
+                Ok(())
+            }
+            Frame::Double(num) => num.fmt(fmt),
+            Frame::Boolean(val) => val.fmt(fmt),
+            Frame::BigNumber(num) => num.fmt(fmt),
+            Frame::Verbatim { data, .. } => match str::from_utf8(data) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", data),
+            },
+        }
+    }
+}
+
+fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.chunk()[0])
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.get_u8())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    src.advance(n);
+    Ok(())
+}
+
+/// Read a new-line terminated unsigned decimal, used for lengths.
+fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// Read a new-line terminated signed decimal, used for integer frames.
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// Read a new-line terminated double, including `inf`, `-inf` and `nan`.
+fn get_double(src: &mut Cursor<&[u8]>) -> Result<f64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+fn get_boolean(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    match get_line(src)? {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err("protocol error; invalid frame format".into()),
+    }
+}
+
+/// Read a length-prefixed string, the prefix having already been consumed.
+fn get_blob(src: &mut Cursor<&[u8]>) -> Result<Bytes, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let n = blob_len(len)?;
+
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
+
+    skip(src, n)?;
+
+    Ok(data)
+}
+
+/// The length of a string of `len` bytes along with its trailing `\r\n`.
+fn blob_len(len: usize) -> Result<usize, Error> {
+    len.checked_add(2).ok_or_else(invalid_format)
+}
+
+/// Return how deep the entries of an aggregate which may nest `max_depth` deep
+/// may nest themselves.
+fn nested(max_depth: usize) -> Result<usize, Error> {
+    max_depth.checked_sub(1).ok_or(Error::TooDeep)
+}
+
+fn invalid_format() -> Error {
+    "protocol error; invalid frame format".into()
+}
+
+/// Read the entries of an array, set or push which may nest aggregates `max_depth`
+/// deep, the prefix having already been consumed.
+fn get_aggregate(src: &mut Cursor<&[u8]>, max_depth: usize) -> Result<Vec<Frame>, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let max_depth = nested(max_depth)?;
+    let mut out = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        out.push(Frame::parse_nested(src, max_depth)?);
+    }
+
+    Ok(out)
+}
+
+/// Find a line.
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+
+    for i in start..buf.len().saturating_sub(1) {
+        if buf[i] == b'\r' && buf[i + 1] == b'\n' {
+            // Move the position to be *after* the \n
+            src.set_position((i + 2) as u64);
+
+            return Ok(&buf[start..i]);
+        }
+    }
+
+    Err(Error::Incomplete)
+}
+
+impl From<String> for Error {
+    fn from(src: String) -> Error {
+        Error::Other(src.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(src: &str) -> Error {
+        src.to_string().into()
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(_src: FromUtf8Error) -> Error {
+        "protocol error; invalid frame format".into()
+    }
+}
+
+impl From<TryFromIntError> for Error {
+    fn from(_src: TryFromIntError) -> Error {
+        "protocol error; invalid frame format".into()
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::TooLarge => "protocol error; string too large".fmt(fmt),
+            Error::TooDeep => "protocol error; aggregates nested too deep".fmt(fmt),
+            Error::Other(err) => err.fmt(fmt),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Glob-style patterns, as `KEYS` and `SCAN MATCH` take them.
+//!
+//! Patterns are matched byte by byte as Redis does: `*` matches any sequence,
+//! `?` any single byte, `[abc]` one of the listed bytes, `[^abc]` any other byte,
+//! `[a-z]` a range, and `\` escapes the byte after it, within classes too.
+
+/// Return whether `string` matches the glob `pattern`.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // The pattern position after the last `*`, and the string position it matches up to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if p < pattern.len() {
+            let (matched, next) = match_one(pattern, p, string[s]);
+            if matched {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+        // Let the last `*` swallow one more byte and try again from there.
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the token of `pattern` at `p`, which is not `*`, returning
+/// whether it matches and the position of the next token.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> (bool, usize) {
+    match pattern[p] {
+        b'?' => (true, p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c, p + 2),
+        b'[' => match_class(pattern, p + 1, c),
+        literal => (literal == c, p + 1),
+    }
+}
+
+/// Match `c` against the class of `pattern` starting after its `[` at `p`.
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> (bool, usize) {
+    let negated = pattern.get(p) == Some(&b'^');
+    if negated {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            // Reversed ranges such as `[z-a]` are accepted, as in Redis.
+            let (low, high) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            matched |= (low..=high).contains(&c);
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+
+    // An unterminated class runs to the end of the pattern.
+    (matched != negated, (p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:42", true),
+            ("user:*", "session:42", false),
+            ("*:42", "user:42", true),
+            ("*a*b*", "xxaxxbxx", true),
+            ("*a*b*", "xxbxxaxx", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[c-a]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("[\\]]", "]", true),
+            ("[a-]", "-", true),
+            ("key[", "key", false),
+            ("key[ab", "keyb", true),
+            ("a*", "b", false),
+            ("", "", true),
+            ("", "a", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                *expected,
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                "{:?} against {:?}",
+                pattern,
+                string
+            );
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! A [`ClientHandle`] shares a few connections to the server among many tasks.
+//!
+//! Each connection is owned by a task which receives [`Command`]s on a channel,
+//! runs them one at a time on its [`Client`] and sends the replies back on the
+//! oneshot channel each command carries. Handles are cheap to clone and hand
+//! the commands out to the connections in turn.
+//!
+//! A command whose caller stopped waiting, because it dropped the receiver or
+//! its request timed out, is skipped if it hasn't been sent yet. Once sent, it
+//! runs to completion and the reply is thrown away, which keeps the connection
+//! in step with the server.
+
+use super::client::{self, Client, ClientError, Result};
+use super::frame::Frame;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// How many commands wait for each connection before senders are held back.
+const QUEUE_CAPACITY: usize = 32;
+
+pub type Responder<T> = oneshot::Sender<Result<T>>;
+
+/// A request for one of the connections of a [`ClientHandle`], along with the
+/// channel its reply is sent on.
+#[derive(Debug)]
+pub enum Command {
+    Get {
+        key: String,
+        resp: Responder<Option<Bytes>>,
+    },
+    Set {
+        key: String,
+        val: Bytes,
+        expire: Option<Duration>,
+        resp: Responder<()>,
+    },
+    Del {
+        keys: Vec<String>,
+        resp: Responder<u64>,
+    },
+    /// `INCRBY`, decrements being negative deltas.
+    Incr {
+        key: String,
+        delta: i64,
+        resp: Responder<i64>,
+    },
+    Expire {
+        key: String,
+        timeout: Duration,
+        resp: Responder<bool>,
+    },
+    Ttl {
+        key: String,
+        resp: Responder<Option<Option<Duration>>>,
+    },
+    /// `LPUSH` and `RPUSH`.
+    Push {
+        key: String,
+        elements: Vec<Bytes>,
+        left: bool,
+        resp: Responder<u64>,
+    },
+    /// `LPOP` and `RPOP`.
+    Pop {
+        key: String,
+        left: bool,
+        resp: Responder<Option<Bytes>>,
+    },
+    Hset {
+        key: String,
+        pairs: Vec<(Bytes, Bytes)>,
+        resp: Responder<u64>,
+    },
+    Hget {
+        key: String,
+        field: Bytes,
+        resp: Responder<Option<Bytes>>,
+    },
+    Sadd {
+        key: String,
+        members: Vec<Bytes>,
+        resp: Responder<u64>,
+    },
+    Smembers {
+        key: String,
+        resp: Responder<Vec<Bytes>>,
+    },
+    Publish {
+        channel: String,
+        message: Bytes,
+        resp: Responder<u64>,
+    },
+    /// Any other command, sent as it is with [`Client::call`].
+    Call {
+        request: Frame,
+        resp: Responder<Frame>,
+    },
+}
+
+impl Command {
+    /// Whether the caller dropped the receiver of the reply.
+    fn is_cancelled(&self) -> bool {
+        match self {
+            Command::Get { resp, .. } => resp.is_closed(),
+            Command::Set { resp, .. } => resp.is_closed(),
+            Command::Del { resp, .. } => resp.is_closed(),
+            Command::Incr { resp, .. } => resp.is_closed(),
+            Command::Expire { resp, .. } => resp.is_closed(),
+            Command::Ttl { resp, .. } => resp.is_closed(),
+            Command::Push { resp, .. } => resp.is_closed(),
+            Command::Pop { resp, .. } => resp.is_closed(),
+            Command::Hset { resp, .. } => resp.is_closed(),
+            Command::Hget { resp, .. } => resp.is_closed(),
+            Command::Sadd { resp, .. } => resp.is_closed(),
+            Command::Smembers { resp, .. } => resp.is_closed(),
+            Command::Publish { resp, .. } => resp.is_closed(),
+            Command::Call { resp, .. } => resp.is_closed(),
+        }
+    }
+
+    /// Answer the command with `err` without running it.
+    fn fail(self, err: ClientError) {
+        match self {
+            Command::Get { resp, .. } => reply(resp, Err(err)),
+            Command::Set { resp, .. } => reply(resp, Err(err)),
+            Command::Del { resp, .. } => reply(resp, Err(err)),
+            Command::Incr { resp, .. } => reply(resp, Err(err)),
+            Command::Expire { resp, .. } => reply(resp, Err(err)),
+            Command::Ttl { resp, .. } => reply(resp, Err(err)),
+            Command::Push { resp, .. } => reply(resp, Err(err)),
+            Command::Pop { resp, .. } => reply(resp, Err(err)),
+            Command::Hset { resp, .. } => reply(resp, Err(err)),
+            Command::Hget { resp, .. } => reply(resp, Err(err)),
+            Command::Sadd { resp, .. } => reply(resp, Err(err)),
+            Command::Smembers { resp, .. } => reply(resp, Err(err)),
+            Command::Publish { resp, .. } => reply(resp, Err(err)),
+            Command::Call { resp, .. } => reply(resp, Err(err)),
+        };
+    }
+
+    /// Run the command on `client` and send its reply, returning whether the
+    /// connection can still be used.
+    async fn execute(self, client: &mut Client) -> bool {
+        match self {
+            Command::Get { key, resp } => reply(resp, client.get(&key).await),
+            Command::Set {
+                key,
+                val,
+                expire: Some(expire),
+                resp,
+            } => reply(resp, client.set_expires(&key, val, expire).await),
+            Command::Set { key, val, resp, .. } => reply(resp, client.set(&key, val).await),
+            Command::Del { keys, resp } => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                reply(resp, client.del(&keys).await)
+            }
+            Command::Incr { key, delta, resp } => reply(resp, client.incr_by(&key, delta).await),
+            Command::Expire { key, timeout, resp } => reply(resp, client.expire(&key, timeout).await),
+            Command::Ttl { key, resp } => reply(resp, client.ttl(&key).await),
+            Command::Push {
+                key,
+                elements,
+                left: true,
+                resp,
+            } => reply(resp, client.lpush(&key, &elements).await),
+            Command::Push { key, elements, resp, .. } => reply(resp, client.rpush(&key, &elements).await),
+            Command::Pop { key, left: true, resp } => reply(resp, client.lpop(&key).await),
+            Command::Pop { key, resp, .. } => reply(resp, client.rpop(&key).await),
+            Command::Hset { key, pairs, resp } => reply(resp, client.hset(&key, &pairs).await),
+            Command::Hget { key, field, resp } => reply(resp, client.hget(&key, &field).await),
+            Command::Sadd { key, members, resp } => reply(resp, client.sadd(&key, &members).await),
+            Command::Smembers { key, resp } => reply(resp, client.smembers(&key).await),
+            Command::Publish { channel, message, resp } => reply(resp, client.publish(&channel, message).await),
+            Command::Call { request, resp } => reply(resp, client.call(request).await),
+        }
+    }
+}
+
+/// Send `result` to the caller, returning whether the connection it came from
+/// can still be used.
+fn reply<T>(resp: Responder<T>, result: Result<T>) -> bool {
+    let usable = !matches!(&result, Err(err) if err.is_connection_error());
+    let _ = resp.send(result);
+    usable
+}
+
+/// A handle on connections to the server, run by tasks of their own.
+///
+/// Clones share the same connections. The connections are closed once every
+/// clone is dropped or [`shutdown`](ClientHandle::shutdown) is called.
+#[derive(Clone)]
+pub struct ClientHandle {
+    connections: Arc<[mpsc::Sender<Command>]>,
+    next: Arc<AtomicUsize>,
+    shutdown: Arc<watch::Sender<bool>>,
+    timeout: Option<Duration>,
+}
+
+impl ClientHandle {
+    /// Open `connections` connections to the server at `addr`, at least one.
+    pub async fn connect<T: ToSocketAddrs + Clone>(addr: T, connections: usize) -> Result<ClientHandle> {
+        let mut clients = Vec::with_capacity(connections.max(1));
+        for _ in 0..connections.max(1) {
+            clients.push(client::connect(addr.clone()).await?);
+        }
+        Ok(ClientHandle::new(clients))
+    }
+
+    /// Spawn a task running each of `clients`, which must be called within a
+    /// tokio runtime.
+    pub fn new(clients: Vec<Client>) -> ClientHandle {
+        let (shutdown, _) = watch::channel(false);
+        let connections = clients
+            .into_iter()
+            .map(|client| {
+                let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+                tokio::spawn(run(client, rx, shutdown.subscribe()));
+                tx
+            })
+            .collect();
+
+        ClientHandle {
+            connections,
+            next: Arc::new(AtomicUsize::new(0)),
+            shutdown: Arc::new(shutdown),
+            timeout: None,
+        }
+    }
+
+    /// Return a handle on the same connections whose requests fail with
+    /// [`ClientError::Timeout`] if no reply came within `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> ClientHandle {
+        ClientHandle {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// Stop every connection once its current command is done. Commands still
+    /// waiting, and any sent later, fail with [`ClientError::Shutdown`].
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Hand `command` to the next connection still running.
+    ///
+    /// Fail with [`ClientError::Shutdown`] once the handle is shut down or all
+    /// its connections were lost.
+    pub async fn send(&self, mut command: Command) -> Result<()> {
+        if *self.shutdown.borrow() {
+            return Err(ClientError::Shutdown);
+        }
+        for _ in 0..self.connections.len() {
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+            match self.connections[index].send(command).await {
+                Ok(()) => return Ok(()),
+                Err(SendError(returned)) => command = returned,
+            }
+        }
+        Err(ClientError::Shutdown)
+    }
+
+    /// Send the command built around a responder by `command` and wait for its reply.
+    async fn request<T>(&self, command: impl FnOnce(Responder<T>) -> Command) -> Result<T> {
+        let (resp, reply) = oneshot::channel();
+        let request = async {
+            self.send(command(resp)).await?;
+            reply.await.map_err(|_| ClientError::Shutdown)?
+        };
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .map_err(|_| ClientError::Timeout)?,
+            None => request.await,
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Get { key, resp }).await
+    }
+
+    pub async fn set(&self, key: &str, val: Bytes) -> Result<()> {
+        let key = key.to_string();
+        self.request(|resp| Command::Set {
+            key,
+            val,
+            expire: None,
+            resp,
+        })
+        .await
+    }
+
+    /// Set `key` to `val`, expiring after `expire`.
+    pub async fn set_expires(&self, key: &str, val: Bytes, expire: Duration) -> Result<()> {
+        let key = key.to_string();
+        self.request(|resp| Command::Set {
+            key,
+            val,
+            expire: Some(expire),
+            resp,
+        })
+        .await
+    }
+
+    /// Delete `keys`, returning how many existed.
+    pub async fn del(&self, keys: &[&str]) -> Result<u64> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        self.request(|resp| Command::Del { keys, resp }).await
+    }
+
+    /// Increment the integer stored at `key` by `delta`, returning the new value.
+    pub async fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        let key = key.to_string();
+        self.request(|resp| Command::Incr { key, delta, resp }).await
+    }
+
+    pub async fn incr(&self, key: &str) -> Result<i64> {
+        self.incr_by(key, 1).await
+    }
+
+    /// Make `key` expire after `timeout`, returning whether it exists.
+    pub async fn expire(&self, key: &str, timeout: Duration) -> Result<bool> {
+        let key = key.to_string();
+        self.request(|resp| Command::Expire { key, timeout, resp }).await
+    }
+
+    /// Return the remaining time to live of `key`, see [`Client::ttl`].
+    pub async fn ttl(&self, key: &str) -> Result<Option<Option<Duration>>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Ttl { key, resp }).await
+    }
+
+    /// Push `elements` at the head of the list of `key`, returning its new length.
+    pub async fn lpush(&self, key: &str, elements: Vec<Bytes>) -> Result<u64> {
+        self.push(key, elements, true).await
+    }
+
+    /// Push `elements` at the tail of the list of `key`, returning its new length.
+    pub async fn rpush(&self, key: &str, elements: Vec<Bytes>) -> Result<u64> {
+        self.push(key, elements, false).await
+    }
+
+    async fn push(&self, key: &str, elements: Vec<Bytes>, left: bool) -> Result<u64> {
+        let key = key.to_string();
+        self.request(|resp| Command::Push {
+            key,
+            elements,
+            left,
+            resp,
+        })
+        .await
+    }
+
+    pub async fn lpop(&self, key: &str) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Pop { key, left: true, resp }).await
+    }
+
+    pub async fn rpop(&self, key: &str) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Pop { key, left: false, resp }).await
+    }
+
+    /// Set `pairs` of fields and values in the hash of `key`, returning how many fields were added.
+    pub async fn hset(&self, key: &str, pairs: Vec<(Bytes, Bytes)>) -> Result<u64> {
+        let key = key.to_string();
+        self.request(|resp| Command::Hset { key, pairs, resp }).await
+    }
+
+    pub async fn hget(&self, key: &str, field: Bytes) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Hget { key, field, resp }).await
+    }
+
+    /// Add `members` to the set of `key`, returning how many were not there yet.
+    pub async fn sadd(&self, key: &str, members: Vec<Bytes>) -> Result<u64> {
+        let key = key.to_string();
+        self.request(|resp| Command::Sadd { key, members, resp }).await
+    }
+
+    pub async fn smembers(&self, key: &str) -> Result<Vec<Bytes>> {
+        let key = key.to_string();
+        self.request(|resp| Command::Smembers { key, resp }).await
+    }
+
+    /// Publish `message` on `channel`, returning how many subscribers received it.
+    pub async fn publish(&self, channel: &str, message: Bytes) -> Result<u64> {
+        let channel = channel.to_string();
+        self.request(|resp| Command::Publish { channel, message, resp }).await
+    }
+
+    /// Send `request` and wait for its reply, error replies being returned as
+    /// [`ClientError::Server`].
+    pub async fn call(&self, request: Frame) -> Result<Frame> {
+        self.request(|resp| Command::Call { request, resp }).await
+    }
+}
+
+/// Run the commands received on `commands` with `client` until the handles
+/// are dropped or shut down, or the connection is lost without reconnection.
+async fn run(mut client: Client, mut commands: mpsc::Receiver<Command>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let command = tokio::select! {
+            biased;
+            // Also wakes up once every handle is dropped.
+            _ = shutdown.changed() => return,
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => return,
+            },
+        };
+        if command.is_cancelled() {
+            continue;
+        }
+        // A client which reconnects opens a new connection for the next command.
+        if !command.execute(&mut client).await && !client.can_reconnect() {
+            // The commands already queued for this connection won't run.
+            commands.close();
+            while let Ok(command) = commands.try_recv() {
+                command.fail(ClientError::ConnectionClosed);
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::test_util::{frame, start_server};
+
+    #[tokio::test]
+    async fn clones_share_the_connections() {
+        fn assert_clone_send<T: Clone + Send + Sync>() {}
+        assert_clone_send::<ClientHandle>();
+
+        let handle = ClientHandle::connect(start_server().await, 3).await.unwrap();
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        handle.incr("counter").await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(Some(Bytes::from("100")), handle.get("counter").await.unwrap());
+
+        handle.rpush("list", vec![Bytes::from("a"), Bytes::from("b")]).await.unwrap();
+        assert_eq!(Some(Bytes::from("b")), handle.rpop("list").await.unwrap());
+        assert_eq!(Frame::Integer(1), handle.call(frame(&["LLEN", "list"])).await.unwrap());
+        assert!(matches!(handle.incr("list").await, Err(ClientError::Server(_))));
+        assert_eq!(2, handle.del(&["counter", "list", "missing"]).await.unwrap());
+        assert_eq!(0, handle.publish("news", Bytes::from("hi")).await.unwrap());
+
+        let (resp, reply) = oneshot::channel();
+        let command = Command::Set {
+            key: "key".to_string(),
+            val: Bytes::from("value"),
+            expire: Some(Duration::from_secs(60)),
+            resp,
+        };
+        handle.send(command).await.unwrap();
+        reply.await.unwrap().unwrap();
+        assert!(handle.ttl("key").await.unwrap().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn timed_out_requests_are_skipped() {
+        let handle = ClientHandle::connect(start_server().await, 1).await.unwrap();
+
+        // Keeps the only connection busy for a while.
+        let busy = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.call(frame(&["BLPOP", "empty", "0.2"])).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let result = handle.with_timeout(Duration::from_millis(20)).incr("counter").await;
+        assert!(matches!(result, Err(ClientError::Timeout)));
+        assert_eq!(Frame::NullArray, busy.await.unwrap().unwrap());
+        assert_eq!(None, handle.get("counter").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_fail_once_shut_down() {
+        let handle = ClientHandle::connect(start_server().await, 2).await.unwrap();
+        handle.set("key", Bytes::from("value")).await.unwrap();
+
+        handle.clone().shutdown();
+        assert!(matches!(handle.get("key").await, Err(ClientError::Shutdown)));
+
+        let empty = ClientHandle::new(vec![]);
+        assert!(matches!(empty.get("key").await, Err(ClientError::Shutdown)));
+    }
+}
@@ -1,151 +1,79 @@
+pub mod aof;
+pub mod blocked;
 pub mod blocking_client;
-
-use bytes::{Buf, Bytes, BytesMut};
-use mini_redis::frame::Error::Incomplete;
-use mini_redis::{Frame, Result};
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    io::Cursor,
-    sync::{Arc, Mutex},
-};
-use tokio::net::TcpStream;
-use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    sync::oneshot,
-};
-
-pub type ShardedDb = Arc<Vec<Db>>;
-pub type Db = Mutex<HashMap<String, Bytes>>;
-
-pub fn new_shared_db(num_shareds: usize) -> ShardedDb {
-    let mut db: Vec<Mutex<HashMap<_, _>>> = Vec::with_capacity(num_shareds);
-    for _ in 0..num_shareds {
-        db.push(Mutex::new(HashMap::new()));
-    }
-    Arc::new(db)
+pub mod client;
+pub mod cmd;
+pub mod config;
+pub mod connection;
+pub mod db;
+pub mod error;
+pub mod eviction;
+pub mod frame;
+pub mod glob;
+pub mod manager;
+pub mod parse;
+pub mod pool;
+pub mod pubsub;
+pub mod server;
+pub mod shutdown;
+pub mod snapshot;
+#[cfg(test)]
+pub mod test_util;
+pub mod transaction;
+pub mod zset;
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+pub use connection::Connection;
+pub use db::{get_db, new_shared_db, spawn_purge_task, Db, ShardedDb};
+pub use frame::{Frame, Protocol};
+pub use pubsub::{new_shared_pub_sub, SharedPubSub};
+
+/// How much the binaries print, with the levels of the Redis `loglevel` directive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Verbose,
+    Notice,
+    Warning,
 }
 
-pub fn get_db<'a>(shared_db: &'a ShardedDb, key: &str) -> &'a Db {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    &shared_db[(hasher.finish() as usize) % shared_db.len()]
-}
+impl FromStr for LogLevel {
+    type Err = String;
 
-pub fn server_dbg_print(description: &str) {
-    println!("[*] [Server] {}", description);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "debug" => Ok(LogLevel::Debug),
+            "verbose" => Ok(LogLevel::Verbose),
+            "notice" => Ok(LogLevel::Notice),
+            "warning" => Ok(LogLevel::Warning),
+            _ => Err(format!("invalid log level '{}', expected debug, verbose, notice or warning", s)),
+        }
+    }
 }
 
-pub fn client_dbg_print(description: &str) {
-    println!("[*] [Client] {}", description);
-}
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Verbose as u8);
 
-#[derive(Debug)]
-pub enum Command {
-    Get {
-        key: String,
-        resp: Responder<Option<Bytes>>,
-    },
-    Set {
-        key: String,
-        val: Bytes,
-        resp: Responder<()>,
-    },
+/// Set the level below which messages are not printed.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
 }
 
-type Responder<T> = oneshot::Sender<mini_redis::Result<T>>;
-
-pub struct Connection {
-    stream: TcpStream,
-    buffer: BytesMut,
+/// Whether messages of `level` are printed.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 >= LOG_LEVEL.load(Ordering::Relaxed)
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
-        Connection {
-            stream: stream,
-            buffer: BytesMut::with_capacity(4096),
-        }
-    }
-    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
-        loop {
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
-            }
-
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err("connection reset by peer".into());
-                }
-            }
-        }
-    }
-
-    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
-        match frame {
-            Frame::Simple(val) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Error(val) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Integer(val) => {
-                self.stream.write_u8(b':').await?;
-                self.write_decimal(*val).await?;
-            }
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Bulk(val) => {
-                let len = val.len();
-
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Array(_val) => unimplemented!(),
-        }
-        self.stream.flush().await?;
-
-        Ok(())
-    }
-
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
-        use std::io::Write;
-
-        let mut buf = [0u8; 12];
-        let mut buf = Cursor::new(&mut buf[..]);
-        write!(&mut buf, "{}", val)?;
-
-        let pos = buf.position() as usize;
-        self.stream.write_all(&buf.get_ref()[..pos]).await?;
-        self.stream.write_all(b"\r\n").await?;
-
-        Ok(())
+pub fn server_dbg_print(description: &str) {
+    if log_enabled(LogLevel::Verbose) {
+        println!("[*] [Server] {}", description);
     }
+}
 
-    pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
-        let mut buf = Cursor::new(&self.buffer[..]);
-
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                let len = buf.position() as usize;
-                buf.set_position(0);
-
-                let frame = Frame::parse(&mut buf)?;
-
-                self.buffer.advance(len);
-                Ok(Some(frame))
-            }
-            Err(Incomplete) => Ok(None),
-            Err(e) => Err(e.into()),
-        }
+pub fn client_dbg_print(description: &str) {
+    if log_enabled(LogLevel::Verbose) {
+        println!("[*] [Client] {}", description);
     }
 }
@@ -0,0 +1,128 @@
+//! A cursor over the tokens of a command frame, modeled after the (private) `Parse` of `mini_redis`.
+
+use super::frame::Frame;
+use bytes::Bytes;
+use std::{fmt, str, vec};
+
+/// Utility for parsing a command.
+///
+/// Commands are represented as array frames, each entry of which is a token.
+#[derive(Debug)]
+pub struct Parse {
+    parts: vec::IntoIter<Frame>,
+}
+
+/// Error encountered while parsing a frame.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The frame has been fully consumed.
+    EndOfStream,
+
+    /// Entries are left after the last expected one.
+    Trailing,
+
+    /// An entry expected to be an integer isn't one.
+    NotInteger,
+
+    /// All other errors.
+    Other(mini_redis::Error),
+}
+
+impl Parse {
+    /// Create a new `Parse` to parse the contents of `frame`.
+    ///
+    /// # Error
+    ///
+    /// Error if `frame` is not an array frame.
+    pub fn new(frame: Frame) -> Result<Parse, ParseError> {
+        let array = match frame {
+            Frame::Array(array) => array,
+            frame => return Err(format!("expected array, got {:?}", frame).into()),
+        };
+
+        Ok(Parse {
+            parts: array.into_iter(),
+        })
+    }
+
+    fn next(&mut self) -> Result<Frame, ParseError> {
+        self.parts.next().ok_or(ParseError::EndOfStream)
+    }
+
+    /// Return the next entry as a string.
+    pub fn next_string(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(s),
+            Frame::Bulk(data) => str::from_utf8(&data[..])
+                .map(|s| s.to_string())
+                .map_err(|_| "invalid string".into()),
+            frame => Err(format!(
+                "expected simple frame or bulk frame, got {:?}",
+                frame
+            )
+            .into()),
+        }
+    }
+
+    /// Return the next entry as raw bytes.
+    pub fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            Frame::Bulk(data) => Ok(data),
+            frame => Err(format!(
+                "expected simple frame or bulk frame, got {:?}",
+                frame
+            )
+            .into()),
+        }
+    }
+
+    /// Return the next entry as a signed integer.
+    ///
+    /// `Simple` and `Bulk` entries are parsed, `Integer` entries are returned as they are.
+    pub fn next_int(&mut self) -> Result<i64, ParseError> {
+        match self.next()? {
+            Frame::Integer(v) => Ok(v),
+            Frame::Simple(data) => data.parse::<i64>().map_err(|_| ParseError::NotInteger),
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or(ParseError::NotInteger),
+            _ => Err(ParseError::NotInteger),
+        }
+    }
+
+    /// Ensure there are no more entries in the array.
+    pub fn finish(&mut self) -> Result<(), ParseError> {
+        if self.parts.next().is_none() {
+            Ok(())
+        } else {
+            Err(ParseError::Trailing)
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(src: String) -> ParseError {
+        ParseError::Other(src.into())
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(src: &str) -> ParseError {
+        src.to_string().into()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::EndOfStream => "unexpected end of stream".fmt(f),
+            ParseError::Trailing => "expected end of frame, but there was more".fmt(f),
+            ParseError::NotInteger => "invalid number".fmt(f),
+            ParseError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
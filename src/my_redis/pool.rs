@@ -0,0 +1,326 @@
+//! A bounded pool of [`Client`] connections, for tasks which each need a
+//! connection of their own for a while, such as request handlers.
+//!
+//! [`Pool::get`] hands out an idle connection, or opens a new one as long as
+//! fewer than `max_size` are checked out, waiting for one to come back
+//! otherwise. The connection returns to the pool when the [`PooledClient`] is
+//! dropped, unless it [broke](Client::is_broken) meanwhile, in which case it is
+//! closed and replaced by a new one when needed.
+//!
+//! Idle connections are checked with `PING`, see [`HealthCheck`].
+
+use super::client::{self, Client, ClientError, Result};
+use std::borrow::{Borrow, BorrowMut};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// When the idle connections of a [`Pool`] are checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthCheck {
+    /// Ping each connection before handing it out.
+    OnCheckout,
+    /// Ping the idle connections every so often, and open connections back up
+    /// to `min_size` at the same time.
+    Interval(Duration),
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// How many connections are opened up front. [`HealthCheck::Interval`]
+    /// keeps that many open.
+    pub min_size: usize,
+    /// How many connections may be checked out at once, at least one.
+    pub max_size: usize,
+    /// How long [`Pool::get`] waits for a connection before failing with
+    /// [`ClientError::Timeout`].
+    pub checkout_timeout: Duration,
+    pub health_check: HealthCheck,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_size: 0,
+            max_size: 10,
+            checkout_timeout: Duration::from_secs(5),
+            health_check: HealthCheck::Interval(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// A pool of connections to the server, cheap to clone.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addr: String,
+    config: PoolConfig,
+    /// Open connections not checked out, the most recently returned last.
+    idle: Mutex<Vec<Client>>,
+    /// One permit per connection checked out, or being opened or checked by the
+    /// health check.
+    permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    /// Open `config.min_size` connections to the server at `addr`.
+    ///
+    /// With [`HealthCheck::Interval`], a task checking the connections is
+    /// spawned, so this must be called within a tokio runtime.
+    pub async fn connect(addr: &str, config: PoolConfig) -> Result<Pool> {
+        let max_size = config.max_size.max(1);
+        let config = PoolConfig {
+            min_size: config.min_size.min(max_size),
+            max_size,
+            ..config
+        };
+
+        let mut idle = Vec::with_capacity(config.min_size);
+        for _ in 0..config.min_size {
+            idle.push(client::connect(addr).await?);
+        }
+
+        let shared = Arc::new(Shared {
+            addr: addr.to_string(),
+            idle: Mutex::new(idle),
+            permits: Arc::new(Semaphore::new(max_size)),
+            config,
+        });
+        if let HealthCheck::Interval(period) = shared.config.health_check {
+            tokio::spawn(maintain(Arc::downgrade(&shared), period));
+        }
+        Ok(Pool { shared })
+    }
+
+    /// Check a connection out, waiting for one to be returned if `max_size`
+    /// are checked out already.
+    pub async fn get(&self) -> Result<PooledClient> {
+        tokio::time::timeout(self.shared.config.checkout_timeout, self.checkout())
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    async fn checkout(&self) -> Result<PooledClient> {
+        let permit = self
+            .shared
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore of the pool is never closed");
+
+        loop {
+            let idle = self.shared.idle.lock().unwrap().pop();
+            let mut client = match idle {
+                Some(client) => client,
+                None => break,
+            };
+            if self.shared.config.health_check == HealthCheck::OnCheckout && client.ping().await.is_err() {
+                continue;
+            }
+            return Ok(self.pooled(client, permit));
+        }
+
+        let client = client::connect(&self.shared.addr[..]).await?;
+        Ok(self.pooled(client, permit))
+    }
+
+    fn pooled(&self, client: Client, permit: OwnedSemaphorePermit) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            shared: self.shared.clone(),
+            _permit: permit,
+        }
+    }
+
+    /// How many connections are open and not checked out.
+    pub fn idle_connections(&self) -> usize {
+        self.shared.idle.lock().unwrap().len()
+    }
+}
+
+impl Shared {
+    /// Ping the idle connections, closing those which don't answer.
+    ///
+    /// Connections checked out meanwhile hold permits, so those left are only
+    /// checked as long as permits are available.
+    async fn check_idle(&self) {
+        let count = self.idle.lock().unwrap().len();
+        let mut checked = Vec::with_capacity(count);
+        for _ in 0..count {
+            let permit = match self.permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let idle = self.idle.lock().unwrap().pop();
+            let mut client = match idle {
+                Some(client) => client,
+                None => break,
+            };
+            if client.ping().await.is_ok() {
+                checked.push((client, permit));
+            }
+        }
+        self.idle.lock().unwrap().extend(checked.into_iter().map(|(client, _)| client));
+    }
+
+    /// Open connections until `min_size` are open.
+    async fn fill(&self) {
+        loop {
+            let checked_out = self.config.max_size - self.permits.available_permits();
+            if self.idle.lock().unwrap().len() + checked_out >= self.config.min_size {
+                return;
+            }
+            let _permit = match self.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            match client::connect(&self.addr[..]).await {
+                Ok(client) => self.idle.lock().unwrap().push(client),
+                Err(err) => {
+                    eprintln!("[!] Can't open a pooled connection to {}: {}", self.addr, err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Run the health check every `period` until the pool is dropped.
+async fn maintain(shared: Weak<Shared>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes right away.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        shared.check_idle().await;
+        shared.fill().await;
+    }
+}
+
+/// A connection checked out of a [`Pool`], returned to it on drop.
+pub struct PooledClient {
+    /// Only taken on drop.
+    client: Option<Client>,
+    shared: Arc<Shared>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Borrow<Client> for PooledClient {
+    fn borrow(&self) -> &Client {
+        self
+    }
+}
+
+impl BorrowMut<Client> for PooledClient {
+    fn borrow_mut(&mut self) -> &mut Client {
+        self
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        // Returned before the permit is released, for the next checkout to find it.
+        if let Some(client) = self.client.take().filter(|client| !client.is_broken()) {
+            self.shared.idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::test_util::{any_port, start_server, TestServer};
+    use bytes::Bytes;
+
+    fn config(min_size: usize, max_size: usize, health_check: HealthCheck) -> PoolConfig {
+        PoolConfig {
+            min_size,
+            max_size,
+            checkout_timeout: Duration::from_millis(100),
+            health_check,
+        }
+    }
+
+    #[tokio::test]
+    async fn checkouts_are_bounded() {
+        let addr = start_server().await;
+        let pool = Pool::connect(&addr.to_string(), config(1, 2, HealthCheck::OnCheckout))
+            .await
+            .unwrap();
+        assert_eq!(1, pool.idle_connections());
+
+        let mut first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        assert!(matches!(pool.get().await, Err(ClientError::Timeout)));
+
+        first.set("key", Bytes::from("value")).await.unwrap();
+        drop(first);
+        assert_eq!(1, pool.idle_connections());
+        let mut third = pool.get().await.unwrap();
+        assert_eq!(Some(Bytes::from("value")), third.get("key").await.unwrap());
+        assert_eq!(0, pool.idle_connections());
+
+        drop((second, third));
+        assert_eq!(2, pool.idle_connections());
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_replaced() {
+        let addr = start_server().await;
+        let pool = Pool::connect(&addr.to_string(), config(0, 1, HealthCheck::OnCheckout))
+            .await
+            .unwrap();
+
+        let mut client = pool.get().await.unwrap();
+        client.set("key", Bytes::from("value")).await.unwrap();
+        // Dropped before its reply comes, which would answer the next request.
+        let pop = client.blpop(&["empty"], Duration::from_millis(200));
+        assert!(tokio::time::timeout(Duration::from_millis(20), pop).await.is_err());
+        assert!(client.is_broken());
+        drop(client);
+        assert_eq!(0, pool.idle_connections());
+
+        let mut client = pool.get().await.unwrap();
+        assert_eq!(Some(Bytes::from("value")), client.get("key").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn health_checks_replace_dead_connections() {
+        for health_check in [HealthCheck::OnCheckout, HealthCheck::Interval(Duration::from_millis(20))] {
+            let server = TestServer::start(any_port()).await;
+            let pool = Pool::connect(&server.addr.to_string(), config(2, 2, health_check)).await.unwrap();
+
+            // The server closes the idle connections as it shuts down.
+            let _server = server.restart().await;
+
+            if let HealthCheck::Interval(period) = health_check {
+                tokio::time::sleep(period * 5).await;
+            }
+            let mut client = pool.get().await.unwrap();
+            client.ping().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! The channel registry used by `PUBLISH` and `SUBSCRIBE`.
+//!
+//! Every channel is a tokio broadcast channel. A connection keeps its
+//! subscriptions in a [`Subscriptions`], which forwards the messages of every
+//! subscribed channel into a single queue the connection can wait on next to
+//! incoming frames.
+//!
+//! Messages and confirmations are push frames, which RESP2 connections send as
+//! plain arrays.
+
+use super::frame::Frame;
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+
+/// How many messages a slow subscriber may lag behind before it starts missing some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+pub type SharedPubSub = Arc<PubSub>;
+
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+/// A channel of the registry, dropped along with its last subscription.
+#[derive(Debug)]
+struct Channel {
+    tx: broadcast::Sender<Bytes>,
+    /// The number of connections subscribed to the channel. Their receivers
+    /// may outlive the subscriptions for a while, until their forwarders stop.
+    subscribers: usize,
+}
+
+pub fn new_shared_pub_sub() -> SharedPubSub {
+    Arc::new(PubSub::default())
+}
+
+impl PubSub {
+    /// Return a receiver of the messages published to `channel`.
+    ///
+    /// Every call must be matched by one to [`unsubscribe`](PubSub::unsubscribe).
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(channel.to_string()).or_insert_with(|| Channel {
+            tx: broadcast::channel(CHANNEL_CAPACITY).0,
+            subscribers: 0,
+        });
+        channel.subscribers += 1;
+        channel.tx.subscribe()
+    }
+
+    /// Give up a subscription to `channel`, dropping the channel from the
+    /// registry once it has no subscribers left.
+    pub fn unsubscribe(&self, channel: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(entry) = channels.get_mut(channel) {
+            entry.subscribers -= 1;
+            if entry.subscribers == 0 {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Publish `message` to `channel`, returning the number of subscribers it was sent to.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let channels = self.channels.lock().unwrap();
+        match channels.get(channel) {
+            // Receivers of subscriptions given up may linger, they don't count.
+            Some(channel) => channel.tx.send(message).map_or(0, |_| channel.subscribers),
+            None => 0,
+        }
+    }
+}
+
+/// The channels a single connection is subscribed to.
+#[derive(Debug)]
+pub struct Subscriptions {
+    forwarders: HashMap<String, Subscription>,
+    tx: mpsc::Sender<(String, Bytes)>,
+    rx: mpsc::Receiver<(String, Bytes)>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::new()
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        Subscriptions {
+            forwarders: HashMap::new(),
+            tx,
+            rx,
+        }
+    }
+
+    /// Return the number of subscribed channels.
+    pub fn len(&self) -> usize {
+        self.forwarders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forwarders.is_empty()
+    }
+
+    /// Subscribe to every channel of `channels`, returning one confirmation frame per channel.
+    pub fn subscribe(&mut self, pub_sub: &SharedPubSub, channels: Vec<String>) -> Vec<Frame> {
+        let mut replies = Vec::with_capacity(channels.len());
+
+        for channel in channels {
+            if !self.forwarders.contains_key(&channel) {
+                let rx = pub_sub.subscribe(&channel);
+                let subscription = Subscription {
+                    channel: channel.clone(),
+                    pub_sub: pub_sub.clone(),
+                    forwarder: tokio::spawn(forward(channel.clone(), rx, self.tx.clone())),
+                };
+                self.forwarders.insert(channel.clone(), subscription);
+            }
+            replies.push(confirmation("subscribe", Some(channel), self.len()));
+        }
+
+        replies
+    }
+
+    /// Unsubscribe from every channel of `channels`, or from all channels if it is empty.
+    ///
+    /// Return one confirmation frame per channel.
+    pub fn unsubscribe(&mut self, channels: Vec<String>) -> Vec<Frame> {
+        let channels = if channels.is_empty() {
+            self.forwarders.keys().cloned().collect()
+        } else {
+            channels
+        };
+
+        if channels.is_empty() {
+            return vec![confirmation("unsubscribe", None, 0)];
+        }
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                self.forwarders.remove(&channel);
+                confirmation("unsubscribe", Some(channel), self.len())
+            })
+            .collect()
+    }
+
+    /// Wait for the next message of any subscribed channel, returning it as a message frame.
+    ///
+    /// Never completes while there are no subscriptions.
+    pub async fn next_message(&mut self) -> Frame {
+        // `self.tx` is alive, so `recv` never returns `None`.
+        let (channel, message) = self.rx.recv().await.unwrap();
+
+        Frame::Push(vec![
+            Frame::Bulk(Bytes::from_static(b"message")),
+            Frame::Bulk(Bytes::from(channel)),
+            Frame::Bulk(message),
+        ])
+    }
+}
+
+/// A subscription to a channel, given up when dropped.
+#[derive(Debug)]
+struct Subscription {
+    channel: String,
+    pub_sub: SharedPubSub,
+    /// The task forwarding the messages of the channel to [`Subscriptions::tx`].
+    forwarder: JoinHandle<()>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        self.pub_sub.unsubscribe(&self.channel);
+    }
+}
+
+async fn forward(channel: String, mut rx: broadcast::Receiver<Bytes>, tx: mpsc::Sender<(String, Bytes)>) {
+    loop {
+        match rx.recv().await {
+            Ok(message) => {
+                if tx.send((channel.clone(), message)).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+fn confirmation(kind: &'static str, channel: Option<String>, count: usize) -> Frame {
+    let channel = match channel {
+        Some(channel) => Frame::Bulk(Bytes::from(channel)),
+        None => Frame::Null,
+    };
+    Frame::Push(vec![
+        Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+        channel,
+        Frame::Integer(count as i64),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_to_subscribers() {
+        let pub_sub = new_shared_pub_sub();
+        assert_eq!(0, pub_sub.publish("news", Bytes::from("nobody")));
+
+        let mut first = Subscriptions::new();
+        let mut second = Subscriptions::new();
+        first.subscribe(&pub_sub, vec!["news".to_string(), "sport".to_string()]);
+        second.subscribe(&pub_sub, vec!["news".to_string()]);
+
+        assert_eq!(2, pub_sub.publish("news", Bytes::from("hello")));
+        assert_eq!(1, pub_sub.publish("sport", Bytes::from("goal")));
+
+        let expected = |channel: &'static str, message: &'static str| {
+            Frame::Push(vec![
+                Frame::Bulk(Bytes::from("message")),
+                Frame::Bulk(Bytes::from(channel)),
+                Frame::Bulk(Bytes::from(message)),
+            ])
+        };
+        assert_eq!(expected("news", "hello"), second.next_message().await);
+
+        let mut received = vec![first.next_message().await, first.next_message().await];
+        received.sort_by_key(|frame| frame.to_string());
+        assert_eq!(vec![expected("news", "hello"), expected("sport", "goal")], received);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_confirmations() {
+        let pub_sub = new_shared_pub_sub();
+        let mut subscriptions = Subscriptions::new();
+
+        let replies = subscriptions.subscribe(&pub_sub, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(Frame::Integer(2), last(&replies[1]));
+
+        let replies = subscriptions.unsubscribe(vec!["a".to_string()]);
+        assert_eq!(Frame::Integer(1), last(&replies[0]));
+
+        let replies = subscriptions.unsubscribe(vec![]);
+        assert_eq!(1, replies.len());
+        assert_eq!(Frame::Integer(0), last(&replies[0]));
+        assert!(subscriptions.is_empty());
+        assert!(pub_sub.channels.lock().unwrap().is_empty());
+
+        let replies = subscriptions.unsubscribe(vec![]);
+        assert_eq!(
+            vec![Frame::Push(vec![
+                Frame::Bulk(Bytes::from("unsubscribe")),
+                Frame::Null,
+                Frame::Integer(0)
+            ])],
+            replies
+        );
+    }
+
+    #[tokio::test]
+    async fn channels_are_dropped_with_their_last_subscriber() {
+        let pub_sub = new_shared_pub_sub();
+        let mut first = Subscriptions::new();
+        let mut second = Subscriptions::new();
+        let channels: Vec<String> = (0..100).map(|i| format!("channel:{}", i)).collect();
+        first.subscribe(&pub_sub, channels.clone());
+        second.subscribe(&pub_sub, vec!["channel:0".to_string()]);
+        assert_eq!(100, pub_sub.channels.lock().unwrap().len());
+
+        first.unsubscribe(channels[..50].to_vec());
+        assert_eq!(51, pub_sub.channels.lock().unwrap().len());
+
+        // Disconnecting gives up the other subscriptions.
+        drop(first);
+        assert_eq!(1, pub_sub.channels.lock().unwrap().len());
+        assert_eq!(1, pub_sub.publish("channel:0", Bytes::from("hello")));
+        second.unsubscribe(vec![]);
+        assert!(pub_sub.channels.lock().unwrap().is_empty());
+        assert_eq!(0, pub_sub.publish("channel:0", Bytes::from("hello")));
+    }
+
+    fn last(frame: &Frame) -> Frame {
+        match frame {
+            Frame::Push(parts) => parts.last().unwrap().clone(),
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+}
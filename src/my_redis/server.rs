@@ -0,0 +1,695 @@
+//! The accept loop and connection handling of the server binary.
+//!
+//! [`run`] serves connections until the given shutdown future completes. It then
+//! stops accepting, signals every connection to close once its current command
+//! is answered, waits up to a deadline for them to do so, and finally persists
+//! the dataset if snapshots or the append-only log are enabled.
+
+use super::aof::SharedAof;
+use super::blocked::BlockedPop;
+use super::cmd::{hello_protocol, hello_reply, Command};
+use super::db::ShardedDb;
+use super::connection::DEFAULT_MAX_BUFFER;
+use super::error::{Error, Result};
+use super::eviction::make_room;
+use super::frame::{self, Frame, Protocol};
+use super::pubsub::{SharedPubSub, Subscriptions};
+use super::server_dbg_print;
+use super::shutdown::Shutdown;
+use super::snapshot::SharedSnapshotter;
+use super::transaction::{Transaction, Watches};
+use super::Connection;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+
+/// The state shared by every connection.
+#[derive(Clone)]
+pub struct Shared {
+    pub shared_db: ShardedDb,
+    pub pub_sub: SharedPubSub,
+    /// `None` unless snapshots are enabled.
+    pub snapshotter: Option<SharedSnapshotter>,
+    /// `None` unless the append-only log is enabled.
+    pub aof: Option<SharedAof>,
+}
+
+/// Limits on the connections the server serves.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Connections accepted past this many are answered with an error and closed.
+    pub max_clients: usize,
+    /// Close connections which send nothing for this long, subscribers excepted.
+    pub idle_timeout: Option<Duration>,
+    /// The largest request a connection buffers, see [`Connection::set_max_buffer`].
+    pub max_buffer: usize,
+    /// How deep a request may nest aggregates, see [`Connection::set_max_depth`].
+    pub max_depth: usize,
+    /// How long connections get to finish after the shutdown signal.
+    pub drain_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_clients: 10_000,
+            idle_timeout: None,
+            max_buffer: DEFAULT_MAX_BUFFER,
+            max_depth: frame::MAX_DEPTH,
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The id of the next connection, reported by `HELLO`.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Serve the connections of `listener` until `shutdown` completes.
+///
+/// Connections still busy [`Options::drain_timeout`] after the shutdown are
+/// abandoned. A final snapshot is written if snapshots are enabled, and the
+/// append-only log flushed, before returning.
+pub async fn run(listener: TcpListener, shared: Shared, options: Options, shutdown: impl Future) {
+    let (notify_shutdown, _) = broadcast::channel(1);
+    // Every connection holds a clone of the sender, `recv` returns `None` once they are all gone.
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+    tokio::select! {
+        _ = accept_loop(&listener, &shared, &options, &notify_shutdown, &shutdown_complete_tx) => {}
+        _ = shutdown => server_dbg_print("Shutting down"),
+    }
+
+    drop(listener);
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    if tokio::time::timeout(options.drain_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        eprintln!("[!] Connections still busy after {:?}, closing them", options.drain_timeout);
+    }
+
+    if let Some(snapshotter) = &shared.snapshotter {
+        match snapshotter.save(&shared.shared_db).await {
+            Ok(count) => server_dbg_print(&format!("Saved {} keys to {:?}", count, snapshotter.path())),
+            Err(err) => eprintln!("[!] Can't save the final snapshot: {}", err),
+        }
+    }
+    if let Some(aof) = &shared.aof {
+        if let Err(err) = aof.sync() {
+            eprintln!("[!] Can't flush the append-only log: {}", err);
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: &TcpListener,
+    shared: &Shared,
+    options: &Options,
+    notify_shutdown: &broadcast::Sender<()>,
+    shutdown_complete_tx: &mpsc::Sender<()>,
+) {
+    let clients = Arc::new(Semaphore::new(options.max_clients));
+
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                eprintln!("[!] Accept failed: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let permit = match clients.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tokio::spawn(async move {
+                    let mut connection = Connection::new(socket);
+                    let _ = connection.write_frame(&Error::MaxClients.into()).await;
+                });
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        let options = options.clone();
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let shutdown_complete = shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            process(socket, shared, options, shutdown).await;
+            drop(permit);
+            drop(shutdown_complete);
+        });
+    }
+}
+
+async fn process(socket: TcpStream, shared: Shared, options: Options, shutdown: Shutdown) {
+    if let Err(err) = handle(socket, shared, options, shutdown).await {
+        eprintln!("[!] Closing the connection: {}", err);
+    }
+}
+
+/// Serve the commands of a connection until it is closed, returning an error on
+/// I/O errors only.
+async fn handle(socket: TcpStream, shared: Shared, options: Options, mut shutdown: Shutdown) -> Result<()> {
+    let Shared { shared_db, pub_sub, snapshotter, aof } = shared;
+
+    let mut connection = Connection::new(socket);
+    connection.set_max_buffer(options.max_buffer);
+    connection.set_max_depth(options.max_depth);
+    let mut subscriptions = Subscriptions::new();
+    // `Some` between `MULTI` and `EXEC` or `DISCARD`.
+    let mut transaction: Option<Transaction> = None;
+    let mut watches = Watches::new(shared_db.clone());
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+
+    while !shutdown.is_shutdown() {
+        let idle_timeout = options.idle_timeout.filter(|_| subscriptions.is_empty());
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // The replies to pipelined commands are buffered until every command
+        // already received is answered, and then sent at once.
+        if !connection.has_buffered_frame() {
+            sync_log(aof.as_ref()).await?;
+            connection.flush().await?;
+        }
+
+        let frame = tokio::select! {
+            frame = connection.read_frame() => match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(Error::Io(err)) => return Err(err.into()),
+                Err(err) => {
+                    let fatal = err.is_fatal();
+                    sync_log(aof.as_ref()).await?;
+                    connection.write_frame(&err.into()).await?;
+                    if fatal {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            },
+            // Only completes once the connection has subscribed to some channel.
+            message = subscriptions.next_message() => {
+                sync_log(aof.as_ref()).await?;
+                connection.write_frame(&message).await?;
+                continue;
+            }
+            // A command already read is answered before the next shutdown check.
+            _ = shutdown.recv() => {
+                sync_log(aof.as_ref()).await?;
+                connection.flush().await?;
+                return Ok(());
+            }
+            _ = idle => return Ok(()),
+        };
+
+        let command = match Command::from_frame(frame) {
+            Ok(command) => command,
+            Err(err) => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.abort();
+                }
+                connection.buffer_frame(&err.into()).await?;
+                continue;
+            }
+        };
+
+        if let Some(transaction) = &mut transaction {
+            if !matches!(command, Command::Multi | Command::Exec | Command::Discard) {
+                connection.buffer_frame(&transaction.queue(command)).await?;
+                continue;
+            }
+        }
+
+        let responses = match command {
+            Command::Subscribe { channels } => subscriptions.subscribe(&pub_sub, channels),
+            Command::Unsubscribe { channels } => subscriptions.unsubscribe(channels),
+            Command::Hello { protocol } => {
+                match protocol.map(hello_protocol).transpose() {
+                    Ok(protocol) => {
+                        if let Some(protocol) = protocol {
+                            connection.set_protocol(protocol);
+                        }
+                        vec![hello_reply(connection.protocol(), id)]
+                    }
+                    Err(err) => vec![err.into()],
+                }
+            }
+            // RESP3 delivers messages out-of-band, so a subscribed connection can keep
+            // issuing commands.
+            _ if !subscriptions.is_empty() && connection.protocol() == Protocol::Resp2 => vec![Frame::Error(
+                "ERR only SUBSCRIBE / UNSUBSCRIBE are allowed in this context".to_string(),
+            )],
+            Command::Multi if transaction.is_some() => vec![Error::NestedMulti.into()],
+            Command::Multi => {
+                transaction = Some(Transaction::new());
+                vec![Frame::Simple("OK".to_string())]
+            }
+            Command::Exec => match transaction.take() {
+                Some(transaction) => vec![transaction.exec(&mut watches, &shared_db, &pub_sub, aof.as_ref())],
+                None => vec![Error::WithoutMulti("EXEC".to_string()).into()],
+            },
+            Command::Discard => match transaction.take() {
+                Some(_) => {
+                    watches.unwatch();
+                    vec![Frame::Simple("OK".to_string())]
+                }
+                None => vec![Error::WithoutMulti("DISCARD".to_string()).into()],
+            },
+            Command::Watch { keys } => {
+                watches.watch(keys);
+                vec![Frame::Simple("OK".to_string())]
+            }
+            Command::Unwatch => {
+                watches.unwatch();
+                vec![Frame::Simple("OK".to_string())]
+            }
+            Command::Bpop { keys, timeout, left } => {
+                let blocked = BlockedPop::new(shared_db.clone(), keys, left);
+                // The replies to the commands pipelined before must not wait.
+                sync_log(aof.as_ref()).await?;
+                connection.flush().await?;
+                match blocking_pop(&blocked, timeout, aof.as_ref(), &mut connection, &mut shutdown).await? {
+                    Some(reply) => vec![reply],
+                    None => return Ok(()),
+                }
+            }
+            Command::Save | Command::Bgsave if snapshotter.is_none() => {
+                vec![Frame::Error("ERR snapshots are disabled".to_string())]
+            }
+            Command::Save => match snapshotter.as_ref().unwrap().save(&shared_db).await {
+                Ok(_) => vec![Frame::Simple("OK".to_string())],
+                Err(err) => vec![Frame::Error(format!("ERR {}", err))],
+            },
+            Command::Bgsave => match snapshotter.as_ref().unwrap().bgsave(&shared_db) {
+                Ok(()) => vec![Frame::Simple("Background saving started".to_string())],
+                Err(err) => vec![Frame::Error(format!("ERR {}", err))],
+            },
+            Command::Bgrewriteaof => match &aof {
+                Some(aof) => {
+                    let aof = aof.clone();
+                    let shared_db = shared_db.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(err) = aof.rewrite(&shared_db) {
+                            eprintln!("[!] Rewriting the append-only log failed: {}", err);
+                        }
+                    });
+                    vec![Frame::Simple("Background append only file rewriting started".to_string())]
+                }
+                None => vec![Frame::Error("ERR the append-only log is disabled".to_string())],
+            },
+            cmd if cmd.may_grow() && make_room(&shared_db, &cmd.keys(), aof.as_ref()).is_err() => {
+                vec![Error::OutOfMemory.into()]
+            }
+            cmd => match &aof {
+                Some(aof) => vec![aof.apply_logged(cmd, |cmd| cmd.apply(&shared_db, &pub_sub))],
+                None => vec![cmd.apply(&shared_db, &pub_sub)],
+            },
+        };
+        for response in responses {
+            connection.buffer_frame(&response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Flush the append-only log to disk if the replies about to be sent may
+/// acknowledge writes which aren't yet, see [`AppendOnlyLog::sync_appended`].
+///
+/// [`AppendOnlyLog::sync_appended`]: super::aof::AppendOnlyLog::sync_appended
+async fn sync_log(aof: Option<&SharedAof>) -> Result<()> {
+    if let Some(aof) = aof {
+        aof.sync_appended().await?;
+    }
+    Ok(())
+}
+
+/// Wait for `blocked` to pop an element or for `timeout` to elapse, returning the
+/// reply, or `None` if the connection is closed or the server shuts down meanwhile.
+async fn blocking_pop(
+    blocked: &BlockedPop,
+    timeout: Option<Duration>,
+    aof: Option<&SharedAof>,
+    connection: &mut Connection,
+    shutdown: &mut Shutdown,
+) -> Result<Option<Frame>> {
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    loop {
+        match blocked.try_pop(aof) {
+            Ok(Some((key, element))) => {
+                return Ok(Some(Frame::Array(vec![Frame::Bulk(key.into()), Frame::Bulk(element)])))
+            }
+            Ok(None) => {}
+            Err(err) => return Ok(Some(err.into())),
+        }
+
+        let expired = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = blocked.notified() => {}
+            _ = expired => return Ok(Some(Frame::NullArray)),
+            closed = connection.closed() => match closed {
+                Err(Error::Io(err)) => return Err(err.into()),
+                _ => return Ok(None),
+            },
+            _ = shutdown.recv() => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::test_util::{any_port, frame, shared, spawn_server, start_server};
+    use crate::my_redis::{db::lock_shards, new_shared_db, snapshot::Snapshotter};
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let dir = std::env::temp_dir().join(format!("learn_rust_shutdown_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot_path = dir.join("dump.snapshot");
+
+        let shared = Shared {
+            snapshotter: Some(Snapshotter::new(&snapshot_path)),
+            ..shared(new_shared_db(4))
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (addr, server) = spawn_server(any_port(), shared, Options::default(), shutdown_rx).await;
+
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        connection.write_frame(&frame(&["SET", "k", "v"])).await.unwrap();
+        assert_eq!(Some(Frame::Simple("OK".to_string())), connection.read_frame().await.unwrap());
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap();
+
+        // The open connection was closed and no new one is accepted.
+        assert_eq!(None, connection.read_frame().await.unwrap());
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        let restored = new_shared_db(4);
+        assert_eq!(1, Snapshotter::new(&snapshot_path).load(&restored).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_keep_the_connection_open() {
+        let addr = start_server().await;
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        // Not a valid frame, the server answers and discards it.
+        tokio::io::AsyncWriteExt::write_all(&mut socket, b"*1\r\n?bogus\r\n").await.unwrap();
+        let mut connection = Connection::new(socket);
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Error(msg)) => assert!(msg.starts_with("ERR Protocol error")),
+            frame => panic!("unexpected {:?}", frame),
+        }
+
+        let requests = [
+            (frame(&["FOO"]), Frame::Error("ERR unknown command 'foo'".to_string())),
+            (
+                frame(&["GET"]),
+                Frame::Error("ERR wrong number of arguments for 'get' command".to_string()),
+            ),
+            (Frame::Integer(3), Frame::Error("ERR Protocol error: expected array, got Integer(3)".to_string())),
+            (frame(&["SET", "k", "v"]), Frame::Simple("OK".to_string())),
+            (frame(&["GET", "k"]), Frame::Bulk(Bytes::from("v"))),
+        ];
+        for (request, reply) in requests {
+            connection.write_frame(&request).await.unwrap();
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn deep_nesting_closes_only_that_connection() {
+        let addr = start_server().await;
+        let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut socket, &b"*1\r\n".repeat(1000)).await.unwrap();
+        let mut connection = Connection::new(socket);
+        assert_eq!(
+            Some(Frame::Error("ERR Protocol error: too many nested aggregates".to_string())),
+            connection.read_frame().await.unwrap()
+        );
+        assert_eq!(None, connection.read_frame().await.unwrap());
+
+        other.write_frame(&frame(&["PING"])).await.unwrap();
+        assert_eq!(Some(Frame::Simple("PONG".to_string())), other.read_frame().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        let addr = start_server().await;
+
+        // Several requests in a single write, the last one blocking: the replies
+        // to the others are sent without waiting for it.
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let requests = [
+            frame(&["INCR", "n"]),
+            frame(&["BOGUS"]),
+            frame(&["MULTI"]),
+            frame(&["INCR", "n"]),
+            frame(&["EXEC"]),
+            frame(&["BLPOP", "jobs", "0"]),
+        ];
+        connection.write_frames(&requests).await.unwrap();
+        for reply in [
+            Frame::Integer(1),
+            Frame::Error("ERR unknown command 'bogus'".to_string()),
+            Frame::Simple("OK".to_string()),
+            Frame::Simple("QUEUED".to_string()),
+            Frame::Array(vec![Frame::Integer(2)]),
+        ] {
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+
+        let mut pusher = Connection::new(TcpStream::connect(addr).await.unwrap());
+        pusher.write_frame(&frame(&["RPUSH", "jobs", "a"])).await.unwrap();
+        assert_eq!(Some(Frame::Integer(1)), pusher.read_frame().await.unwrap());
+        assert_eq!(
+            Some(Frame::Array(vec![Frame::Bulk(Bytes::from("jobs")), Frame::Bulk(Bytes::from("a"))])),
+            connection.read_frame().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn max_clients_and_idle_timeout() {
+        let options = Options {
+            max_clients: 1,
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Options::default()
+        };
+        let shared = shared(new_shared_db(4));
+        let (addr, _) = spawn_server(any_port(), shared, options, std::future::pending::<()>()).await;
+
+        let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+        first.write_frame(&frame(&["SET", "k", "v"])).await.unwrap();
+        assert_eq!(Some(Frame::Simple("OK".to_string())), first.read_frame().await.unwrap());
+
+        let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(
+            Some(Frame::Error("ERR max number of clients reached".to_string())),
+            second.read_frame().await.unwrap()
+        );
+        assert_eq!(None, second.read_frame().await.unwrap());
+
+        // The first connection is closed once idle, which frees its slot.
+        assert_eq!(None, first.read_frame().await.unwrap());
+        let mut third = Connection::new(TcpStream::connect(addr).await.unwrap());
+        third.write_frame(&frame(&["GET", "k"])).await.unwrap();
+        assert_eq!(Some(Frame::Bulk(Bytes::from("v"))), third.read_frame().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn multi_exec_discard() {
+        let addr = start_server().await;
+
+        let ok = || Frame::Simple("OK".to_string());
+        let queued = || Frame::Simple("QUEUED".to_string());
+        let error = |msg: &str| Frame::Error(msg.to_string());
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+        let requests = [
+            (frame(&["EXEC"]), error("ERR EXEC without MULTI")),
+            (frame(&["DISCARD"]), error("ERR DISCARD without MULTI")),
+            (frame(&["MULTI"]), ok()),
+            (frame(&["MULTI"]), error("ERR MULTI calls can not be nested")),
+            (frame(&["SET", "a", "1"]), queued()),
+            (frame(&["INCR", "b"]), queued()),
+            (frame(&["GET", "a"]), queued()),
+        ];
+        for (request, reply) in requests {
+            connection.write_frame(&request).await.unwrap();
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+
+        // Other clients don't see queued commands.
+        other.write_frame(&frame(&["GET", "a"])).await.unwrap();
+        assert_eq!(Some(Frame::Null), other.read_frame().await.unwrap());
+
+        connection.write_frame(&frame(&["EXEC"])).await.unwrap();
+        assert_eq!(
+            Some(Frame::Array(vec![ok(), Frame::Integer(1), Frame::Bulk(Bytes::from("1"))])),
+            connection.read_frame().await.unwrap()
+        );
+
+        // An error while queuing aborts the transaction, DISCARD drops it.
+        let requests = [
+            (frame(&["MULTI"]), ok()),
+            (frame(&["SET", "a", "2"]), queued()),
+            (frame(&["GET"]), error("ERR wrong number of arguments for 'get' command")),
+            (frame(&["EXEC"]), error("EXECABORT Transaction discarded because of previous errors.")),
+            (frame(&["MULTI"]), ok()),
+            (frame(&["SET", "a", "3"]), queued()),
+            (frame(&["DISCARD"]), ok()),
+            (frame(&["GET", "a"]), Frame::Bulk(Bytes::from("1"))),
+        ];
+        for (request, reply) in requests {
+            connection.write_frame(&request).await.unwrap();
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_aborts_exec() {
+        let addr = start_server().await;
+
+        let ok = || Frame::Simple("OK".to_string());
+        let queued = || Frame::Simple("QUEUED".to_string());
+        let forbidden = Frame::Error("ERR Command not allowed inside a transaction".to_string());
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+        for (request, reply) in [
+            (frame(&["WATCH", "balance"]), ok()),
+            (frame(&["MULTI"]), ok()),
+            (frame(&["WATCH", "other"]), forbidden),
+            (frame(&["DISCARD"]), ok()),
+            (frame(&["WATCH", "balance"]), ok()),
+            (frame(&["MULTI"]), ok()),
+            (frame(&["INCR", "balance"]), queued()),
+        ] {
+            connection.write_frame(&request).await.unwrap();
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+
+        // Another client modifies the watched key before EXEC.
+        other.write_frame(&frame(&["SET", "balance", "10"])).await.unwrap();
+        assert_eq!(Some(ok()), other.read_frame().await.unwrap());
+
+        for (request, reply) in [
+            (frame(&["EXEC"]), Frame::NullArray),
+            (frame(&["GET", "balance"]), Frame::Bulk(Bytes::from("10"))),
+            // EXEC unwatched the key, as does UNWATCH.
+            (frame(&["MULTI"]), ok()),
+            (frame(&["INCR", "balance"]), queued()),
+            (frame(&["EXEC"]), Frame::Array(vec![Frame::Integer(11)])),
+            (frame(&["WATCH", "balance"]), ok()),
+            (frame(&["UNWATCH"]), ok()),
+            (frame(&["SET", "balance", "0"]), ok()),
+            (frame(&["MULTI"]), ok()),
+            (frame(&["INCR", "balance"]), queued()),
+            (frame(&["EXEC"]), Frame::Array(vec![Frame::Integer(1)])),
+        ] {
+            connection.write_frame(&request).await.unwrap();
+            assert_eq!(Some(reply), connection.read_frame().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_pops() {
+        let shared_db = new_shared_db(4);
+        let shutdown = std::future::pending::<()>();
+        let (addr, _) = spawn_server(any_port(), shared(shared_db.clone()), Options::default(), shutdown).await;
+
+        let connect = || async { Connection::new(TcpStream::connect(addr).await.unwrap()) };
+        let blocked_clients = |key: &str| lock_shards(&shared_db, &[key]).shard(key).blocked_clients(key);
+        // Wait for a connection to block, so that clients queue up in a known order.
+        let wait_blocked = |key: &'static str, count: usize| {
+            let blocked_clients = &blocked_clients;
+            async move {
+                while blocked_clients(key) != count {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        let popped = |key: &str, element: &str| {
+            Some(Frame::Array(vec![
+                Frame::Bulk(Bytes::copy_from_slice(key.as_bytes())),
+                Frame::Bulk(Bytes::copy_from_slice(element.as_bytes())),
+            ]))
+        };
+
+        let mut pusher = connect().await;
+        for (request, reply) in [
+            (frame(&["BLPOP", "jobs"]), "ERR wrong number of arguments for 'blpop' command"),
+            (frame(&["BLPOP", "jobs", "-1"]), "ERR timeout is negative"),
+            (frame(&["BRPOP", "jobs", "soon"]), "ERR timeout is not a float or out of range"),
+        ] {
+            pusher.write_frame(&request).await.unwrap();
+            assert_eq!(Some(Frame::Error(reply.to_string())), pusher.read_frame().await.unwrap());
+        }
+        pusher.write_frame(&frame(&["BRPOP", "jobs", "0.05"])).await.unwrap();
+        assert_eq!(Some(Frame::NullArray), pusher.read_frame().await.unwrap());
+        assert_eq!(0, blocked_clients("jobs"));
+
+        // The longest waiting client is served first, whichever of its keys gets an element.
+        let mut first = connect().await;
+        first.write_frame(&frame(&["BLPOP", "other", "jobs", "0"])).await.unwrap();
+        wait_blocked("jobs", 1).await;
+        let mut second = connect().await;
+        second.write_frame(&frame(&["BLPOP", "jobs", "0"])).await.unwrap();
+        wait_blocked("jobs", 2).await;
+        let mut third = connect().await;
+        third.write_frame(&frame(&["BRPOP", "jobs", "10"])).await.unwrap();
+        wait_blocked("jobs", 3).await;
+
+        pusher.write_frame(&frame(&["RPUSH", "jobs", "a", "b"])).await.unwrap();
+        assert_eq!(Some(Frame::Integer(2)), pusher.read_frame().await.unwrap());
+        assert_eq!(popped("jobs", "a"), first.read_frame().await.unwrap());
+        assert_eq!(popped("jobs", "b"), second.read_frame().await.unwrap());
+        wait_blocked("jobs", 1).await;
+
+        // A client closing while blocked leaves its queues, and the element for the next one.
+        let mut gone = connect().await;
+        gone.write_frame(&frame(&["BLPOP", "jobs", "0"])).await.unwrap();
+        wait_blocked("jobs", 2).await;
+        drop(third);
+        wait_blocked("jobs", 1).await;
+        drop(gone);
+        wait_blocked("jobs", 0).await;
+        assert_eq!(0, blocked_clients("other"));
+
+        // Commands sent while blocked are answered once unblocked.
+        first.write_frame(&frame(&["BRPOP", "jobs", "0"])).await.unwrap();
+        first.write_frame(&frame(&["LLEN", "jobs"])).await.unwrap();
+        wait_blocked("jobs", 1).await;
+        pusher.write_frame(&frame(&["LPUSH", "jobs", "c"])).await.unwrap();
+        assert_eq!(Some(Frame::Integer(1)), pusher.read_frame().await.unwrap());
+        assert_eq!(popped("jobs", "c"), first.read_frame().await.unwrap());
+        assert_eq!(Some(Frame::Integer(0)), first.read_frame().await.unwrap());
+    }
+}
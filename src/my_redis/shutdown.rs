@@ -0,0 +1,35 @@
+use tokio::sync::broadcast;
+
+/// Listens for the server shutdown signal, modeled after the `Shutdown` of `mini_redis`.
+///
+/// The signal is sent once, through a broadcast channel, to every connection.
+#[derive(Debug)]
+pub struct Shutdown {
+    is_shutdown: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown {
+            is_shutdown: false,
+            notify,
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+
+    /// Wait for the shutdown signal, returning right away if it was already received.
+    pub async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+
+        // A dropped sender counts as a signal too.
+        let _ = self.notify.recv().await;
+
+        self.is_shutdown = true;
+    }
+}
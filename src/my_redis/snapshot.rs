@@ -0,0 +1,609 @@
+//! Point-in-time snapshots of a [`ShardedDb`], written by `SAVE` and `BGSAVE`.
+//!
+//! # Format
+//!
+//! All integers are big endian.
+//!
+//! ```text
+//! header   := "LRDB" version:u16
+//! blob     := len:u32 byte*
+//! entry    := 0x01 type:u8 expires_at:i64 key:blob value
+//! trailer  := 0xFF checksum:u64
+//! snapshot := header entry* trailer
+//! ```
+//!
+//! `expires_at` is a unix timestamp in milliseconds, `-1` for keys without an
+//! expiration. The checksum is the 64 bits FNV-1a hash of every byte before it.
+//! The encoding of the value depends on its `type`:
+//!
+//! ```text
+//! 0 string := blob
+//! 1 list   := count:u32 blob*
+//! 2 hash   := count:u32 (field:blob value:blob)*
+//! 3 set    := count:u32 blob*
+//! 4 zset   := count:u32 (member:blob score:f64)*
+//! ```
+//!
+//! Scores are stored as the big endian bits of the `f64`, members in ascending
+//! score order.
+//!
+//! Version 1 snapshots, which only hold strings, are still loaded.
+//!
+//! Snapshots are written shard by shard, so a shard is only locked while its own
+//! entries are copied out, and go to a temporary file renamed over the target
+//! once complete.
+
+use super::db::{from_unix_millis, get_db, to_unix_millis, Entry, ShardedDb, Value};
+use super::zset::SortedSet;
+use bytes::Bytes;
+use std::{
+    fmt, fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const MAGIC: &[u8; 4] = b"LRDB";
+const VERSION: u16 = 2;
+
+const OP_ENTRY: u8 = 0x01;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+
+/// Error raised when a snapshot can't be saved or loaded.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The file does not start with the snapshot magic bytes.
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The file ends before the snapshot does.
+    Truncated,
+    ChecksumMismatch,
+    Corrupt(String),
+    /// A `SAVE` or `BGSAVE` is already running.
+    InProgress,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot I/O error: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot file is truncated"),
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+            SnapshotError::InProgress => write!(f, "Background save already in progress"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// The 64 bits FNV-1a hash.
+#[derive(Clone, Copy)]
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Checksum {
+        Checksum(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// A writer hashing everything written through it.
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a snapshot of `shared_db` into `dst`, returning the number of keys written.
+pub fn write_snapshot<W: Write>(shared_db: &ShardedDb, dst: W) -> io::Result<usize> {
+    let mut dst = ChecksumWriter {
+        inner: dst,
+        checksum: Checksum::new(),
+    };
+    dst.write_all(MAGIC)?;
+    dst.write_all(&VERSION.to_be_bytes())?;
+
+    let mut count = 0;
+    for db in shared_db.iter() {
+        // Only hold the lock of one shard at a time, long enough to copy its entries.
+        let entries: Vec<(String, Entry)> = db
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+
+        for (key, entry) in entries {
+            let expires_at = entry.expires_at.map(to_unix_millis).unwrap_or(-1);
+
+            let kind = match entry.value {
+                Value::String(_) => TYPE_STRING,
+                Value::List(_) => TYPE_LIST,
+                Value::Hash(_) => TYPE_HASH,
+                Value::Set(_) => TYPE_SET,
+                Value::SortedSet(_) => TYPE_ZSET,
+            };
+            dst.write_all(&[OP_ENTRY, kind])?;
+            dst.write_all(&expires_at.to_be_bytes())?;
+            write_blob(&mut dst, key.as_bytes())?;
+            write_value(&mut dst, &entry.value)?;
+            count += 1;
+        }
+    }
+
+    dst.write_all(&[OP_EOF])?;
+    let checksum = dst.checksum.0;
+    dst.inner.write_all(&checksum.to_be_bytes())?;
+    dst.flush()?;
+
+    Ok(count)
+}
+
+fn write_blob<W: Write>(dst: &mut W, blob: &[u8]) -> io::Result<()> {
+    dst.write_all(&(blob.len() as u32).to_be_bytes())?;
+    dst.write_all(blob)
+}
+
+fn write_value<W: Write>(dst: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::String(value) => write_blob(dst, value),
+        Value::List(list) => {
+            dst.write_all(&(list.len() as u32).to_be_bytes())?;
+            list.iter().try_for_each(|element| write_blob(dst, element))
+        }
+        Value::Set(set) => {
+            dst.write_all(&(set.len() as u32).to_be_bytes())?;
+            set.iter().try_for_each(|member| write_blob(dst, member))
+        }
+        Value::Hash(hash) => {
+            dst.write_all(&(hash.len() as u32).to_be_bytes())?;
+            hash.iter().try_for_each(|(field, value)| {
+                write_blob(dst, field)?;
+                write_blob(dst, value)
+            })
+        }
+        Value::SortedSet(zset) => {
+            dst.write_all(&(zset.len() as u32).to_be_bytes())?;
+            zset.iter().try_for_each(|(member, score)| {
+                write_blob(dst, member)?;
+                dst.write_all(&score.to_bits().to_be_bytes())
+            })
+        }
+    }
+}
+
+/// A cursor over the bytes of a snapshot, failing with [`SnapshotError::Truncated`] past its end.
+struct Reader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.src.len() - self.pos < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let bytes = &self.src[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, SnapshotError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn blob(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn value(&mut self, kind: u8) -> Result<Value, SnapshotError> {
+        match kind {
+            TYPE_STRING => Ok(Value::String(Bytes::copy_from_slice(self.blob()?))),
+            TYPE_LIST => {
+                let count = self.u32()?;
+                (0..count)
+                    .map(|_| Ok(Bytes::copy_from_slice(self.blob()?)))
+                    .collect::<Result<_, _>>()
+                    .map(Value::List)
+            }
+            TYPE_SET => {
+                let count = self.u32()?;
+                (0..count)
+                    .map(|_| Ok(Bytes::copy_from_slice(self.blob()?)))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Set)
+            }
+            TYPE_HASH => {
+                let count = self.u32()?;
+                (0..count)
+                    .map(|_| {
+                        let field = Bytes::copy_from_slice(self.blob()?);
+                        Ok((field, Bytes::copy_from_slice(self.blob()?)))
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Value::Hash)
+            }
+            TYPE_ZSET => {
+                let count = self.u32()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..count {
+                    let member = Bytes::copy_from_slice(self.blob()?);
+                    let score = f64::from_bits(self.u64()?);
+                    if score.is_nan() {
+                        return Err(SnapshotError::Corrupt("NaN score".to_string()));
+                    }
+                    zset.insert(member, score);
+                }
+                Ok(Value::SortedSet(zset))
+            }
+            _ => Err(SnapshotError::Corrupt(format!("unknown value type {}", kind))),
+        }
+    }
+}
+
+/// Decode a snapshot, returning its entries which have not expired yet.
+///
+/// Nothing is returned unless the whole snapshot is well formed and its checksum matches.
+pub fn read_snapshot(src: &[u8]) -> Result<Vec<(String, Entry)>, SnapshotError> {
+    let mut reader = Reader { src, pos: 0 };
+
+    if reader.take(MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = u16::from_be_bytes(reader.take(2)?.try_into().unwrap());
+    if !(1..=VERSION).contains(&version) {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let mut entries = vec![];
+    loop {
+        match reader.u8()? {
+            OP_ENTRY => {
+                let kind = reader.u8()?;
+                let expires_at = reader.i64()?;
+                let key = String::from_utf8(reader.blob()?.to_vec())
+                    .map_err(|_| SnapshotError::Corrupt("key is not valid UTF-8".to_string()))?;
+                let value = reader.value(kind)?;
+
+                let expires_at = match expires_at {
+                    -1 => None,
+                    millis => match from_unix_millis(millis) {
+                        Some(when) => Some(when),
+                        // Expired while the server was down.
+                        None => continue,
+                    },
+                };
+                entries.push((key, Entry::new(value, expires_at)));
+            }
+            OP_EOF => break,
+            op => return Err(SnapshotError::Corrupt(format!("unknown opcode {:#x}", op))),
+        }
+    }
+
+    let mut checksum = Checksum::new();
+    checksum.update(&src[..reader.pos]);
+    if reader.u64()? != checksum.0 {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+    if reader.pos != src.len() {
+        return Err(SnapshotError::Corrupt("trailing bytes after the checksum".to_string()));
+    }
+
+    Ok(entries)
+}
+
+/// Save a snapshot of `shared_db` to `path`, returning the number of keys saved.
+///
+/// The snapshot is first written to a temporary file next to `path`, so a failed
+/// save never clobbers the previous snapshot.
+pub fn save(shared_db: &ShardedDb, path: &Path) -> io::Result<usize> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let file = fs::File::create(&tmp)?;
+    let mut writer = BufWriter::new(file);
+    let count = write_snapshot(shared_db, &mut writer)?;
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(count)
+}
+
+/// Load the snapshot at `path` into `shared_db`, returning the number of keys loaded.
+pub fn load(shared_db: &ShardedDb, path: &Path) -> Result<usize, SnapshotError> {
+    let entries = read_snapshot(&fs::read(path)?)?;
+    let count = entries.len();
+
+    for (key, entry) in entries {
+        get_db(shared_db, &key).lock().unwrap().insert_entry(key, entry);
+    }
+
+    Ok(count)
+}
+
+pub type SharedSnapshotter = Arc<Snapshotter>;
+
+/// Runs the `SAVE` and `BGSAVE` of a server, one at a time.
+#[derive(Debug)]
+pub struct Snapshotter {
+    path: PathBuf,
+    in_progress: AtomicBool,
+}
+
+impl Snapshotter {
+    pub fn new(path: impl Into<PathBuf>) -> SharedSnapshotter {
+        Arc::new(Snapshotter {
+            path: path.into(),
+            in_progress: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the snapshot into `shared_db` if there is one, returning the number of keys loaded.
+    pub fn load(&self, shared_db: &ShardedDb) -> Result<usize, SnapshotError> {
+        match load(shared_db, &self.path) {
+            Err(SnapshotError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Mark a save as running until the returned guard is dropped, even by a panic.
+    fn start(self: &Arc<Self>) -> Result<Saving, SnapshotError> {
+        self.in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| Saving(self.clone()))
+            .map_err(|_| SnapshotError::InProgress)
+    }
+
+    /// Save a snapshot, completing once it is on disk.
+    pub async fn save(self: &Arc<Self>, shared_db: &ShardedDb) -> Result<usize, SnapshotError> {
+        let saving = self.start()?;
+
+        let shared_db = shared_db.clone();
+        // The save goes on if this future is dropped, and stays marked as running until then.
+        let result = tokio::task::spawn_blocking(move || save(&shared_db, &saving.0.path))
+            .await
+            .map_err(io::Error::from)?;
+        Ok(result?)
+    }
+
+    /// Start saving a snapshot in the background.
+    pub fn bgsave(self: &Arc<Self>, shared_db: &ShardedDb) -> Result<(), SnapshotError> {
+        let saving = self.start()?;
+
+        let shared_db = shared_db.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = save(&shared_db, &saving.0.path) {
+                eprintln!("[!] Background saving failed: {}", err);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// A running save of a [`Snapshotter`], see [`Snapshotter::start`].
+struct Saving(SharedSnapshotter);
+
+impl Drop for Saving {
+    fn drop(&mut self) {
+        self.0.in_progress.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::db::new_shared_db;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::time::Duration;
+
+    fn populated_db() -> ShardedDb {
+        let shared_db = new_shared_db(4);
+        for i in 0..100 {
+            let key = format!("key:{}", i);
+            let expire = if i % 10 == 0 { Some(Duration::from_secs(3600)) } else { None };
+            get_db(&shared_db, &key)
+                .lock()
+                .unwrap()
+                .set(key, Bytes::from(format!("value:{}", i)), expire);
+        }
+        shared_db
+    }
+
+    fn encoded(shared_db: &ShardedDb) -> Vec<u8> {
+        let mut buf = vec![];
+        write_snapshot(shared_db, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let buf = encoded(&populated_db());
+
+        // Load into a database with another shard count.
+        let shared_db = new_shared_db(3);
+        for (key, entry) in read_snapshot(&buf).unwrap() {
+            get_db(&shared_db, &key).lock().unwrap().insert_entry(key, entry);
+        }
+
+        for i in 0..100 {
+            let key = format!("key:{}", i);
+            let mut db = get_db(&shared_db, &key).lock().unwrap();
+            assert_eq!(Some(Bytes::from(format!("value:{}", i))), db.get(&key).unwrap());
+            let ttl = db.ttl(&key).unwrap();
+            if i % 10 == 0 {
+                assert!(ttl.unwrap() > Duration::from_secs(3590));
+            } else {
+                assert_eq!(None, ttl);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_snapshots() {
+        let buf = encoded(&populated_db());
+
+        for len in 0..buf.len() {
+            assert!(read_snapshot(&buf[..len]).is_err(), "accepted {} bytes", len);
+        }
+        assert!(matches!(read_snapshot(&buf[..buf.len() - 3]), Err(SnapshotError::Truncated)));
+
+        let mut flipped = buf.clone();
+        flipped[buf.len() / 2] ^= 0x40;
+        assert!(read_snapshot(&flipped).is_err());
+
+        let mut wrong_magic = buf.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(read_snapshot(&wrong_magic), Err(SnapshotError::BadMagic)));
+
+        let mut wrong_version = buf;
+        wrong_version[5] = 9;
+        assert!(matches!(
+            read_snapshot(&wrong_version),
+            Err(SnapshotError::UnsupportedVersion(9))
+        ));
+    }
+
+    #[test]
+    fn snapshot_aggregates() {
+        let shared_db = new_shared_db(4);
+        let list: VecDeque<Bytes> = ["a", "", "c"].into_iter().map(Bytes::from).collect();
+        let hash: HashMap<Bytes, Bytes> = [("f", "1"), ("g", "")]
+            .into_iter()
+            .map(|(field, value)| (Bytes::from(field), Bytes::from(value)))
+            .collect();
+        let set: HashSet<Bytes> = ["x", "y"].into_iter().map(Bytes::from).collect();
+        let mut zset = SortedSet::new();
+        zset.insert(Bytes::from("alice"), 2.5);
+        zset.insert(Bytes::from("bob"), f64::NEG_INFINITY);
+        let values = [
+            ("hash", Value::Hash(hash.clone())),
+            ("list", Value::List(list)),
+            ("set", Value::Set(set.clone())),
+            ("string", Value::String(Bytes::from("s"))),
+            ("zset", Value::SortedSet(zset.clone())),
+        ];
+        for (key, value) in values {
+            let entry = Entry::new(value, None);
+            get_db(&shared_db, key).lock().unwrap().insert_entry(key.to_string(), entry);
+        }
+
+        let mut entries = read_snapshot(&encoded(&shared_db)).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(5, entries.len());
+        assert_eq!(Value::Hash(hash), entries[0].1.value);
+        assert_eq!("list", entries[1].0);
+        assert_eq!(
+            Value::List(["a", "", "c"].into_iter().map(Bytes::from).collect()),
+            entries[1].1.value
+        );
+        assert_eq!(Value::Set(set), entries[2].1.value);
+        assert_eq!(Value::String(Bytes::from("s")), entries[3].1.value);
+        assert_eq!(Value::SortedSet(zset), entries[4].1.value);
+    }
+
+    #[test]
+    fn load_version_1() {
+        let mut buf = vec![];
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(&[OP_ENTRY, TYPE_STRING]);
+        buf.extend_from_slice(&(-1i64).to_be_bytes());
+        write_blob(&mut buf, b"key").unwrap();
+        write_blob(&mut buf, b"value").unwrap();
+        buf.push(OP_EOF);
+        let mut checksum = Checksum::new();
+        checksum.update(&buf);
+        buf.extend_from_slice(&checksum.0.to_be_bytes());
+
+        let entries = read_snapshot(&buf).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(Value::String(Bytes::from("value")), entries[0].1.value);
+    }
+
+    #[tokio::test]
+    async fn save_and_load_file() {
+        let dir = std::env::temp_dir().join(format!("learn_rust_snapshot_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let snapshotter = Snapshotter::new(dir.join("dump.snapshot"));
+
+        let empty = new_shared_db(2);
+        assert_eq!(0, snapshotter.load(&empty).unwrap());
+
+        assert_eq!(100, snapshotter.save(&populated_db()).await.unwrap());
+        let shared_db = new_shared_db(2);
+        assert_eq!(100, snapshotter.load(&shared_db).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn panics_end_the_save() {
+        let snapshotter = Snapshotter::new("unused.snapshot");
+        let saving = snapshotter.start().unwrap();
+        assert!(matches!(snapshotter.start(), Err(SnapshotError::InProgress)));
+
+        std::panic::catch_unwind(move || {
+            let _saving = saving;
+            panic!("save failed");
+        })
+        .unwrap_err();
+        assert!(snapshotter.start().is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+//! Helpers shared by the tests of the other modules.
+
+use super::cmd::Command;
+use super::db::{new_shared_db, ShardedDb};
+use super::frame::Frame;
+use super::pubsub::new_shared_pub_sub;
+use super::server::{run, Options, Shared};
+use bytes::Bytes;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Return the request made of `parts`, the way clients send it.
+pub fn frame(parts: &[&str]) -> Frame {
+    Frame::Array(
+        parts
+            .iter()
+            .map(|part| Frame::Bulk(Bytes::copy_from_slice(part.as_bytes())))
+            .collect(),
+    )
+}
+
+/// Return the command made of `parts`, which must be a valid one.
+pub fn command(parts: &[&str]) -> Command {
+    Command::from_frame(frame(parts)).unwrap()
+}
+
+/// Return the state of a server on `shared_db`, without snapshots or an append-only log.
+pub fn shared(shared_db: ShardedDb) -> Shared {
+    Shared {
+        shared_db,
+        pub_sub: new_shared_pub_sub(),
+        snapshotter: None,
+        aof: None,
+    }
+}
+
+/// Spawn a server of `shared` listening on `addr`, until `shutdown` completes.
+///
+/// Returns the address the server listens on, a port being picked if `addr` has none.
+pub async fn spawn_server(
+    addr: SocketAddr,
+    shared: Shared,
+    options: Options,
+    shutdown: impl Future + Send + 'static,
+) -> (SocketAddr, JoinHandle<()>) {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (addr, tokio::spawn(run(listener, shared, options, shutdown)))
+}
+
+/// Spawn a server with an empty dataset on a local port, returning its address.
+pub async fn start_server() -> SocketAddr {
+    let shared = shared(new_shared_db(4));
+    spawn_server(any_port(), shared, Options::default(), std::future::pending::<()>())
+        .await
+        .0
+}
+
+/// A local address the server listens on whichever port is free.
+pub fn any_port() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+/// A server with an empty dataset which can be stopped, closing its connections.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    server: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Start a server on `addr`, see [`any_port`].
+    pub async fn start(addr: SocketAddr) -> TestServer {
+        let (stop, stopped) = oneshot::channel();
+        let (addr, server) = spawn_server(addr, shared(new_shared_db(4)), Options::default(), stopped).await;
+        TestServer { addr, stop, server }
+    }
+
+    /// Stop the server, returning the address it listened on.
+    pub async fn stop(self) -> SocketAddr {
+        self.stop.send(()).unwrap();
+        self.server.await.unwrap();
+        self.addr
+    }
+
+    /// Stop the server and start a new one on the same address.
+    pub async fn restart(self) -> TestServer {
+        let addr = self.stop().await;
+        TestServer::start(addr).await
+    }
+}
@@ -0,0 +1,333 @@
+//! `MULTI` transactions and `WATCH`ed keys of a connection.
+//!
+//! After `MULTI`, commands are parsed and queued rather than applied. A command
+//! which fails to parse or can't run inside a transaction is answered with its
+//! error and marks the transaction as aborted, so that the following `EXEC`
+//! discards it, as Redis does. Otherwise `EXEC` locks every shard the queued
+//! commands touch at once, in the ascending order of [`lock_shards`], and
+//! applies them with [`Command::apply_all`].
+//!
+//! `WATCH` records the [version](super::db::Shard::version) of keys. `EXEC`
+//! compares them again with the same shards locked, and fails with a null reply
+//! if any watched key was modified in the meantime.
+
+use super::aof::SharedAof;
+use super::cmd::Command;
+use super::db::{lock_shards, LockedShards, ShardedDb};
+use super::error::Error;
+use super::eviction::make_room;
+use super::frame::Frame;
+use super::pubsub::PubSub;
+
+/// The commands queued since `MULTI`.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Vec<Command>,
+    /// Whether a command failed to queue.
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    /// Queue `command`, returning the reply to send for it.
+    ///
+    /// Commands handled by the connection or the server rather than the dataset,
+    /// and those walking every shard, are refused and abort the transaction.
+    pub fn queue(&mut self, command: Command) -> Frame {
+        match command {
+            Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Bpop { .. }
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::Hello { .. }
+            | Command::Save
+            | Command::Bgsave
+            | Command::Bgrewriteaof => {
+                self.aborted = true;
+                Error::ForbiddenInMulti.into()
+            }
+            command => {
+                self.queued.push(command);
+                Frame::Simple("QUEUED".to_string())
+            }
+        }
+    }
+
+    /// Mark the transaction as aborted, after a command failed to parse.
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Apply the queued commands atomically unless a key of `watches` was
+    /// modified, returning the array of their replies or a null reply.
+    ///
+    /// The keys stop being watched either way.
+    pub fn exec(
+        self,
+        watches: &mut Watches,
+        shared_db: &ShardedDb,
+        pub_sub: &PubSub,
+        aof: Option<&SharedAof>,
+    ) -> Frame {
+        if self.aborted {
+            watches.unwatch();
+            return Error::ExecAbort.into();
+        }
+
+        // Room is made before the log is entered, as for single commands.
+        let growing: Vec<&str> = self
+            .queued
+            .iter()
+            .filter(|command| command.may_grow())
+            .flat_map(Command::keys)
+            .collect();
+        if let Err(err) = make_room(shared_db, &growing, aof) {
+            watches.unwatch();
+            return err.into();
+        }
+
+        let mut unchanged = true;
+        let mut apply = |commands: Vec<Command>| {
+            let keys: Vec<&str> = commands
+                .iter()
+                .flat_map(Command::keys)
+                .chain(watches.keys.iter().map(|(key, _)| key.as_str()))
+                .collect();
+            let mut shards = lock_shards(shared_db, &keys);
+            unchanged = watches.unchanged(&mut shards);
+            if unchanged {
+                Command::apply_all(commands, &mut shards, pub_sub)
+            } else {
+                vec![]
+            }
+        };
+        // The log is entered before locking the shards, as for single commands.
+        let replies = match aof {
+            Some(aof) => aof.apply_logged_all(self.queued, apply),
+            None => apply(self.queued),
+        };
+        watches.unwatch();
+
+        if unchanged {
+            Frame::Array(replies)
+        } else {
+            Frame::NullArray
+        }
+    }
+}
+
+/// The keys a connection watches, with their versions when it started watching them.
+///
+/// The keys are unwatched on drop, so that a closed connection doesn't leave
+/// them tracked.
+pub struct Watches {
+    shared_db: ShardedDb,
+    keys: Vec<(String, u64)>,
+}
+
+impl Watches {
+    pub fn new(shared_db: ShardedDb) -> Watches {
+        Watches { shared_db, keys: vec![] }
+    }
+
+    /// Watch `keys`, keeping the version recorded first for those already watched.
+    pub fn watch(&mut self, keys: Vec<String>) {
+        let mut shards = lock_shards(&self.shared_db, &keys);
+        for key in keys {
+            if self.keys.iter().all(|(watched, _)| *watched != key) {
+                let version = shards.shard_mut(&key).watch(&key);
+                self.keys.push((key, version));
+            }
+        }
+    }
+
+    /// Stop watching every key.
+    pub fn unwatch(&mut self) {
+        let keys: Vec<&str> = self.keys.iter().map(|(key, _)| key.as_str()).collect();
+        let mut shards = lock_shards(&self.shared_db, &keys);
+        for (key, _) in self.keys.drain(..) {
+            shards.shard_mut(&key).unwatch(&key);
+        }
+    }
+
+    /// Whether no watched key was modified, `shards` holding every one of them.
+    fn unchanged(&self, shards: &mut LockedShards) -> bool {
+        self.keys
+            .iter()
+            .all(|(key, version)| shards.shard_mut(key).version(key) == Some(*version))
+    }
+}
+
+impl Drop for Watches {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::my_redis::db::new_shared_db;
+    use crate::my_redis::pubsub::new_shared_pub_sub;
+    use crate::my_redis::test_util::command;
+    use bytes::Bytes;
+
+    #[test]
+    fn exec_applies_the_queue_in_order() {
+        let db = new_shared_db(8);
+        let pub_sub = new_shared_pub_sub();
+        command(&["SET", "name", "ada"]).apply(&db, &pub_sub);
+        let mut watches = Watches::new(db.clone());
+
+        let mut transaction = Transaction::new();
+        for parts in [
+            &["INCR", "counter"][..],
+            &["INCRBY", "counter", "10"],
+            &["RPUSH", "list", "a", "b"],
+            &["INCR", "name"],
+            &["GET", "counter"],
+            &["SADD", "set", "x"],
+        ] {
+            assert_eq!(Frame::Simple("QUEUED".to_string()), transaction.queue(command(parts)));
+        }
+        // Nothing is applied before EXEC.
+        assert_eq!(Frame::Null, command(&["GET", "counter"]).apply(&db, &pub_sub));
+
+        // A failing command doesn't stop the others, as in Redis.
+        assert_eq!(
+            Frame::Array(vec![
+                Frame::Integer(1),
+                Frame::Integer(11),
+                Frame::Integer(2),
+                Frame::Error("ERR value is not an integer or out of range".to_string()),
+                Frame::Bulk(Bytes::from("11")),
+                Frame::Integer(1),
+            ]),
+            transaction.exec(&mut watches, &db, &pub_sub, None)
+        );
+    }
+
+    #[test]
+    fn aborted_transactions_are_discarded() {
+        let db = new_shared_db(4);
+        let pub_sub = new_shared_pub_sub();
+        let mut watches = Watches::new(db.clone());
+
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["SET", "k", "v"]));
+        assert!(matches!(transaction.queue(command(&["SUBSCRIBE", "news"])), Frame::Error(_)));
+        assert_eq!(
+            Frame::Error("EXECABORT Transaction discarded because of previous errors.".to_string()),
+            transaction.exec(&mut watches, &db, &pub_sub, None)
+        );
+        assert_eq!(Frame::Null, command(&["GET", "k"]).apply(&db, &pub_sub));
+
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["SET", "k", "v"]));
+        transaction.abort();
+        assert!(matches!(transaction.exec(&mut watches, &db, &pub_sub, None), Frame::Error(_)));
+        assert_eq!(Frame::Null, command(&["GET", "k"]).apply(&db, &pub_sub));
+    }
+
+    /// Transactions moving a unit between counters on different shards, checked by
+    /// transactions reading every counter: the total never changes.
+    #[test]
+    fn concurrent_transactions_are_atomic() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 200;
+
+        let db = new_shared_db(8);
+        let keys: Vec<String> = (0..8).map(|i| format!("account:{}", i)).collect();
+        for key in &keys {
+            command(&["SET", key, "100"]).apply(&db, &new_shared_pub_sub());
+        }
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for thread in 0..THREADS {
+            let db = db.clone();
+            let keys = keys.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                let pub_sub = new_shared_pub_sub();
+                let mut watches = Watches::new(db.clone());
+                for round in 0..ROUNDS {
+                    let from = &keys[(thread + round) % keys.len()];
+                    let to = &keys[(thread * 3 + round * 5 + 1) % keys.len()];
+                    let mut transfer = Transaction::new();
+                    transfer.queue(command(&["DECR", from]));
+                    transfer.queue(command(&["INCR", to]));
+                    transfer.exec(&mut watches, &db, &pub_sub, None);
+
+                    let mut audit = Transaction::new();
+                    for key in keys.iter().rev() {
+                        audit.queue(command(&["GET", key]));
+                    }
+                    let total: i64 = match audit.exec(&mut watches, &db, &pub_sub, None) {
+                        Frame::Array(replies) => replies
+                            .iter()
+                            .map(|reply| reply.to_string().parse::<i64>().unwrap())
+                            .sum(),
+                        frame => panic!("unexpected {:?}", frame),
+                    };
+                    assert_eq!(800, total);
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        drop(done_tx);
+
+        for _ in 0..THREADS {
+            done_rx
+                .recv_timeout(std::time::Duration::from_secs(30))
+                .expect("transactions deadlocked or a thread panicked");
+        }
+    }
+
+    #[test]
+    fn watched_keys_abort_exec() {
+        let db = new_shared_db(4);
+        let pub_sub = new_shared_pub_sub();
+        command(&["SET", "balance", "10"]).apply(&db, &pub_sub);
+
+        // Untouched watched keys let the transaction through.
+        let mut watches = Watches::new(db.clone());
+        watches.watch(vec!["balance".to_string(), "missing".to_string()]);
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["INCR", "balance"]));
+        assert_eq!(
+            Frame::Array(vec![Frame::Integer(11)]),
+            transaction.exec(&mut watches, &db, &pub_sub, None)
+        );
+
+        // EXEC unwatched the keys, so this write doesn't matter.
+        command(&["SET", "balance", "0"]).apply(&db, &pub_sub);
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["INCR", "balance"]));
+        assert_eq!(
+            Frame::Array(vec![Frame::Integer(1)]),
+            transaction.exec(&mut watches, &db, &pub_sub, None)
+        );
+
+        // Creating a watched key counts as a modification, and nothing is applied.
+        watches.watch(vec!["missing".to_string()]);
+        command(&["RPUSH", "missing", "a"]).apply(&db, &pub_sub);
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["INCR", "balance"]));
+        assert_eq!(Frame::NullArray, transaction.exec(&mut watches, &db, &pub_sub, None));
+        assert_eq!(Frame::Bulk(Bytes::from("1")), command(&["GET", "balance"]).apply(&db, &pub_sub));
+
+        // The versions are no longer tracked once every watcher is gone.
+        watches.watch(vec!["balance".to_string()]);
+        let mut other = Watches::new(db.clone());
+        other.watch(vec!["balance".to_string()]);
+        drop(other);
+        watches.unwatch();
+        assert_eq!(None, lock_shards(&db, &["balance"]).shard_mut("balance").version("balance"));
+    }
+}
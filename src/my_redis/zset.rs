@@ -0,0 +1,239 @@
+//! The sorted set value type, as used for leaderboards.
+//!
+//! A [`SortedSet`] keeps the score of each member in a hash map, for O(1)
+//! lookups, and the `(score, member)` pairs in a [`SkipList`], for O(log n)
+//! insertions, removals and rank queries. Members with equal scores are ordered
+//! by their bytes, as in Redis.
+
+use super::error::Error;
+use crate::data_structures::skip_list::SkipList;
+use bytes::Bytes;
+use std::{cmp::Ordering, collections::HashMap, ops::Range, str::FromStr};
+
+/// A member and its score, ordered by score then member.
+#[derive(Debug, Clone)]
+struct ScoredMember {
+    score: f64,
+    member: Bytes,
+}
+
+impl PartialEq for ScoredMember {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredMember {}
+
+impl PartialOrd for ScoredMember {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredMember {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| self.member.cmp(&other.member))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList<ScoredMember>,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Set the score of `member`, returning its previous score, `None` if it was added.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        // `total_cmp` tells -0 from 0, which are the same score.
+        let score = if score == 0.0 { 0.0 } else { score };
+
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            if previous.total_cmp(&score) == Ordering::Equal {
+                return Some(previous);
+            }
+            self.list.remove(&ScoredMember {
+                score: previous,
+                member: member.clone(),
+            });
+        }
+        self.list.insert(ScoredMember { score, member });
+        previous
+    }
+
+    /// Remove `member`, returning whether it was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(&ScoredMember {
+                    score,
+                    member: Bytes::copy_from_slice(member),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Return the 0-based rank of `member` by ascending score.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(&ScoredMember {
+            score,
+            member: Bytes::copy_from_slice(member),
+        })
+    }
+
+    /// Return the ranks of the members whose score lies between `min` and `max`.
+    pub fn score_range(&self, min: ScoreBound, max: ScoreBound) -> Range<usize> {
+        let start = self.list.partition_point(|scored| !min.admits_above(scored.score));
+        let end = self.list.partition_point(|scored| max.admits_below(scored.score));
+        start..end.max(start)
+    }
+
+    /// Iterate over the members of the given ranks and their scores, by ascending score.
+    pub fn range(&self, ranks: Range<usize>) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.list
+            .iter_from(ranks.start)
+            .take(ranks.len())
+            .map(|scored| (&scored.member, scored.score))
+    }
+
+    /// Iterate over every member and its score, by ascending score.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.range(0..self.len())
+    }
+}
+
+/// Parse a score the way Redis does, accepting `inf` and `-inf` but not `nan`.
+pub fn parse_score(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+/// One end of a score range, as in `ZRANGEBYSCORE key (1 +inf`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    /// Whether the bound itself is out of the range, written with a leading `(`.
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Whether `score` is in the range when the bound is its minimum.
+    fn admits_above(&self, score: f64) -> bool {
+        if self.exclusive {
+            score > self.value
+        } else {
+            score >= self.value
+        }
+    }
+
+    /// Whether `score` is in the range when the bound is its maximum.
+    fn admits_below(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+impl FromStr for ScoreBound {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ScoreBound, Error> {
+        let (value, exclusive) = match s.strip_prefix('(') {
+            Some(value) => (value, true),
+            None => (s, false),
+        };
+        let value = parse_score(value).ok_or(Error::MinMaxNotFloat)?;
+        Ok(ScoreBound { value, exclusive })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn members<'a>(entries: impl Iterator<Item = (&'a Bytes, f64)>) -> Vec<(String, f64)> {
+        entries
+            .map(|(member, score)| (String::from_utf8(member.to_vec()).unwrap(), score))
+            .collect()
+    }
+
+    #[test]
+    fn sorted_set_scores_and_ranks() {
+        let mut zset = SortedSet::new();
+        assert_eq!(None, zset.insert(Bytes::from("carol"), 30.0));
+        assert_eq!(None, zset.insert(Bytes::from("alice"), 10.0));
+        assert_eq!(None, zset.insert(Bytes::from("bob"), 10.0));
+        assert_eq!(None, zset.insert(Bytes::from("dave"), -0.0));
+
+        assert_eq!(Some(10.0), zset.insert(Bytes::from("alice"), 40.0));
+        assert_eq!(Some(0.0), zset.insert(Bytes::from("dave"), 0.0));
+        assert_eq!(4, zset.len());
+        assert_eq!(Some(40.0), zset.score(b"alice"));
+        assert_eq!(None, zset.score(b"erin"));
+
+        assert_eq!(
+            vec![
+                ("dave".to_string(), 0.0),
+                ("bob".to_string(), 10.0),
+                ("carol".to_string(), 30.0),
+                ("alice".to_string(), 40.0)
+            ],
+            members(zset.iter())
+        );
+        assert_eq!(Some(3), zset.rank(b"alice"));
+        assert_eq!(Some(0), zset.rank(b"dave"));
+        assert_eq!(None, zset.rank(b"erin"));
+
+        assert!(zset.remove(b"bob"));
+        assert!(!zset.remove(b"bob"));
+        assert_eq!(Some(1), zset.rank(b"carol"));
+        assert_eq!(vec![("carol".to_string(), 30.0)], members(zset.range(1..2)));
+    }
+
+    #[test]
+    fn sorted_set_score_range() {
+        let mut zset = SortedSet::new();
+        for (i, member) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            zset.insert(Bytes::from(*member), i as f64);
+        }
+        let bound = |s: &str| s.parse::<ScoreBound>().unwrap();
+
+        assert_eq!(1..4, zset.score_range(bound("1"), bound("3")));
+        assert_eq!(2..3, zset.score_range(bound("(1"), bound("(3")));
+        assert_eq!(0..5, zset.score_range(bound("-inf"), bound("+inf")));
+        assert_eq!(5..5, zset.score_range(bound("(4"), bound("inf")));
+        assert_eq!(3..3, zset.score_range(bound("3"), bound("1")));
+        assert!("nan".parse::<ScoreBound>().is_err());
+        assert!("(x".parse::<ScoreBound>().is_err());
+    }
+}